@@ -0,0 +1,15 @@
+-- Files attached to messages. `message_id` stays NULL from when a file is
+-- attached in the composer until the message it belongs to is sent.
+CREATE TABLE IF NOT EXISTS attachments (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    message_id INTEGER,
+    kind TEXT NOT NULL,  -- 'image' or 'document'
+    media_type TEXT NOT NULL,
+    filename TEXT NOT NULL,
+    data BLOB NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_attachments_message
+ON attachments(message_id);
@@ -0,0 +1,22 @@
+-- Message Batches submitted for offline processing. `requests` holds the
+-- prompts sent (JSON, each with its custom_id) and `settings` the
+-- conversation settings they ran with, so the results can be imported as
+-- conversations once the batch has ended.
+CREATE TABLE IF NOT EXISTS batches (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    provider_batch_id TEXT NOT NULL UNIQUE,
+    provider TEXT NOT NULL,
+    model TEXT NOT NULL,
+    status TEXT NOT NULL,  -- 'in_progress', 'canceling' or 'ended'
+    request_counts TEXT NOT NULL,  -- JSON string of per-outcome counts
+    requests TEXT NOT NULL,
+    settings TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    updated_at TEXT NOT NULL DEFAULT (datetime('now')),
+    ended_at TEXT,
+    expires_at TEXT,
+    imported_at TEXT  -- Set once the results are saved as conversations
+);
+
+CREATE INDEX IF NOT EXISTS idx_batches_created
+ON batches(created_at);
@@ -1,51 +1,462 @@
-use super::provider::{ChatProvider, Message, ProviderConfig, StreamCallback, StreamResponse};
+use super::error::ProviderError;
+use super::provider::{
+    with_extra_headers, ChatProvider, ContentBlock, Message, ModelInfo, ProviderConfig,
+    ProviderResponse, ResponseSchema, SamplingLimits, SamplingParams, StreamCallback,
+    StreamResponse, ThinkingBlock, ToolCall, ToolDefinition, Usage,
+};
+use super::recording::{self, Recording};
+use super::retry::{Failure, RetryPolicy};
+use super::sse;
 use async_trait::async_trait;
-use futures_util::StreamExt;
-use reqwest::{Client, ClientBuilder};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use reqwest::{Client, RequestBuilder};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
 use std::time::Duration;
 
+const DEFAULT_BASE_URL: &str = "https://api.anthropic.com";
+const DEFAULT_API_VERSION: &str = "2023-06-01";
+/// Smallest `budget_tokens` the API accepts
+const MIN_THINKING_BUDGET: u32 = 1024;
+
 pub struct AnthropicProvider {
     api_key: String,
     model: String,
     max_tokens: u32,
+    sampling: SamplingParams,
+    base_url: String,
+    api_version: String,
+    extra_headers: HashMap<String, String>,
+    tools: Vec<ToolDefinition>,
+    retry: RetryPolicy,
+    recording: Option<Recording>,
+    thinking_budget: Option<u32>,
+    prompt_caching: bool,
+    response_schema: Option<ResponseSchema>,
     client: Client,
 }
 
 #[derive(Serialize)]
 struct AnthropicMessage {
     role: String,
-    content: String,
+    content: Vec<RequestContent>,
+}
+
+/// A content block plus an optional cache breakpoint after it.
+#[derive(Serialize)]
+struct RequestContent {
+    #[serde(flatten)]
+    block: RequestContentBlock,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_control: Option<CacheControl>,
+}
+
+impl From<RequestContentBlock> for RequestContent {
+    fn from(block: RequestContentBlock) -> Self {
+        Self {
+            block,
+            cache_control: None,
+        }
+    }
+}
+
+/// Marks the end of a prefix the API should cache.
+#[derive(Serialize)]
+struct CacheControl {
+    #[serde(rename = "type")]
+    cache_type: &'static str,
+}
+
+const EPHEMERAL: CacheControl = CacheControl {
+    cache_type: "ephemeral",
+};
+
+/// The `system` field takes a plain string, or text blocks when one needs
+/// a cache breakpoint.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum SystemPrompt {
+    Text(String),
+    Blocks(Vec<SystemBlock>),
+}
+
+#[derive(Serialize)]
+struct SystemBlock {
+    #[serde(rename = "type")]
+    block_type: &'static str,
+    text: String,
+    cache_control: CacheControl,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RequestContentBlock {
+    Text {
+        text: String,
+    },
+    Image {
+        source: MediaSource,
+    },
+    Document {
+        source: MediaSource,
+        title: String,
+    },
+    Thinking {
+        thinking: String,
+        signature: String,
+    },
+    RedactedThinking {
+        data: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        is_error: bool,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum MediaSource {
+    Base64 {
+        media_type: String,
+        data: String,
+    },
+    /// Plain text documents are sent as text rather than base64
+    Text {
+        media_type: String,
+        data: String,
+    },
+}
+
+impl From<ContentBlock> for RequestContentBlock {
+    fn from(block: ContentBlock) -> Self {
+        match block {
+            ContentBlock::Text { text } => RequestContentBlock::Text { text },
+            ContentBlock::Image { media_type, data } => RequestContentBlock::Image {
+                source: MediaSource::Base64 { media_type, data },
+            },
+            ContentBlock::Document {
+                media_type,
+                data,
+                filename,
+            } => {
+                let source = if media_type.starts_with("text/") {
+                    let text = BASE64.decode(&data).unwrap_or_default();
+                    MediaSource::Text {
+                        media_type: "text/plain".to_string(),
+                        data: String::from_utf8_lossy(&text).into_owned(),
+                    }
+                } else {
+                    MediaSource::Base64 { media_type, data }
+                };
+                RequestContentBlock::Document {
+                    source,
+                    title: filename,
+                }
+            }
+        }
+    }
 }
 
 #[derive(Serialize)]
 struct AnthropicRequest {
     model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<SystemPrompt>,
     messages: Vec<AnthropicMessage>,
     max_tokens: u32,
     stream: bool,
+    #[serde(flatten)]
+    sampling: SamplingParams,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<ToolDefinition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<ToolChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<ThinkingConfig>,
+}
+
+/// Forces a call to the named tool.
+#[derive(Serialize)]
+struct ToolChoice {
+    #[serde(rename = "type")]
+    choice_type: &'static str,
+    name: String,
+}
+
+#[derive(Serialize)]
+struct ThinkingConfig {
+    #[serde(rename = "type")]
+    thinking_type: &'static str,
+    budget_tokens: u32,
 }
 
 #[derive(Deserialize, Debug)]
 struct EventData {
     #[serde(rename = "type")]
     event_type: String,
+    index: Option<usize>,
+    content_block: Option<ResponseContentBlock>,
     delta: Option<Delta>,
+    error: Option<AnthropicError>,
+    /// Set on `message_start`
+    message: Option<MessageStart>,
+    /// Set on `message_delta`, with the running output token count
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessageStart {
+    usage: Option<Usage>,
 }
 
 #[derive(Deserialize, Debug)]
 struct Delta {
     text: Option<String>,
+    thinking: Option<String>,
+    signature: Option<String>,
+    partial_json: Option<String>,
+    stop_reason: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
-struct NonStreamingResponse {
-    content: Vec<ContentBlock>,
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ResponseContentBlock {
+    Text {
+        text: String,
+    },
+    Thinking {
+        thinking: String,
+        #[serde(default)]
+        signature: String,
+    },
+    RedactedThinking {
+        data: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    #[serde(other)]
+    Other,
 }
 
 #[derive(Deserialize, Debug)]
-struct ContentBlock {
-    text: String,
+struct NonStreamingResponse {
+    content: Vec<ResponseContentBlock>,
+    stop_reason: Option<String>,
+    usage: Option<Usage>,
+}
+
+impl From<NonStreamingResponse> for ProviderResponse {
+    fn from(response: NonStreamingResponse) -> Self {
+        let mut result = ProviderResponse {
+            stop_reason: response.stop_reason,
+            usage: response.usage,
+            ..Default::default()
+        };
+        for block in response.content {
+            match block {
+                ResponseContentBlock::Text { text } => result.text.push_str(&text),
+                ResponseContentBlock::Thinking {
+                    thinking,
+                    signature,
+                } => result.thinking.push(ThinkingBlock::Thinking {
+                    thinking,
+                    signature,
+                }),
+                ResponseContentBlock::RedactedThinking { data } => result
+                    .thinking
+                    .push(ThinkingBlock::RedactedThinking { data }),
+                ResponseContentBlock::ToolUse { id, name, input } => {
+                    result.tool_calls.push(ToolCall {
+                        id,
+                        name,
+                        input,
+                        signature: None,
+                    })
+                }
+                ResponseContentBlock::Other => {}
+            }
+        }
+        result
+    }
+}
+
+/// A `tool_use` block whose input is still arriving as `input_json_delta`s.
+struct PendingToolUse {
+    id: String,
+    name: String,
+    input_json: String,
+}
+
+/// Accumulates the events of one streamed response.
+#[derive(Default)]
+struct StreamState {
+    response: ProviderResponse,
+    pending_tools: HashMap<usize, PendingToolUse>,
+    /// Thinking blocks whose text and signature are still arriving
+    pending_thinking: HashMap<usize, ThinkingBlock>,
+}
+
+impl StreamState {
+    /// Applies one event, returning `true` once the message is complete.
+    /// `name` is the SSE `event:` field, falling back to the payload's `type`.
+    fn handle_event(
+        &mut self,
+        name: &str,
+        event: EventData,
+        callback: &StreamCallback,
+    ) -> Result<bool, Failure> {
+        match name {
+            "content_block_start" => {
+                let (Some(index), Some(block)) = (event.index, event.content_block) else {
+                    return Ok(false);
+                };
+                match block {
+                    ResponseContentBlock::ToolUse { id, name, .. } => {
+                        self.pending_tools.insert(
+                            index,
+                            PendingToolUse {
+                                id,
+                                name,
+                                input_json: String::new(),
+                            },
+                        );
+                    }
+                    ResponseContentBlock::Thinking {
+                        thinking,
+                        signature,
+                    } => {
+                        self.pending_thinking.insert(
+                            index,
+                            ThinkingBlock::Thinking {
+                                thinking,
+                                signature,
+                            },
+                        );
+                    }
+                    ResponseContentBlock::RedactedThinking { data } => {
+                        self.pending_thinking
+                            .insert(index, ThinkingBlock::RedactedThinking { data });
+                    }
+                    ResponseContentBlock::Text { .. } | ResponseContentBlock::Other => {}
+                }
+            }
+            "content_block_delta" => {
+                let Some(delta) = event.delta else {
+                    return Ok(false);
+                };
+                if let Some(text) = delta.text {
+                    self.response.text.push_str(&text);
+                    callback(StreamResponse {
+                        text,
+                        is_done: false,
+                        thinking: String::new(),
+                    });
+                }
+                if let Some(ThinkingBlock::Thinking {
+                    thinking,
+                    signature,
+                }) = event
+                    .index
+                    .and_then(|index| self.pending_thinking.get_mut(&index))
+                {
+                    if let Some(delta_thinking) = delta.thinking {
+                        thinking.push_str(&delta_thinking);
+                        callback(StreamResponse {
+                            text: String::new(),
+                            is_done: false,
+                            thinking: delta_thinking,
+                        });
+                    }
+                    if let Some(delta_signature) = delta.signature {
+                        signature.push_str(&delta_signature);
+                    }
+                }
+                if let Some(partial_json) = delta.partial_json {
+                    if let Some(tool) = event
+                        .index
+                        .and_then(|index| self.pending_tools.get_mut(&index))
+                    {
+                        tool.input_json.push_str(&partial_json);
+                    }
+                }
+            }
+            "content_block_stop" => {
+                if let Some(block) = event
+                    .index
+                    .and_then(|index| self.pending_thinking.remove(&index))
+                {
+                    self.response.thinking.push(block);
+                }
+                if let Some(tool) = event
+                    .index
+                    .and_then(|index| self.pending_tools.remove(&index))
+                {
+                    // A tool without parameters streams no input at all
+                    let input = if tool.input_json.trim().is_empty() {
+                        Value::Object(Default::default())
+                    } else {
+                        serde_json::from_str(&tool.input_json).map_err(|e| {
+                            ProviderError::parse(format!(
+                                "Failed to parse input for tool {}: {}",
+                                tool.name, e
+                            ))
+                        })?
+                    };
+                    self.response.tool_calls.push(ToolCall {
+                        id: tool.id,
+                        name: tool.name,
+                        input,
+                        signature: None,
+                    });
+                }
+            }
+            "message_start" => {
+                if let Some(usage) = event.message.and_then(|message| message.usage) {
+                    self.merge_usage(usage);
+                }
+            }
+            "message_delta" => {
+                if let Some(stop_reason) = event.delta.and_then(|delta| delta.stop_reason) {
+                    self.response.stop_reason = Some(stop_reason);
+                }
+                if let Some(usage) = event.usage {
+                    self.merge_usage(usage);
+                }
+            }
+            "message_stop" => return Ok(true),
+            "error" => {
+                let error = event
+                    .error
+                    .ok_or_else(|| ProviderError::parse("Stream error without details"))?;
+                let message = format!("API error: {} - {}", error.error_type, error.message);
+                // Overload can surface mid-stream even after a 200 response
+                return Err(ProviderError::from_error_type(&error.error_type, message).into());
+            }
+            // `ping` carries nothing we need
+            _ => {}
+        }
+        Ok(false)
+    }
+
+    fn merge_usage(&mut self, usage: Usage) {
+        self.response
+            .usage
+            .get_or_insert_with(Usage::default)
+            .merge(usage);
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -61,56 +472,348 @@ struct AnthropicError {
 }
 
 impl AnthropicProvider {
-    pub fn new(config: ProviderConfig) -> Self {
-        // Configure client with timeouts and other settings
-        let client = ClientBuilder::new()
-            .timeout(Duration::from_secs(120)) // 2 minute timeout
-            .connect_timeout(Duration::from_secs(30))
-            .build()
-            .unwrap_or_else(|_| Client::new());
+    pub const SAMPLING_LIMITS: SamplingLimits = SamplingLimits {
+        max_temperature: 1.0,
+        supports_top_k: true,
+        max_stop_sequences: None,
+    };
 
-        Self {
+    pub fn new(config: ProviderConfig) -> Result<Self, ProviderError> {
+        // 2 minute timeout unless the network settings say otherwise
+        let client = config.network.client(Duration::from_secs(120))?;
+
+        let base_url = config
+            .base_url
+            .filter(|url| !url.trim().is_empty())
+            .unwrap_or_else(|| DEFAULT_BASE_URL.to_string());
+
+        Ok(Self {
             api_key: config.api_key,
             model: config.model,
             max_tokens: config.max_tokens,
+            sampling: config.sampling,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_version: config
+                .api_version
+                .filter(|version| !version.trim().is_empty())
+                .unwrap_or_else(|| DEFAULT_API_VERSION.to_string()),
+            extra_headers: config.extra_headers,
+            tools: config.tools,
+            retry: config.retry,
+            recording: config.recording,
+            thinking_budget: config.thinking_budget,
+            prompt_caching: config.prompt_caching,
+            response_schema: config.response_schema,
             client,
-        }
+        })
+    }
+
+    fn build_request(&self, request_body: &AnthropicRequest) -> RequestBuilder {
+        self.with_headers(self.client.post(format!("{}/v1/messages", self.base_url)))
+            .json(request_body)
+    }
+
+    fn with_headers(&self, request: RequestBuilder) -> RequestBuilder {
+        let request = request
+            .header("Content-Type", "application/json")
+            .header("X-API-Key", &self.api_key)
+            .header("anthropic-version", &self.api_version);
+
+        // Extra headers carry `anthropic-beta` flags or gateway credentials,
+        // and replace the defaults above if they share a name
+        with_extra_headers(request, &self.extra_headers)
     }
 
     async fn handle_response_error(
         response: reqwest::Response,
-    ) -> Result<reqwest::Response, String> {
+    ) -> Result<reqwest::Response, Failure> {
         if !response.status().is_success() {
             let status = response.status();
+            let headers = response.headers().clone();
             let error_text = response
                 .text()
                 .await
                 .unwrap_or_else(|_| "Failed to read error response".to_string());
 
             // Try to parse as ErrorResponse
-            if let Ok(error_response) = serde_json::from_str::<ErrorResponse>(&error_text) {
-                return Err(format!(
+            let message = match serde_json::from_str::<ErrorResponse>(&error_text) {
+                Ok(error_response) => format!(
                     "API error ({}): {} - {}",
                     status, error_response.error.error_type, error_response.error.message
-                ));
-            }
-
-            return Err(format!("Request failed ({}): {}", status, error_text));
+                ),
+                Err(_) => format!("Request failed ({}): {}", status, error_text),
+            };
+            return Err(Failure::from_status(status, &headers, message));
         }
         Ok(response)
     }
 
-    fn convert_messages(messages: Vec<Message>) -> Vec<AnthropicMessage> {
-        messages
+    /// Checks the constraints the API puts on extended thinking, so a bad
+    /// setting fails before anything is sent.
+    pub fn validate_thinking(config: &ProviderConfig) -> Result<(), String> {
+        let Some(budget) = config.thinking_budget else {
+            return Ok(());
+        };
+
+        if config.response_schema.is_some() {
+            return Err(
+                "thinking can't be enabled with a response schema, which forces tool use"
+                    .to_string(),
+            );
+        }
+
+        if budget < MIN_THINKING_BUDGET {
+            return Err(format!(
+                "thinking budget must be at least {} tokens, got {}",
+                MIN_THINKING_BUDGET, budget
+            ));
+        }
+        if budget >= config.max_tokens {
+            return Err(format!(
+                "thinking budget ({}) must be less than max_tokens ({})",
+                budget, config.max_tokens
+            ));
+        }
+        if config
+            .sampling
+            .temperature
+            .is_some_and(|temperature| temperature != 1.0)
+            || config.sampling.top_k.is_some()
+        {
+            return Err(
+                "temperature and top_k can't be changed while thinking is enabled".to_string(),
+            );
+        }
+        if config
+            .sampling
+            .top_p
+            .is_some_and(|top_p| !(0.95..=1.0).contains(&top_p))
+        {
+            return Err("top_p must be between 0.95 and 1 while thinking is enabled".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Splits out `system` messages, which the Messages API only accepts as
+    /// the top-level `system` field rather than as a conversation turn.
+    /// Thinking blocks are only replayed while thinking is enabled.
+    fn convert_messages(
+        messages: Vec<Message>,
+        include_thinking: bool,
+    ) -> (Option<String>, Vec<AnthropicMessage>) {
+        let (system, conversation): (Vec<Message>, Vec<Message>) =
+            messages.into_iter().partition(|msg| msg.role == "system");
+
+        let system = system
             .into_iter()
-            .map(|msg| AnthropicMessage {
-                role: msg.role,
-                content: msg.content,
-            })
-            .collect()
+            .map(|msg| msg.content)
+            .filter(|content| !content.trim().is_empty())
+            .collect::<Vec<_>>();
+        let system = (!system.is_empty()).then(|| system.join("\n\n"));
+
+        let mut converted: Vec<AnthropicMessage> = Vec::new();
+        for msg in conversation {
+            let blocks = msg.content_blocks();
+            let mut content = Vec::new();
+            // Tool results go back to the model as part of a user turn
+            let role = match msg.tool_result {
+                Some(result) => {
+                    content.push(RequestContentBlock::ToolResult {
+                        tool_use_id: result.tool_use_id,
+                        content: msg.content,
+                        is_error: result.is_error,
+                    });
+                    "user".to_string()
+                }
+                None => {
+                    // Thinking has to come first in an assistant turn
+                    if include_thinking {
+                        content.extend(msg.thinking.into_iter().map(|block| match block {
+                            ThinkingBlock::Thinking {
+                                thinking,
+                                signature,
+                            } => RequestContentBlock::Thinking {
+                                thinking,
+                                signature,
+                            },
+                            ThinkingBlock::RedactedThinking { data } => {
+                                RequestContentBlock::RedactedThinking { data }
+                            }
+                        }));
+                    }
+                    content.extend(blocks.into_iter().map(RequestContentBlock::from));
+                    content.extend(msg.tool_calls.into_iter().map(|call| {
+                        RequestContentBlock::ToolUse {
+                            id: call.id,
+                            name: call.name,
+                            input: call.input,
+                        }
+                    }));
+                    msg.role
+                }
+            };
+
+            // Consecutive turns from the same role are merged, so that all
+            // results for one assistant turn arrive in a single user message
+            let content = content.into_iter().map(RequestContent::from);
+            match converted.last_mut() {
+                Some(last) if last.role == role => last.content.extend(content),
+                _ => converted.push(AnthropicMessage {
+                    role,
+                    content: content.collect(),
+                }),
+            }
+        }
+
+        (system, converted)
+    }
+
+    /// Adds a breakpoint after the last two user turns. The newest one writes
+    /// the whole conversation to the cache for the next request, while the
+    /// one before still matches what the previous request cached.
+    fn add_history_breakpoints(messages: &mut [AnthropicMessage]) {
+        for message in messages
+            .iter_mut()
+            .rev()
+            .filter(|message| message.role == "user")
+            .take(2)
+        {
+            // Thinking blocks can't carry a breakpoint
+            let last = message.content.iter_mut().rev().find(|content| {
+                !matches!(
+                    content.block,
+                    RequestContentBlock::Thinking { .. }
+                        | RequestContentBlock::RedactedThinking { .. }
+                )
+            });
+            if let Some(last) = last {
+                last.cache_control = Some(EPHEMERAL);
+            }
+        }
+    }
+
+    fn build_body(&self, messages: Vec<Message>, stream: bool) -> AnthropicRequest {
+        let (system, mut messages) =
+            Self::convert_messages(messages, self.thinking_budget.is_some());
+
+        // The system prompt (and the tools before it) rarely change, so they
+        // get their own breakpoint
+        let system = system.map(|text| match self.prompt_caching {
+            true => SystemPrompt::Blocks(vec![SystemBlock {
+                block_type: "text",
+                text,
+                cache_control: EPHEMERAL,
+            }]),
+            false => SystemPrompt::Text(text),
+        });
+        if self.prompt_caching {
+            Self::add_history_breakpoints(&mut messages);
+        }
+
+        AnthropicRequest {
+            model: self.model.clone(),
+            system,
+            messages,
+            max_tokens: self.max_tokens,
+            stream,
+            sampling: self.sampling.clone(),
+            // A response schema is offered as the only tool, which the
+            // model has to call
+            tools: match &self.response_schema {
+                Some(schema) => vec![ToolDefinition {
+                    name: schema.name.clone(),
+                    description: "Respond with JSON that follows this schema".to_string(),
+                    input_schema: schema.schema.clone(),
+                }],
+                None => self.tools.clone(),
+            },
+            tool_choice: self.response_schema.as_ref().map(|schema| ToolChoice {
+                choice_type: "tool",
+                name: schema.name.clone(),
+            }),
+            thinking: self.thinking_budget.map(|budget_tokens| ThinkingConfig {
+                thinking_type: "enabled",
+                budget_tokens,
+            }),
+        }
+    }
+
+    /// With a response schema, turns the forced tool call into the reply:
+    /// its input, as JSON text, ending the turn.
+    fn take_structured(&self, response: &mut ProviderResponse) {
+        let Some(schema) = &self.response_schema else {
+            return;
+        };
+        let Some(index) = response
+            .tool_calls
+            .iter()
+            .position(|call| call.name == schema.name)
+        else {
+            return;
+        };
+        let call = response.tool_calls.remove(index);
+        response.text = call.input.to_string();
+        if response.stop_reason.as_deref() == Some("tool_use") {
+            response.stop_reason = Some("end_turn".to_string());
+        }
+    }
+
+    /// Sends one streaming request and reads it to `message_stop`.
+    async fn stream_attempt(
+        &self,
+        request_body: &AnthropicRequest,
+        callback: &StreamCallback,
+    ) -> Result<ProviderResponse, Failure> {
+        let response =
+            recording::send(self.recording.as_ref(), self.build_request(request_body)).await?;
+
+        let response = Self::handle_response_error(response).await?;
+
+        let mut state = StreamState::default();
+        let mut completed = false;
+        let result = sse::for_each_event(response, |event| {
+            let event_data = match serde_json::from_str::<EventData>(&event.data) {
+                Ok(event_data) => event_data,
+                Err(e) => {
+                    log::warn!("Skipping unparseable stream event: {}", e);
+                    return Ok(false);
+                }
+            };
+            let name = event.event.unwrap_or_else(|| event_data.event_type.clone());
+            completed = state.handle_event(&name, event_data, callback)?;
+            Ok(completed)
+        })
+        .await;
+
+        let partial = !state.response.text.is_empty();
+        result.map_err(|failure| failure.with_partial(partial))?;
+        if !completed {
+            return Err(
+                Failure::from(ProviderError::network("Stream ended before message_stop"))
+                    .with_partial(partial),
+            );
+        }
+
+        Ok(state.response)
     }
 }
 
+/// One page of `GET /v1/models`.
+#[derive(Deserialize)]
+struct ModelsPage {
+    data: Vec<ModelEntry>,
+    #[serde(default)]
+    has_more: bool,
+    last_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ModelEntry {
+    id: String,
+    display_name: Option<String>,
+}
+
 #[async_trait]
 impl ChatProvider for AnthropicProvider {
     fn supports_streaming(&self) -> bool {
@@ -121,124 +824,410 @@ impl ChatProvider for AnthropicProvider {
         &self,
         messages: Vec<Message>,
         callback: StreamCallback,
-    ) -> Result<String, String> {
-        let request_body = AnthropicRequest {
-            model: self.model.clone(),
-            messages: Self::convert_messages(messages),
-            max_tokens: self.max_tokens,
-            stream: true,
-        };
-
-        let response = self
-            .client
-            .post("https://api.anthropic.com/v1/messages")
-            .header("Content-Type", "application/json")
-            .header("X-API-Key", &self.api_key)
-            .header("anthropic-version", "2023-06-01")
-            .json(&request_body)
-            .send()
-            .await
-            .map_err(|e| format!("Failed to send request: {}", e))?;
+    ) -> Result<ProviderResponse, ProviderError> {
+        let request_body = self.build_body(messages, true);
 
-        let response = Self::handle_response_error(response).await?;
-        let mut stream = response.bytes_stream();
+        let mut response = self
+            .retry
+            .run(|| self.stream_attempt(&request_body, &callback))
+            .await?;
 
-        let mut full_response = String::new();
-        let mut buffer = String::new();
+        // The tool input isn't streamed as text, so send the JSON in one go
+        if self.response_schema.is_some() {
+            self.take_structured(&mut response);
+            callback(StreamResponse {
+                text: response.text.clone(),
+                is_done: false,
+                thinking: String::new(),
+            });
+        }
 
-        while let Some(item) = stream.next().await {
-            let chunk = item.map_err(|e| format!("Error reading chunk: {}", e))?;
-            buffer.push_str(&String::from_utf8_lossy(&chunk));
+        callback(StreamResponse {
+            text: String::new(),
+            is_done: true,
+            thinking: String::new(),
+        });
 
-            // Process complete lines in buffer
-            while let Some(end_index) = buffer.find('\n') {
-                let line = buffer[..end_index].trim().to_string();
-                buffer = buffer[end_index + 1..].to_string();
+        Ok(response)
+    }
 
-                if line.starts_with("data: ") {
-                    let data = line.strip_prefix("data: ").unwrap();
+    async fn send_message_blocking(
+        &self,
+        messages: Vec<Message>,
+    ) -> Result<ProviderResponse, ProviderError> {
+        let request_body = self.build_body(messages, false);
 
-                    // Handle [DONE] message
-                    if data == "[DONE]" {
-                        callback(StreamResponse {
-                            text: String::new(),
-                            is_done: true,
-                        });
-                        return Ok(full_response);
-                    }
+        let response = self
+            .retry
+            .run(|| async {
+                let response =
+                    recording::send(self.recording.as_ref(), self.build_request(&request_body))
+                        .await?;
+                Self::handle_response_error(response).await
+            })
+            .await?;
 
-                    if let Ok(event_data) = serde_json::from_str::<EventData>(data) {
-                        let is_done = event_data.event_type == "content_block_stop";
+        let response_data = response
+            .json::<NonStreamingResponse>()
+            .await
+            .map_err(|e| ProviderError::parse(format!("Failed to parse response: {}", e)))?;
 
-                        if let Some(delta) = event_data.delta {
-                            if let Some(text) = delta.text {
-                                full_response.push_str(&text);
-                                callback(StreamResponse {
-                                    text,
-                                    is_done: false,
-                                });
-                            }
-                        }
+        let mut response = response_data.into();
+        self.take_structured(&mut response);
+        Ok(response)
+    }
 
-                        if is_done {
-                            callback(StreamResponse {
-                                text: String::new(),
-                                is_done: true,
-                            });
-                            return Ok(full_response);
-                        }
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, ProviderError> {
+        let mut models = Vec::new();
+        let mut after_id: Option<String> = None;
+        loop {
+            let response = self
+                .retry
+                .run(|| async {
+                    let mut request = self
+                        .with_headers(self.client.get(format!("{}/v1/models", self.base_url)))
+                        .query(&[("limit", "1000")]);
+                    if let Some(after_id) = &after_id {
+                        request = request.query(&[("after_id", after_id)]);
                     }
-                }
+                    let response = recording::send(self.recording.as_ref(), request).await?;
+                    Self::handle_response_error(response).await
+                })
+                .await?;
+
+            let page = response
+                .json::<ModelsPage>()
+                .await
+                .map_err(|e| ProviderError::parse(format!("Failed to parse model list: {}", e)))?;
+            models.extend(page.data.into_iter().map(|model| ModelInfo {
+                id: model.id,
+                display_name: model.display_name,
+                ..Default::default()
+            }));
+
+            match page.last_id {
+                Some(last_id) if page.has_more => after_id = Some(last_id),
+                _ => break,
             }
         }
+        Ok(models)
+    }
+}
 
-        // Process any remaining data in buffer
-        if !buffer.is_empty() {
-            if let Ok(event_data) = serde_json::from_str::<EventData>(&buffer) {
-                if let Some(delta) = event_data.delta {
-                    if let Some(text) = delta.text {
-                        full_response.push_str(&text);
-                    }
+/// A Message Batch, as reported by `/v1/messages/batches`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchStatus {
+    pub id: String,
+    /// `in_progress`, `canceling` or `ended`
+    pub processing_status: String,
+    pub request_counts: BatchRequestCounts,
+    #[serde(default)]
+    pub ended_at: Option<String>,
+    /// Unfinished requests expire at this time
+    #[serde(default)]
+    pub expires_at: Option<String>,
+}
+
+impl BatchStatus {
+    pub fn has_ended(&self) -> bool {
+        self.processing_status == "ended"
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BatchRequestCounts {
+    pub processing: u32,
+    pub succeeded: u32,
+    pub errored: u32,
+    pub canceled: u32,
+    pub expired: u32,
+}
+
+/// How one request of a finished batch turned out.
+pub struct BatchResult {
+    pub custom_id: String,
+    pub outcome: Result<ProviderResponse, ProviderError>,
+}
+
+#[derive(Serialize)]
+struct CreateBatch {
+    requests: Vec<BatchRequest>,
+}
+
+#[derive(Serialize)]
+struct BatchRequest {
+    custom_id: String,
+    params: AnthropicRequest,
+}
+
+/// One line of the JSONL results file.
+#[derive(Deserialize)]
+struct BatchResultLine {
+    custom_id: String,
+    result: BatchResultBody,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BatchResultBody {
+    Succeeded { message: NonStreamingResponse },
+    Errored { error: ErrorResponse },
+    Canceled,
+    Expired,
+}
+
+/// The Message Batches API: requests are processed asynchronously, within
+/// 24 hours, at half the price of the Messages API.
+impl AnthropicProvider {
+    fn batches_url(&self, path: &str) -> String {
+        format!("{}/v1/messages/batches{}", self.base_url, path)
+    }
+
+    /// Sends a batches API request with retries. A request that isn't
+    /// `idempotent` is only retried when the server turned it away, since a
+    /// dropped connection or timeout may have followed its acceptance.
+    async fn send_batch_request(
+        &self,
+        request: impl Fn() -> RequestBuilder,
+        idempotent: bool,
+    ) -> Result<reqwest::Response, ProviderError> {
+        self.retry
+            .run(|| async {
+                let result = async {
+                    let response = recording::send(self.recording.as_ref(), request()).await?;
+                    Self::handle_response_error(response).await
                 }
-            }
-        }
+                .await;
+                result.map_err(|failure| match failure.error {
+                    _ if idempotent => failure,
+                    ProviderError::RateLimited { .. } | ProviderError::Overloaded { .. } => failure,
+                    _ => Failure {
+                        retryable: false,
+                        ..failure
+                    },
+                })
+            })
+            .await
+    }
 
-        Ok(full_response)
+    async fn parse_batch_status(response: reqwest::Response) -> Result<BatchStatus, ProviderError> {
+        response
+            .json::<BatchStatus>()
+            .await
+            .map_err(|e| ProviderError::parse(format!("Failed to parse batch: {}", e)))
     }
 
-    async fn send_message_blocking(&self, messages: Vec<Message>) -> Result<String, String> {
-        let request_body = AnthropicRequest {
-            model: self.model.clone(),
-            messages: Self::convert_messages(messages),
-            max_tokens: self.max_tokens,
-            stream: false,
+    /// Submits one Messages request per entry, built with this provider's
+    /// settings, each identified by its `custom_id`.
+    pub async fn create_batch(
+        &self,
+        requests: Vec<(String, Vec<Message>)>,
+    ) -> Result<BatchStatus, ProviderError> {
+        let body = CreateBatch {
+            requests: requests
+                .into_iter()
+                .map(|(custom_id, messages)| BatchRequest {
+                    custom_id,
+                    params: self.build_body(messages, false),
+                })
+                .collect(),
         };
+        // Retrying after the server accepted the batch would submit (and
+        // bill) it twice
+        let response = self
+            .send_batch_request(
+                || {
+                    self.with_headers(self.client.post(self.batches_url("")))
+                        .json(&body)
+                },
+                false,
+            )
+            .await?;
+        Self::parse_batch_status(response).await
+    }
 
+    pub async fn get_batch(&self, batch_id: &str) -> Result<BatchStatus, ProviderError> {
         let response = self
-            .client
-            .post("https://api.anthropic.com/v1/messages")
-            .header("Content-Type", "application/json")
-            .header("X-API-Key", &self.api_key)
-            .header("anthropic-version", "2023-06-01")
-            .json(&request_body)
-            .send()
+            .send_batch_request(
+                || self.with_headers(self.client.get(self.batches_url(&format!("/{}", batch_id)))),
+                true,
+            )
+            .await?;
+        Self::parse_batch_status(response).await
+    }
+
+    /// Asks for the batch to stop; requests already processed keep their
+    /// results. The batch ends once in-flight requests are done.
+    pub async fn cancel_batch(&self, batch_id: &str) -> Result<BatchStatus, ProviderError> {
+        let response = self
+            .send_batch_request(
+                || {
+                    self.with_headers(
+                        self.client
+                            .post(self.batches_url(&format!("/{}/cancel", batch_id))),
+                    )
+                },
+                true,
+            )
+            .await?;
+        Self::parse_batch_status(response).await
+    }
+
+    /// The results of an ended batch, in no particular order.
+    pub async fn batch_results(&self, batch_id: &str) -> Result<Vec<BatchResult>, ProviderError> {
+        let response = self
+            .send_batch_request(
+                || {
+                    self.with_headers(
+                        self.client
+                            .get(self.batches_url(&format!("/{}/results", batch_id))),
+                    )
+                },
+                true,
+            )
+            .await?;
+        let body = response
+            .text()
             .await
-            .map_err(|e| format!("Failed to send request: {}", e))?;
+            .map_err(|e| ProviderError::network(format!("Failed to read batch results: {}", e)))?;
 
-        let response = Self::handle_response_error(response).await?;
+        body.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let line = serde_json::from_str::<BatchResultLine>(line).map_err(|e| {
+                    ProviderError::parse(format!("Failed to parse batch result: {}", e))
+                })?;
+                let outcome = match line.result {
+                    BatchResultBody::Succeeded { message } => Ok(message.into()),
+                    BatchResultBody::Errored { error } => Err(ProviderError::from_error_type(
+                        &error.error.error_type,
+                        error.error.message,
+                    )),
+                    BatchResultBody::Canceled => Err(ProviderError::invalid_request(
+                        "The batch was canceled before this request was processed",
+                    )),
+                    BatchResultBody::Expired => Err(ProviderError::invalid_request(
+                        "The batch expired before this request was processed",
+                    )),
+                };
+                Ok(BatchResult {
+                    custom_id: line.custom_id,
+                    outcome,
+                })
+            })
+            .collect()
+    }
+}
 
-        let response_data = response
-            .json::<NonStreamingResponse>()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apimodels::recording::RecordingMode;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    /// An SSE stream as the Messages API sends it, including a `ping` and
+    /// a comment the parser has to skip.
+    const RECORDED_STREAM: &str = concat!(
+        "event: message_start\n",
+        "data: {\"type\":\"message_start\",\"message\":{\"id\":\"msg_1\",\"type\":\"message\",",
+        "\"role\":\"assistant\",\"content\":[],\"usage\":{\"input_tokens\":12,\"output_tokens\":1}}}\n\n",
+        "event: content_block_start\n",
+        "data: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}\n\n",
+        "event: ping\n",
+        "data: {\"type\":\"ping\"}\n\n",
+        ": keep-alive\n\n",
+        "event: content_block_delta\n",
+        "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Hello\"}}\n\n",
+        "event: content_block_delta\n",
+        "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\", world\"}}\n\n",
+        "event: content_block_stop\n",
+        "data: {\"type\":\"content_block_stop\",\"index\":0}\n\n",
+        "event: message_delta\n",
+        "data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"end_turn\"},\"usage\":{\"output_tokens\":5}}\n\n",
+        "event: message_stop\n",
+        "data: {\"type\":\"message_stop\"}\n\n",
+    );
+
+    #[tokio::test]
+    async fn replays_a_recorded_stream() {
+        let recording = Recording {
+            mode: RecordingMode::Replay,
+            dir: std::env::temp_dir().join(format!("chatopus-replay-{}", ulid::Ulid::new())),
+        };
+        let provider = AnthropicProvider::new(ProviderConfig {
+            api_key: "test-key".to_string(),
+            model: "claude-test".to_string(),
+            max_tokens: 1024,
+            recording: Some(recording.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+        let messages = vec![Message {
+            role: "user".to_string(),
+            content: "Hi".to_string(),
+            ..Default::default()
+        }];
+
+        // Save the exchange where the provider will look for it
+        let request = provider
+            .build_request(&provider.build_body(messages.clone(), true))
+            .build()
+            .unwrap();
+        let path = recording.exchange_path(&request);
+        std::fs::create_dir_all(&recording.dir).unwrap();
+        let exchange = serde_json::json!({
+            "method": "POST",
+            "url": request.url().as_str(),
+            "status": 200,
+            "headers": [["content-type", "text/event-stream"]],
+            "body": RECORDED_STREAM,
+        });
+        std::fs::write(&path, exchange.to_string()).unwrap();
+
+        let streamed = Arc::new(Mutex::new(Vec::new()));
+        let callback: StreamCallback = Box::new({
+            let streamed = Arc::clone(&streamed);
+            move |chunk| streamed.lock().push(chunk)
+        });
+        let response = provider
+            .send_message_streaming(messages, callback)
             .await
-            .map_err(|e| format!("Failed to parse response: {}", e))?;
+            .unwrap();
 
-        let full_text = response_data
-            .content
-            .into_iter()
-            .map(|block| block.text)
-            .collect::<Vec<_>>()
-            .join("");
+        assert_eq!(response.text, "Hello, world");
+        assert_eq!(response.stop_reason.as_deref(), Some("end_turn"));
+        let usage = response.usage.unwrap();
+        assert_eq!(usage.input_tokens, 12);
+        assert_eq!(usage.output_tokens, 5);
 
-        Ok(full_text)
+        let streamed = streamed.lock();
+        let text: Vec<&str> = streamed.iter().map(|chunk| chunk.text.as_str()).collect();
+        assert_eq!(text, ["Hello", ", world", ""]);
+        assert!(streamed.last().unwrap().is_done);
+    }
+
+    #[tokio::test]
+    async fn replay_without_a_recording_is_an_error() {
+        let provider = AnthropicProvider::new(ProviderConfig {
+            model: "claude-test".to_string(),
+            max_tokens: 1024,
+            recording: Some(Recording {
+                mode: RecordingMode::Replay,
+                dir: std::env::temp_dir().join(format!("chatopus-replay-{}", ulid::Ulid::new())),
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let error = provider
+            .send_message_blocking(vec![Message {
+                role: "user".to_string(),
+                content: "Hi".to_string(),
+                ..Default::default()
+            }])
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ProviderError::InvalidRequest { .. }));
     }
 }
@@ -0,0 +1,208 @@
+//! Errors returned by providers, classified so callers (and the UI) can
+//! react to the cause instead of parsing messages.
+
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+/// Serialized with a `code` tag, e.g.
+/// `{"code": "rate_limited", "message": "...", "retry_after_ms": 20000}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum ProviderError {
+    /// Missing, invalid or unauthorized API key
+    Auth {
+        message: String,
+    },
+    RateLimited {
+        message: String,
+        retry_after_ms: Option<u64>,
+    },
+    /// The provider is temporarily out of capacity (HTTP 503/529)
+    Overloaded {
+        message: String,
+    },
+    /// Rejected settings or messages; sending it again won't help
+    InvalidRequest {
+        message: String,
+    },
+    /// The conversation doesn't fit in the model's context window
+    ContextTooLong {
+        message: String,
+    },
+    /// Connection failures, dropped streams and other server-side errors
+    Network {
+        message: String,
+    },
+    Timeout {
+        message: String,
+    },
+    /// A response we couldn't make sense of
+    Parse {
+        message: String,
+    },
+}
+
+impl ProviderError {
+    pub fn message(&self) -> &str {
+        match self {
+            Self::Auth { message }
+            | Self::RateLimited { message, .. }
+            | Self::Overloaded { message }
+            | Self::InvalidRequest { message }
+            | Self::ContextTooLong { message }
+            | Self::Network { message }
+            | Self::Timeout { message }
+            | Self::Parse { message } => message,
+        }
+    }
+
+    fn message_mut(&mut self) -> &mut String {
+        match self {
+            Self::Auth { message }
+            | Self::RateLimited { message, .. }
+            | Self::Overloaded { message }
+            | Self::InvalidRequest { message }
+            | Self::ContextTooLong { message }
+            | Self::Network { message }
+            | Self::Timeout { message }
+            | Self::Parse { message } => message,
+        }
+    }
+
+    /// Appends context to the message, keeping the classification.
+    pub fn with_suffix(mut self, suffix: &str) -> Self {
+        self.message_mut().push_str(suffix);
+        self
+    }
+
+    /// A short description of the kind of failure, for error headings.
+    pub fn title(&self) -> &'static str {
+        match self {
+            Self::Auth { .. } => "Authentication failed",
+            Self::RateLimited { .. } => "Rate limit exceeded",
+            Self::Overloaded { .. } => "Provider overloaded",
+            Self::InvalidRequest { .. } => "Invalid request",
+            Self::ContextTooLong { .. } => "Conversation too long",
+            Self::Network { .. } => "Network error",
+            Self::Timeout { .. } => "Request timed out",
+            Self::Parse { .. } => "Unexpected response",
+        }
+    }
+
+    /// Whether the same request could succeed later.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::RateLimited { .. }
+                | Self::Overloaded { .. }
+                | Self::Network { .. }
+                | Self::Timeout { .. }
+        )
+    }
+
+    pub fn invalid_request(message: impl Into<String>) -> Self {
+        Self::InvalidRequest {
+            message: message.into(),
+        }
+    }
+
+    pub fn parse(message: impl Into<String>) -> Self {
+        Self::Parse {
+            message: message.into(),
+        }
+    }
+
+    pub fn network(message: impl Into<String>) -> Self {
+        Self::Network {
+            message: message.into(),
+        }
+    }
+
+    /// Classifies a non-success HTTP response. `message` is what the
+    /// provider said, which is the only way to tell a context overflow
+    /// apart from other 400s.
+    pub fn from_status(status: StatusCode, message: String, retry_after: Option<Duration>) -> Self {
+        match status.as_u16() {
+            401 | 403 => Self::Auth { message },
+            408 => Self::Timeout { message },
+            429 => Self::RateLimited {
+                message,
+                retry_after_ms: retry_after.map(|delay| delay.as_millis() as u64),
+            },
+            503 | 529 => Self::Overloaded { message },
+            400 | 413 if is_context_overflow(&message) => Self::ContextTooLong { message },
+            500..=599 | 409 => Self::Network { message },
+            _ => Self::InvalidRequest { message },
+        }
+    }
+
+    /// Classifies an error event sent in place of (or in the middle of) a
+    /// response body, by the provider's error type.
+    pub fn from_error_type(error_type: &str, message: String) -> Self {
+        match error_type {
+            // Gemini reports gRPC status names
+            "authentication_error"
+            | "permission_error"
+            | "invalid_api_key"
+            | "UNAUTHENTICATED"
+            | "PERMISSION_DENIED" => Self::Auth { message },
+            "rate_limit_error" | "rate_limit_exceeded" | "RESOURCE_EXHAUSTED" => {
+                Self::RateLimited {
+                    message,
+                    retry_after_ms: None,
+                }
+            }
+            "overloaded_error" | "UNAVAILABLE" => Self::Overloaded { message },
+            "api_error" | "server_error" | "INTERNAL" => Self::Network { message },
+            "DEADLINE_EXCEEDED" => Self::Timeout { message },
+            "context_length_exceeded" => Self::ContextTooLong { message },
+            _ if is_context_overflow(&message) => Self::ContextTooLong { message },
+            _ => Self::InvalidRequest { message },
+        }
+    }
+
+    /// A request that never got a response. One that couldn't even be
+    /// built (e.g. an invalid header value) is a settings mistake, not a
+    /// network problem, so it isn't retried or passed to a fallback.
+    pub fn from_send_error(error: &reqwest::Error) -> Self {
+        let message = format!("Failed to send request: {}", error);
+        if error.is_builder() {
+            Self::InvalidRequest { message }
+        } else if error.is_timeout() {
+            Self::Timeout { message }
+        } else {
+            Self::Network { message }
+        }
+    }
+
+    /// A failure while reading a response body that had already started.
+    pub fn from_read_error(error: &reqwest::Error) -> Self {
+        let message = format!("Error reading chunk: {}", error);
+        if error.is_timeout() {
+            Self::Timeout { message }
+        } else {
+            Self::Network { message }
+        }
+    }
+}
+
+/// Anthropic says "prompt is too long", OpenAI-compatible servers mention
+/// the "maximum context length" and Gemini "the input token count".
+fn is_context_overflow(message: &str) -> bool {
+    let message = message.to_ascii_lowercase();
+    message.contains("prompt is too long")
+        || message.contains("context length")
+        || message.contains("context_length_exceeded")
+        || message.contains("context window")
+        || message.contains("input token count")
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl std::error::Error for ProviderError {}
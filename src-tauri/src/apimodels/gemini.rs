@@ -0,0 +1,750 @@
+use super::error::ProviderError;
+use super::provider::{
+    with_extra_headers, ChatProvider, ContentBlock, Message, ModelInfo, ProviderConfig,
+    ProviderResponse, ResponseSchema, SamplingLimits, SamplingParams, StreamCallback,
+    StreamResponse, ToolCall, ToolDefinition, Usage,
+};
+use super::recording::{self, Recording};
+use super::retry::{Failure, RetryPolicy};
+use super::sse;
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+
+const DEFAULT_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+
+/// Provider for the Gemini API (`generateContent`/`streamGenerateContent`).
+pub struct GeminiProvider {
+    api_key: String,
+    model: String,
+    max_tokens: u32,
+    sampling: SamplingParams,
+    base_url: String,
+    extra_headers: HashMap<String, String>,
+    tools: Vec<ToolDefinition>,
+    retry: RetryPolicy,
+    recording: Option<Recording>,
+    response_schema: Option<ResponseSchema>,
+    client: Client,
+}
+
+#[derive(Serialize)]
+struct GeminiContent {
+    role: &'static str,
+    parts: Vec<Part>,
+}
+
+/// Exactly one field of a part is set, besides `thought_signature`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct Part {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    /// Marks `text` as a thought summary rather than part of the reply
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    thought: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    inline_data: Option<InlineData>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    function_call: Option<FunctionCall>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    function_response: Option<FunctionResponse>,
+    /// Thinking models sign their function calls and reject a later turn
+    /// whose calls come back without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thought_signature: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct InlineData {
+    mime_type: String,
+    data: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct FunctionCall {
+    name: String,
+    #[serde(default)]
+    args: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct FunctionResponse {
+    name: String,
+    response: Value,
+}
+
+impl From<ContentBlock> for Part {
+    fn from(block: ContentBlock) -> Self {
+        match block {
+            ContentBlock::Text { text } => Part {
+                text: Some(text),
+                ..Default::default()
+            },
+            // Text files are inlined; images and PDFs go as inline data
+            ContentBlock::Document {
+                media_type,
+                data,
+                filename,
+            } if media_type.starts_with("text/") => {
+                let text = BASE64.decode(&data).unwrap_or_default();
+                Part {
+                    text: Some(format!("{}:\n{}", filename, String::from_utf8_lossy(&text))),
+                    ..Default::default()
+                }
+            }
+            ContentBlock::Image { media_type, data }
+            | ContentBlock::Document {
+                media_type, data, ..
+            } => Part {
+                inline_data: Some(InlineData {
+                    mime_type: media_type,
+                    data,
+                }),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SystemInstruction {
+    parts: Vec<Part>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerationConfig {
+    max_output_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+    /// `application/json` when the reply has to follow `response_json_schema`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_mime_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_json_schema: Option<Value>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiTool {
+    function_declarations: Vec<FunctionDeclaration>,
+}
+
+#[derive(Serialize)]
+struct FunctionDeclaration {
+    name: String,
+    description: String,
+    parameters: Value,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiRequest {
+    contents: Vec<GeminiContent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<SystemInstruction>,
+    generation_config: GenerationConfig,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<GeminiTool>,
+}
+
+/// Both the blocking response and each streamed chunk.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GenerateContentResponse {
+    #[serde(default)]
+    candidates: Vec<Candidate>,
+    usage_metadata: Option<UsageMetadata>,
+    prompt_feedback: Option<PromptFeedback>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Candidate {
+    content: Option<CandidateContent>,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CandidateContent {
+    #[serde(default)]
+    parts: Vec<Part>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct UsageMetadata {
+    #[serde(default)]
+    prompt_token_count: u32,
+    #[serde(default)]
+    candidates_token_count: u32,
+    #[serde(default)]
+    cached_content_token_count: u32,
+    #[serde(default)]
+    thoughts_token_count: u32,
+}
+
+impl From<UsageMetadata> for Usage {
+    fn from(usage: UsageMetadata) -> Self {
+        // Like OpenAI, the prompt count includes cached tokens; thoughts are
+        // billed as output
+        Usage {
+            input_tokens: usage
+                .prompt_token_count
+                .saturating_sub(usage.cached_content_token_count),
+            output_tokens: usage.candidates_token_count + usage.thoughts_token_count,
+            cache_creation_input_tokens: 0,
+            cache_read_input_tokens: usage.cached_content_token_count,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PromptFeedback {
+    block_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ErrorResponse {
+    error: GeminiError,
+}
+
+#[derive(Deserialize, Debug)]
+struct GeminiError {
+    message: String,
+    /// A gRPC status name, e.g. `INVALID_ARGUMENT`
+    #[serde(default)]
+    status: String,
+}
+
+/// One page of `GET /models`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ModelsPage {
+    #[serde(default)]
+    models: Vec<ModelEntry>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ModelEntry {
+    /// `models/gemini-1.5-pro`
+    name: String,
+    display_name: Option<String>,
+    input_token_limit: Option<u32>,
+    #[serde(default)]
+    supported_generation_methods: Vec<String>,
+}
+
+impl GeminiProvider {
+    pub const SAMPLING_LIMITS: SamplingLimits = SamplingLimits {
+        max_temperature: 2.0,
+        supports_top_k: true,
+        max_stop_sequences: Some(5),
+    };
+
+    pub fn new(config: ProviderConfig) -> Result<Self, ProviderError> {
+        // Long-context requests take a while to process
+        let client = config.network.client(Duration::from_secs(300))?;
+
+        let base_url = config
+            .base_url
+            .filter(|url| !url.trim().is_empty())
+            .unwrap_or_else(|| DEFAULT_BASE_URL.to_string());
+
+        Ok(Self {
+            api_key: config.api_key,
+            model: config.model,
+            max_tokens: config.max_tokens,
+            sampling: config.sampling,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            extra_headers: config.extra_headers,
+            tools: config.tools,
+            retry: config.retry,
+            recording: config.recording,
+            response_schema: config.response_schema,
+            client,
+        })
+    }
+
+    fn build_body(&self, messages: Vec<Message>) -> GeminiRequest {
+        let (system_instruction, contents) = Self::convert_messages(messages);
+        GeminiRequest {
+            contents,
+            system_instruction,
+            generation_config: GenerationConfig {
+                max_output_tokens: self.max_tokens,
+                temperature: self.sampling.temperature,
+                top_p: self.sampling.top_p,
+                top_k: self.sampling.top_k,
+                stop_sequences: self.sampling.stop_sequences.clone(),
+                response_mime_type: self.response_schema.as_ref().map(|_| "application/json"),
+                response_json_schema: self
+                    .response_schema
+                    .as_ref()
+                    .map(|schema| schema.schema.clone()),
+            },
+            tools: if self.tools.is_empty() {
+                Vec::new()
+            } else {
+                vec![GeminiTool {
+                    function_declarations: self
+                        .tools
+                        .iter()
+                        .map(|tool| FunctionDeclaration {
+                            name: tool.name.clone(),
+                            description: tool.description.clone(),
+                            parameters: tool.input_schema.clone(),
+                        })
+                        .collect(),
+                }]
+            },
+        }
+    }
+
+    /// Maps roles onto Gemini's `user`/`model`, moves the system prompt to
+    /// `systemInstruction` and merges consecutive turns of the same role,
+    /// so all the results of one round of tool calls form a single turn.
+    fn convert_messages(messages: Vec<Message>) -> (Option<SystemInstruction>, Vec<GeminiContent>) {
+        let mut system = None;
+        let mut contents: Vec<GeminiContent> = Vec::new();
+        // Function responses are matched to calls by name, not by id
+        let mut call_names: HashMap<String, String> = HashMap::new();
+
+        for msg in messages {
+            let (role, parts) = if msg.role == "system" {
+                system = Some(SystemInstruction {
+                    parts: vec![Part {
+                        text: Some(msg.content),
+                        ..Default::default()
+                    }],
+                });
+                continue;
+            } else if let Some(result) = &msg.tool_result {
+                let name = call_names
+                    .get(&result.tool_use_id)
+                    .cloned()
+                    .unwrap_or_default();
+                let key = if result.is_error { "error" } else { "content" };
+                let part = Part {
+                    function_response: Some(FunctionResponse {
+                        name,
+                        response: json!({ key: msg.content }),
+                    }),
+                    ..Default::default()
+                };
+                ("user", vec![part])
+            } else if msg.role == "assistant" {
+                let mut parts = Vec::new();
+                if !msg.content.is_empty() {
+                    parts.push(Part {
+                        text: Some(msg.content),
+                        ..Default::default()
+                    });
+                }
+                for call in msg.tool_calls {
+                    call_names.insert(call.id, call.name.clone());
+                    parts.push(Part {
+                        function_call: Some(FunctionCall {
+                            name: call.name,
+                            args: call.input,
+                        }),
+                        thought_signature: call.signature,
+                        ..Default::default()
+                    });
+                }
+                // e.g. a reply cut off before any text; Gemini rejects a
+                // turn without parts
+                if parts.is_empty() {
+                    continue;
+                }
+                ("model", parts)
+            } else {
+                let parts = msg.content_blocks().into_iter().map(Part::from).collect();
+                ("user", parts)
+            };
+
+            match contents.last_mut() {
+                Some(last) if last.role == role => last.parts.extend(parts),
+                _ => contents.push(GeminiContent { role, parts }),
+            }
+        }
+
+        (system, contents)
+    }
+
+    fn with_headers(&self, request: RequestBuilder) -> RequestBuilder {
+        let request = request
+            .header("Content-Type", "application/json")
+            .header("x-goog-api-key", &self.api_key);
+        with_extra_headers(request, &self.extra_headers)
+    }
+
+    fn build_request(&self, request_body: &GeminiRequest, stream: bool) -> RequestBuilder {
+        // `alt=sse` streams the chunks as server-sent events instead of one
+        // long JSON array
+        let url = if stream {
+            format!(
+                "{}/models/{}:streamGenerateContent?alt=sse",
+                self.base_url, self.model
+            )
+        } else {
+            format!("{}/models/{}:generateContent", self.base_url, self.model)
+        };
+        self.with_headers(self.client.post(url)).json(request_body)
+    }
+
+    async fn handle_response_error(
+        response: reqwest::Response,
+    ) -> Result<reqwest::Response, Failure> {
+        if !response.status().is_success() {
+            let status = response.status();
+            let headers = response.headers().clone();
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Failed to read error response".to_string());
+
+            let parsed = serde_json::from_str::<ErrorResponse>(&error_text).ok();
+            let message = match &parsed {
+                Some(error_response) => format!(
+                    "API error ({}): {} - {}",
+                    status, error_response.error.status, error_response.error.message
+                ),
+                None => format!("Request failed ({}): {}", status, error_text),
+            };
+            let mut failure = Failure::from_status(status, &headers, message);
+            // A bad key is a 400 INVALID_ARGUMENT rather than a 401
+            if let Some(error_response) = parsed {
+                if error_response.error.message.contains("API key not valid")
+                    || error_response.error.status == "UNAUTHENTICATED"
+                {
+                    failure.error = ProviderError::Auth {
+                        message: failure.error.to_string(),
+                    };
+                }
+            }
+            return Err(failure);
+        }
+        Ok(response)
+    }
+
+    /// Maps `finishReason` onto the Anthropic stop reasons used by `ProviderResponse`.
+    fn normalize_stop_reason(finish_reason: String) -> String {
+        match finish_reason.as_str() {
+            "STOP" => "end_turn".to_string(),
+            "MAX_TOKENS" => "max_tokens".to_string(),
+            _ => finish_reason.to_lowercase(),
+        }
+    }
+
+    /// Applies one response (or streamed chunk) to `result`, streaming text
+    /// through `callback`. Returns whether the candidate finished.
+    fn apply_response(
+        response: GenerateContentResponse,
+        result: &mut ProviderResponse,
+        callback: Option<&StreamCallback>,
+    ) -> Result<bool, ProviderError> {
+        if let Some(reason) = response
+            .prompt_feedback
+            .and_then(|feedback| feedback.block_reason)
+        {
+            return Err(ProviderError::invalid_request(format!(
+                "Prompt blocked: {}",
+                reason
+            )));
+        }
+
+        if let Some(usage) = response.usage_metadata {
+            result.usage = Some(usage.into());
+        }
+
+        let mut finished = false;
+        // Only one candidate is requested
+        if let Some(candidate) = response.candidates.into_iter().next() {
+            for part in candidate
+                .content
+                .map(|content| content.parts)
+                .unwrap_or_default()
+            {
+                match part {
+                    Part {
+                        text: Some(text),
+                        thought,
+                        ..
+                    } if !text.is_empty() => {
+                        if !thought {
+                            result.text.push_str(&text);
+                        }
+                        if let Some(callback) = callback {
+                            let (text, thinking) = if thought {
+                                (String::new(), text)
+                            } else {
+                                (text, String::new())
+                            };
+                            callback(StreamResponse {
+                                text,
+                                is_done: false,
+                                thinking,
+                            });
+                        }
+                    }
+                    Part {
+                        function_call: Some(call),
+                        thought_signature,
+                        ..
+                    } => {
+                        // Gemini has no call ids, so number them within the response
+                        result.tool_calls.push(ToolCall {
+                            id: format!("call_{}", result.tool_calls.len()),
+                            name: call.name,
+                            input: call.args,
+                            signature: thought_signature,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+
+            if let Some(finish_reason) = candidate.finish_reason {
+                finished = true;
+                result.stop_reason = Some(if result.tool_calls.is_empty() {
+                    Self::normalize_stop_reason(finish_reason)
+                } else {
+                    "tool_use".to_string()
+                });
+            }
+        }
+        Ok(finished)
+    }
+
+    /// Sends one streaming request and reads it until the candidate finishes.
+    async fn stream_attempt(
+        &self,
+        request_body: &GeminiRequest,
+        callback: &StreamCallback,
+    ) -> Result<ProviderResponse, Failure> {
+        let response = recording::send(
+            self.recording.as_ref(),
+            self.build_request(request_body, true),
+        )
+        .await?;
+
+        let response = Self::handle_response_error(response).await?;
+
+        let mut result = ProviderResponse::default();
+        let mut finished = false;
+        let stream_result = sse::for_each_event(response, |event| {
+            // Errors can arrive in place of a chunk
+            if let Ok(error_response) = serde_json::from_str::<ErrorResponse>(&event.data) {
+                let message = format!("API error: {}", error_response.error.message);
+                return Err(
+                    ProviderError::from_error_type(&error_response.error.status, message).into(),
+                );
+            }
+
+            let chunk = match serde_json::from_str::<GenerateContentResponse>(&event.data) {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    log::warn!("Skipping unparseable stream chunk: {}", e);
+                    return Ok(false);
+                }
+            };
+            // Usage arrives with the final chunk, so keep reading after the
+            // finish reason until the stream closes
+            finished |= Self::apply_response(chunk, &mut result, Some(callback))?;
+            Ok(false)
+        })
+        .await;
+
+        let partial = !result.text.is_empty();
+        stream_result.map_err(|failure| failure.with_partial(partial))?;
+        if !finished {
+            return Err(Failure::from(ProviderError::network(
+                "Stream ended before a finish reason",
+            ))
+            .with_partial(partial));
+        }
+
+        Ok(result)
+    }
+}
+
+#[async_trait]
+impl ChatProvider for GeminiProvider {
+    fn supports_streaming(&self) -> bool {
+        true
+    }
+
+    async fn send_message_streaming(
+        &self,
+        messages: Vec<Message>,
+        callback: StreamCallback,
+    ) -> Result<ProviderResponse, ProviderError> {
+        let request_body = self.build_body(messages);
+
+        let result = self
+            .retry
+            .run(|| self.stream_attempt(&request_body, &callback))
+            .await?;
+
+        callback(StreamResponse {
+            text: String::new(),
+            is_done: true,
+            thinking: String::new(),
+        });
+
+        Ok(result)
+    }
+
+    async fn send_message_blocking(
+        &self,
+        messages: Vec<Message>,
+    ) -> Result<ProviderResponse, ProviderError> {
+        let request_body = self.build_body(messages);
+
+        let response = self
+            .retry
+            .run(|| async {
+                let response = recording::send(
+                    self.recording.as_ref(),
+                    self.build_request(&request_body, false),
+                )
+                .await?;
+                Self::handle_response_error(response).await
+            })
+            .await?;
+
+        let response_data = response
+            .json::<GenerateContentResponse>()
+            .await
+            .map_err(|e| ProviderError::parse(format!("Failed to parse response: {}", e)))?;
+
+        let mut result = ProviderResponse::default();
+        Self::apply_response(response_data, &mut result, None)?;
+
+        Ok(result)
+    }
+
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, ProviderError> {
+        let mut models = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let response = self
+                .retry
+                .run(|| async {
+                    let mut request = self
+                        .with_headers(self.client.get(format!("{}/models", self.base_url)))
+                        .query(&[("pageSize", "1000")]);
+                    if let Some(page_token) = &page_token {
+                        request = request.query(&[("pageToken", page_token)]);
+                    }
+                    let response = recording::send(self.recording.as_ref(), request).await?;
+                    Self::handle_response_error(response).await
+                })
+                .await?;
+
+            let page = response
+                .json::<ModelsPage>()
+                .await
+                .map_err(|e| ProviderError::parse(format!("Failed to parse model list: {}", e)))?;
+            // Skip embedding and other models that can't chat
+            models.extend(
+                page.models
+                    .into_iter()
+                    .filter(|model| {
+                        model
+                            .supported_generation_methods
+                            .iter()
+                            .any(|method| method == "generateContent")
+                    })
+                    .map(|model| ModelInfo {
+                        id: model
+                            .name
+                            .strip_prefix("models/")
+                            .unwrap_or(&model.name)
+                            .to_string(),
+                        display_name: model.display_name,
+                        context_window: model.input_token_limit,
+                        ..Default::default()
+                    }),
+            );
+
+            match page.next_page_token {
+                Some(token) if !token.is_empty() => page_token = Some(token),
+                _ => break,
+            }
+        }
+        Ok(models)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn function_call_signatures_are_sent_back() {
+        let chunk: GenerateContentResponse = serde_json::from_value(json!({
+            "candidates": [{
+                "content": {
+                    "role": "model",
+                    "parts": [{
+                        "functionCall": { "name": "get_weather", "args": { "city": "Oslo" } },
+                        "thoughtSignature": "c2lnbmF0dXJl"
+                    }]
+                },
+                "finishReason": "STOP"
+            }]
+        }))
+        .unwrap();
+        let mut response = ProviderResponse::default();
+        assert!(GeminiProvider::apply_response(chunk, &mut response, None).unwrap());
+        assert_eq!(response.stop_reason.as_deref(), Some("tool_use"));
+        let call = &response.tool_calls[0];
+        assert_eq!(call.signature.as_deref(), Some("c2lnbmF0dXJl"));
+
+        let (_, contents) = GeminiProvider::convert_messages(vec![Message {
+            role: "assistant".to_string(),
+            tool_calls: response.tool_calls,
+            ..Default::default()
+        }]);
+        let sent = serde_json::to_value(&contents).unwrap();
+        assert_eq!(
+            sent[0]["parts"][0]["thoughtSignature"],
+            json!("c2lnbmF0dXJl")
+        );
+    }
+
+    #[test]
+    fn empty_model_turns_are_skipped() {
+        let message = |role: &str, content: &str| Message {
+            role: role.to_string(),
+            content: content.to_string(),
+            ..Default::default()
+        };
+        let (_, contents) = GeminiProvider::convert_messages(vec![
+            message("user", "Hello"),
+            message("assistant", ""),
+            message("user", "Are you there?"),
+        ]);
+        assert_eq!(contents.len(), 1);
+        assert_eq!(contents[0].role, "user");
+        assert_eq!(contents[0].parts.len(), 2);
+    }
+}
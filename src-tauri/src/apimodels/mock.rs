@@ -0,0 +1,395 @@
+//! A provider that plays scripted responses from a fixture file, giving the
+//! chat flow a deterministic backend that needs no network or API key.
+//!
+//! The fixture is a JSON file like:
+//!
+//! ```json
+//! {
+//!   "responses": [
+//!     { "error": { "code": "rate_limited", "message": "Slow down", "retry_after_ms": 10 } },
+//!     { "chunks": ["Hello", ", world"], "delay_ms": 50 },
+//!     { "when": "weather", "tool_calls": [{ "id": "call_1", "name": "get_weather", "input": {} }] }
+//!   ]
+//! }
+//! ```
+//!
+//! Every request, retries included, takes the next response in the script
+//! whose `when` (if set) appears in the last message, wrapping around at
+//! the end. The position is kept per fixture for the life of the process.
+
+use super::error::ProviderError;
+use super::provider::{
+    ChatProvider, Message, ModelInfo, ProviderConfig, ProviderResponse, SamplingLimits,
+    StreamCallback, StreamResponse, ToolCall, Usage,
+};
+use super::retry::{Failure, RetryPolicy};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+/// Next position in each fixture's script.
+static CURSORS: LazyLock<Mutex<HashMap<PathBuf, usize>>> = LazyLock::new(Default::default);
+
+pub struct MockProvider {
+    model: String,
+    fixture: Option<PathBuf>,
+    retry: RetryPolicy,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Fixture {
+    responses: Vec<MockResponse>,
+    /// Returned by `list_models`; defaults to the configured model
+    models: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct MockResponse {
+    /// Only used when the last message contains this text
+    when: Option<String>,
+    /// Streamed one after another; the reply is their concatenation
+    chunks: Vec<String>,
+    /// Streamed as extended thinking before the reply
+    thinking: Vec<String>,
+    /// Pause before each chunk
+    delay_ms: u64,
+    tool_calls: Vec<ToolCall>,
+    /// Defaults to `tool_use` when there are tool calls, `end_turn` otherwise
+    stop_reason: Option<String>,
+    usage: Option<Usage>,
+    /// Fails the request after streaming the chunks, if any
+    error: Option<ProviderError>,
+}
+
+impl MockProvider {
+    pub const SAMPLING_LIMITS: SamplingLimits = SamplingLimits {
+        max_temperature: 2.0,
+        supports_top_k: true,
+        max_stop_sequences: None,
+    };
+
+    pub fn new(config: ProviderConfig) -> Self {
+        Self {
+            model: config.model,
+            fixture: config
+                .fixture
+                .filter(|path| !path.trim().is_empty())
+                .map(PathBuf::from),
+            retry: config.retry,
+        }
+    }
+
+    async fn load_fixture(path: &Path) -> Result<Fixture, ProviderError> {
+        let contents = tokio::fs::read(path).await.map_err(|e| {
+            ProviderError::invalid_request(format!(
+                "Failed to read fixture {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        serde_json::from_slice(&contents).map_err(|e| {
+            ProviderError::invalid_request(format!(
+                "Failed to parse fixture {}: {}",
+                path.display(),
+                e
+            ))
+        })
+    }
+
+    /// Takes the next scripted response for `messages`. Without a fixture
+    /// the last message is echoed back.
+    async fn next_response(&self, messages: &[Message]) -> Result<MockResponse, ProviderError> {
+        let last = messages
+            .last()
+            .map(|message| message.content.as_str())
+            .unwrap_or_default();
+        let Some(path) = &self.fixture else {
+            return Ok(MockResponse {
+                chunks: vec![last.to_string()],
+                ..Default::default()
+            });
+        };
+        let fixture = Self::load_fixture(path).await?;
+
+        let responses = fixture.responses;
+        let mut cursors = CURSORS.lock();
+        let cursor = cursors.entry(path.clone()).or_default();
+        let found = (0..responses.len())
+            .map(|offset| (*cursor + offset) % responses.len())
+            .find(|&index| {
+                responses[index]
+                    .when
+                    .as_ref()
+                    .is_none_or(|when| last.contains(when.as_str()))
+            });
+        match found {
+            Some(index) => {
+                *cursor = index + 1;
+                Ok(responses[index].clone())
+            }
+            None => Err(ProviderError::invalid_request(format!(
+                "No response in {} matches the last message",
+                path.display()
+            ))),
+        }
+    }
+
+    async fn attempt(
+        &self,
+        messages: &[Message],
+        callback: Option<&StreamCallback>,
+    ) -> Result<ProviderResponse, Failure> {
+        let response = self.next_response(messages).await?;
+        let delay = Duration::from_millis(response.delay_ms);
+
+        let mut result = ProviderResponse::default();
+        for thinking in response.thinking {
+            tokio::time::sleep(delay).await;
+            if let Some(callback) = callback {
+                callback(StreamResponse {
+                    text: String::new(),
+                    is_done: false,
+                    thinking,
+                });
+            }
+        }
+        for chunk in response.chunks {
+            tokio::time::sleep(delay).await;
+            result.text.push_str(&chunk);
+            if let Some(callback) = callback {
+                callback(StreamResponse {
+                    text: chunk,
+                    is_done: false,
+                    thinking: String::new(),
+                });
+            }
+        }
+
+        if let Some(error) = response.error {
+            let retry_after = match &error {
+                ProviderError::RateLimited { retry_after_ms, .. } => {
+                    retry_after_ms.map(Duration::from_millis)
+                }
+                _ => None,
+            };
+            let partial = callback.is_some() && !result.text.is_empty();
+            return Err(Failure {
+                retry_after,
+                ..Failure::from(error)
+            }
+            .with_partial(partial));
+        }
+
+        result.stop_reason = Some(response.stop_reason.unwrap_or_else(|| {
+            if response.tool_calls.is_empty() {
+                "end_turn".to_string()
+            } else {
+                "tool_use".to_string()
+            }
+        }));
+        result.tool_calls = response.tool_calls;
+        result.usage = response.usage;
+        Ok(result)
+    }
+}
+
+#[async_trait]
+impl ChatProvider for MockProvider {
+    fn supports_streaming(&self) -> bool {
+        true
+    }
+
+    async fn send_message_streaming(
+        &self,
+        messages: Vec<Message>,
+        callback: StreamCallback,
+    ) -> Result<ProviderResponse, ProviderError> {
+        let result = self
+            .retry
+            .run(|| self.attempt(&messages, Some(&callback)))
+            .await?;
+
+        callback(StreamResponse {
+            text: String::new(),
+            is_done: true,
+            thinking: String::new(),
+        });
+
+        Ok(result)
+    }
+
+    async fn send_message_blocking(
+        &self,
+        messages: Vec<Message>,
+    ) -> Result<ProviderResponse, ProviderError> {
+        self.retry.run(|| self.attempt(&messages, None)).await
+    }
+
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, ProviderError> {
+        let models = match &self.fixture {
+            Some(path) => Self::load_fixture(path).await?.models,
+            None => Vec::new(),
+        };
+        let models = if models.is_empty() {
+            vec![self.model.clone()]
+        } else {
+            models
+        };
+        Ok(models
+            .into_iter()
+            .map(|id| ModelInfo {
+                id,
+                ..Default::default()
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apimodels::RetryEvent;
+    use std::sync::Arc;
+
+    /// Writes `fixture` to a file of its own, so each test has its own cursor.
+    fn provider(name: &str, fixture: serde_json::Value, retry: RetryPolicy) -> MockProvider {
+        let path =
+            std::env::temp_dir().join(format!("chatopus-mock-{}-{}.json", name, ulid::Ulid::new()));
+        std::fs::write(&path, fixture.to_string()).unwrap();
+        MockProvider::new(ProviderConfig {
+            model: "mock".to_string(),
+            fixture: Some(path.to_string_lossy().into_owned()),
+            retry,
+            ..Default::default()
+        })
+    }
+
+    fn user(content: &str) -> Vec<Message> {
+        vec![Message {
+            role: "user".to_string(),
+            content: content.to_string(),
+            ..Default::default()
+        }]
+    }
+
+    #[tokio::test]
+    async fn picks_matching_responses_and_wraps_around() {
+        let provider = provider(
+            "when",
+            serde_json::json!({
+                "responses": [
+                    { "when": "weather", "chunks": ["Sunny"] },
+                    { "chunks": ["Hello", ", world"] },
+                ]
+            }),
+            RetryPolicy::default(),
+        );
+
+        // The first response doesn't match, so the second is used
+        let reply = provider.send_message_blocking(user("hi")).await.unwrap();
+        assert_eq!(reply.text, "Hello, world");
+        assert_eq!(reply.stop_reason.as_deref(), Some("end_turn"));
+
+        // The end of the script wraps around to the first response
+        let reply = provider
+            .send_message_blocking(user("what's the weather?"))
+            .await
+            .unwrap();
+        assert_eq!(reply.text, "Sunny");
+
+        let reply = provider.send_message_blocking(user("hi")).await.unwrap();
+        assert_eq!(reply.text, "Hello, world");
+    }
+
+    #[tokio::test]
+    async fn no_matching_response_is_an_error() {
+        let provider = provider(
+            "no-match",
+            serde_json::json!({ "responses": [{ "when": "weather", "chunks": ["Sunny"] }] }),
+            RetryPolicy::default(),
+        );
+
+        let error = provider
+            .send_message_blocking(user("hi"))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ProviderError::InvalidRequest { .. }));
+    }
+
+    #[tokio::test]
+    async fn scripted_errors_are_retried_after_retry_after_ms() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let retry = RetryPolicy {
+            on_retry: Some({
+                let events = Arc::clone(&events);
+                Arc::new(move |event: RetryEvent| events.lock().push(event))
+            }),
+            ..Default::default()
+        };
+        let provider = provider(
+            "error",
+            serde_json::json!({
+                "responses": [
+                    {
+                        "chunks": ["Partial"],
+                        "error": { "code": "rate_limited", "message": "Slow down", "retry_after_ms": 10 }
+                    },
+                    { "chunks": ["Done"] },
+                ]
+            }),
+            retry,
+        );
+
+        let streamed = Arc::new(Mutex::new(String::new()));
+        let callback: StreamCallback = Box::new({
+            let streamed = Arc::clone(&streamed);
+            move |chunk| streamed.lock().push_str(&chunk.text)
+        });
+        let reply = provider
+            .send_message_streaming(user("hi"), callback)
+            .await
+            .unwrap();
+        assert_eq!(reply.text, "Done");
+        assert_eq!(*streamed.lock(), "PartialDone");
+
+        let events = events.lock();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].attempt, 2);
+        assert_eq!(events[0].delay_ms, 10);
+        assert!(events[0].discard_partial);
+    }
+
+    #[tokio::test]
+    async fn scripted_errors_are_returned_once_retries_run_out() {
+        let provider = provider(
+            "error-exhausted",
+            serde_json::json!({
+                "responses": [{
+                    "error": { "code": "rate_limited", "message": "Slow down", "retry_after_ms": 1 }
+                }]
+            }),
+            RetryPolicy {
+                max_attempts: 2,
+                ..Default::default()
+            },
+        );
+
+        let error = provider
+            .send_message_blocking(user("hi"))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            ProviderError::RateLimited {
+                retry_after_ms: Some(1),
+                ..
+            }
+        ));
+    }
+}
@@ -1,6 +1,23 @@
 pub mod anthropic;
+pub mod error;
+pub mod gemini;
+pub mod mock;
+pub mod network;
+pub mod ollama;
+pub mod openai;
 pub mod provider;
+pub mod recording;
+pub mod retry;
+pub mod scheduler;
+pub mod sse;
 
 pub use provider::{
-    ChatProvider, Message, MessageReactions, ProviderConfig, ProviderFactory, StreamResponse,
+    Attachment, AttachmentKind, ChatProvider, Comparison, Message, MessageReactions, ModelInfo,
+    ProviderConfig, ProviderFactory, ResponseSchema, SamplingParams, StreamResponse, ThinkingBlock,
+    ToolCall, ToolDefinition, ToolResult, Usage,
 };
+pub use error::ProviderError;
+pub use network::NetworkSettings;
+pub use recording::Recording;
+pub use retry::{RetryEvent, RetryPolicy};
+pub use scheduler::{QueueEvent, RateLimits, Scheduler};
@@ -0,0 +1,489 @@
+use super::error::ProviderError;
+use super::provider::{
+    with_extra_headers, ChatProvider, ContentBlock, Message, ModelInfo, ProviderConfig,
+    ProviderResponse, ResponseSchema, SamplingLimits, SamplingParams, StreamCallback,
+    StreamResponse, ToolCall, ToolDefinition, Usage,
+};
+use super::recording::{self, Recording};
+use super::retry::{Failure, RetryPolicy};
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures_util::StreamExt;
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+
+const DEFAULT_BASE_URL: &str = "http://localhost:11434";
+
+/// Provider for a local Ollama server using its native `/api/chat` endpoint.
+pub struct OllamaProvider {
+    model: String,
+    max_tokens: u32,
+    num_ctx: Option<u32>,
+    sampling: SamplingParams,
+    base_url: String,
+    extra_headers: HashMap<String, String>,
+    tools: Vec<ToolDefinition>,
+    retry: RetryPolicy,
+    recording: Option<Recording>,
+    response_schema: Option<ResponseSchema>,
+    client: Client,
+}
+
+#[derive(Serialize)]
+struct OllamaMessage {
+    role: String,
+    content: String,
+    /// Base64-encoded images, for vision models
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<OllamaToolCall>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct OllamaToolCall {
+    function: OllamaFunctionCall,
+}
+
+/// Unlike the OpenAI protocol, Ollama sends `arguments` as a JSON object.
+#[derive(Serialize, Deserialize, Debug)]
+struct OllamaFunctionCall {
+    name: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+#[derive(Serialize)]
+struct OllamaTool {
+    #[serde(rename = "type")]
+    tool_type: &'static str,
+    function: OllamaFunction,
+}
+
+#[derive(Serialize)]
+struct OllamaFunction {
+    name: String,
+    description: String,
+    parameters: Value,
+}
+
+#[derive(Serialize)]
+struct OllamaOptions {
+    num_predict: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_ctx: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct OllamaRequest {
+    model: String,
+    messages: Vec<OllamaMessage>,
+    stream: bool,
+    options: OllamaOptions,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<OllamaTool>,
+    /// A JSON Schema the reply is constrained to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<Value>,
+}
+
+/// One line of the NDJSON stream, and also the shape of the non-streaming reply.
+#[derive(Deserialize, Debug)]
+struct ChatChunk {
+    message: Option<ChunkMessage>,
+    #[serde(default)]
+    done: bool,
+    done_reason: Option<String>,
+    error: Option<String>,
+    /// Token counts, only present on the final chunk
+    prompt_eval_count: Option<u32>,
+    eval_count: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChunkMessage {
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    tool_calls: Vec<OllamaToolCall>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ErrorResponse {
+    error: String,
+}
+
+impl OllamaProvider {
+    pub const SAMPLING_LIMITS: SamplingLimits = SamplingLimits {
+        max_temperature: 2.0,
+        supports_top_k: true,
+        max_stop_sequences: None,
+    };
+
+    pub fn new(config: ProviderConfig) -> Result<Self, ProviderError> {
+        // Local models can take a while to load, so be generous with the timeout
+        let client = config.network.client(Duration::from_secs(300))?;
+
+        let base_url = config
+            .base_url
+            .filter(|url| !url.trim().is_empty())
+            .unwrap_or_else(|| DEFAULT_BASE_URL.to_string());
+
+        Ok(Self {
+            model: config.model,
+            max_tokens: config.max_tokens,
+            num_ctx: config.num_ctx,
+            sampling: config.sampling,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            extra_headers: config.extra_headers,
+            tools: config.tools,
+            retry: config.retry,
+            recording: config.recording,
+            response_schema: config.response_schema,
+            client,
+        })
+    }
+
+    fn build_body(
+        &self,
+        messages: Vec<Message>,
+        stream: bool,
+    ) -> Result<OllamaRequest, ProviderError> {
+        Ok(OllamaRequest {
+            model: self.model.clone(),
+            messages: Self::convert_messages(messages)?,
+            stream,
+            options: OllamaOptions {
+                num_predict: self.max_tokens,
+                num_ctx: self.num_ctx,
+                temperature: self.sampling.temperature,
+                top_p: self.sampling.top_p,
+                top_k: self.sampling.top_k,
+                stop: self.sampling.stop_sequences.clone(),
+            },
+            tools: self
+                .tools
+                .iter()
+                .map(|tool| OllamaTool {
+                    tool_type: "function",
+                    function: OllamaFunction {
+                        name: tool.name.clone(),
+                        description: tool.description.clone(),
+                        parameters: tool.input_schema.clone(),
+                    },
+                })
+                .collect(),
+            format: self
+                .response_schema
+                .as_ref()
+                .map(|schema| schema.schema.clone()),
+        })
+    }
+
+    fn post_chat(&self, request_body: &OllamaRequest) -> RequestBuilder {
+        let request = self.client.post(format!("{}/api/chat", self.base_url));
+        with_extra_headers(request, &self.extra_headers).json(request_body)
+    }
+
+    async fn handle_response_error(
+        response: reqwest::Response,
+    ) -> Result<reqwest::Response, Failure> {
+        if !response.status().is_success() {
+            let status = response.status();
+            let headers = response.headers().clone();
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Failed to read error response".to_string());
+
+            // Try to parse as ErrorResponse
+            let message = match serde_json::from_str::<ErrorResponse>(&error_text) {
+                Ok(error_response) => format!("API error ({}): {}", status, error_response.error),
+                Err(_) => format!("Request failed ({}): {}", status, error_text),
+            };
+            return Err(Failure::from_status(status, &headers, message));
+        }
+        Ok(response)
+    }
+
+    /// Ollama takes images as a separate list and has no document type, so
+    /// text files are inlined and other documents are rejected.
+    fn convert_messages(messages: Vec<Message>) -> Result<Vec<OllamaMessage>, ProviderError> {
+        messages
+            .into_iter()
+            .map(|msg| {
+                let mut content = String::new();
+                let mut images = Vec::new();
+                for block in msg.content_blocks() {
+                    match block {
+                        ContentBlock::Text { text } => content.push_str(&text),
+                        ContentBlock::Image { data, .. } => images.push(data),
+                        ContentBlock::Document {
+                            media_type,
+                            data,
+                            filename,
+                        } if media_type.starts_with("text/") => {
+                            let text = BASE64.decode(&data).unwrap_or_default();
+                            content.push_str(&format!(
+                                "{}:\n{}\n\n",
+                                filename,
+                                String::from_utf8_lossy(&text)
+                            ));
+                        }
+                        ContentBlock::Document { filename, .. } => {
+                            return Err(ProviderError::invalid_request(format!(
+                                "Ollama can't read {}: only images and text files are supported",
+                                filename
+                            )));
+                        }
+                    }
+                }
+
+                Ok(OllamaMessage {
+                    role: if msg.tool_result.is_some() {
+                        "tool".to_string()
+                    } else {
+                        msg.role
+                    },
+                    content,
+                    images,
+                    tool_calls: msg
+                        .tool_calls
+                        .into_iter()
+                        .map(|call| OllamaToolCall {
+                            function: OllamaFunctionCall {
+                                name: call.name,
+                                arguments: call.input,
+                            },
+                        })
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+
+    /// Applies one chunk to `result`, streaming any text through `callback`.
+    /// Returns `Ok(true)` once the final chunk has been seen.
+    fn apply_chunk(
+        chunk: ChatChunk,
+        result: &mut ProviderResponse,
+        callback: Option<&StreamCallback>,
+    ) -> Result<bool, ProviderError> {
+        if let Some(error) = chunk.error {
+            return Err(ProviderError::from_error_type(
+                "",
+                format!("API error: {}", error),
+            ));
+        }
+
+        if let Some(message) = chunk.message {
+            if !message.content.is_empty() {
+                result.text.push_str(&message.content);
+                if let Some(callback) = callback {
+                    callback(StreamResponse {
+                        text: message.content,
+                        is_done: false,
+                        thinking: String::new(),
+                    });
+                }
+            }
+            // Ollama has no tool call ids, so number them within the response
+            for call in message.tool_calls {
+                result.tool_calls.push(ToolCall {
+                    id: format!("call_{}", result.tool_calls.len()),
+                    name: call.function.name,
+                    input: call.function.arguments,
+                    signature: None,
+                });
+            }
+        }
+
+        if chunk.done {
+            result.usage = Some(Usage {
+                input_tokens: chunk.prompt_eval_count.unwrap_or(0),
+                output_tokens: chunk.eval_count.unwrap_or(0),
+                ..Default::default()
+            });
+            result.stop_reason = Some(if !result.tool_calls.is_empty() {
+                "tool_use".to_string()
+            } else {
+                match chunk.done_reason.as_deref() {
+                    Some("length") => "max_tokens".to_string(),
+                    _ => "end_turn".to_string(),
+                }
+            });
+        }
+
+        Ok(chunk.done)
+    }
+
+    /// Parses one NDJSON line of the stream.
+    fn handle_line(
+        line: &[u8],
+        result: &mut ProviderResponse,
+        callback: &StreamCallback,
+    ) -> Result<bool, ProviderError> {
+        let line = std::str::from_utf8(line)
+            .map_err(|e| ProviderError::parse(format!("Invalid UTF-8 in stream: {}", e)))?
+            .trim();
+        if line.is_empty() {
+            return Ok(false);
+        }
+
+        let chunk = serde_json::from_str::<ChatChunk>(line)
+            .map_err(|e| ProviderError::parse(format!("Failed to parse stream chunk: {}", e)))?;
+
+        Self::apply_chunk(chunk, result, Some(callback))
+    }
+
+    /// Sends one streaming request and reads it to the final chunk.
+    async fn stream_attempt(
+        &self,
+        request_body: &OllamaRequest,
+        callback: &StreamCallback,
+    ) -> Result<ProviderResponse, Failure> {
+        let response =
+            recording::send(self.recording.as_ref(), self.post_chat(request_body)).await?;
+
+        let response = Self::handle_response_error(response).await?;
+        let mut stream = response.bytes_stream();
+
+        let mut result = ProviderResponse::default();
+        // Buffer raw bytes so multi-byte characters split across chunks stay intact
+        let mut buffer: Vec<u8> = Vec::new();
+
+        while let Some(item) = stream.next().await {
+            let chunk = item.map_err(|e| {
+                Failure::from(ProviderError::from_read_error(&e))
+                    .with_partial(!result.text.is_empty())
+            })?;
+            buffer.extend_from_slice(&chunk);
+
+            while let Some(end_index) = buffer.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end_index).collect();
+                let done = Self::handle_line(&line, &mut result, callback)
+                    .map_err(|e| Failure::from(e).with_partial(!result.text.is_empty()))?;
+                if done {
+                    return Ok(result);
+                }
+            }
+        }
+
+        // The final chunk may not be newline-terminated
+        let done = Self::handle_line(&buffer, &mut result, callback)
+            .map_err(|e| Failure::from(e).with_partial(!result.text.is_empty()))?;
+        if !done {
+            return Err(
+                Failure::from(ProviderError::network("Stream ended before done"))
+                    .with_partial(!result.text.is_empty()),
+            );
+        }
+
+        Ok(result)
+    }
+}
+
+/// `GET /api/tags`: the models pulled locally.
+#[derive(Deserialize)]
+struct TagsResponse {
+    models: Vec<LocalModel>,
+}
+
+#[derive(Deserialize)]
+struct LocalModel {
+    name: String,
+}
+
+#[async_trait]
+impl ChatProvider for OllamaProvider {
+    fn supports_streaming(&self) -> bool {
+        true
+    }
+
+    async fn send_message_streaming(
+        &self,
+        messages: Vec<Message>,
+        callback: StreamCallback,
+    ) -> Result<ProviderResponse, ProviderError> {
+        let request_body = self.build_body(messages, true)?;
+
+        let result = self
+            .retry
+            .run(|| self.stream_attempt(&request_body, &callback))
+            .await?;
+
+        callback(StreamResponse {
+            text: String::new(),
+            is_done: true,
+            thinking: String::new(),
+        });
+
+        Ok(result)
+    }
+
+    async fn send_message_blocking(
+        &self,
+        messages: Vec<Message>,
+    ) -> Result<ProviderResponse, ProviderError> {
+        let request_body = self.build_body(messages, false)?;
+
+        let response = self
+            .retry
+            .run(|| async {
+                let response =
+                    recording::send(self.recording.as_ref(), self.post_chat(&request_body)).await?;
+                Self::handle_response_error(response).await
+            })
+            .await?;
+
+        let response_data = response
+            .json::<ChatChunk>()
+            .await
+            .map_err(|e| ProviderError::parse(format!("Failed to parse response: {}", e)))?;
+
+        let mut result = ProviderResponse::default();
+        Self::apply_chunk(response_data, &mut result, None)?;
+
+        Ok(result)
+    }
+
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, ProviderError> {
+        let response = self
+            .retry
+            .run(|| async {
+                let request = with_extra_headers(
+                    self.client.get(format!("{}/api/tags", self.base_url)),
+                    &self.extra_headers,
+                );
+                let response = recording::send(self.recording.as_ref(), request).await?;
+                Self::handle_response_error(response).await
+            })
+            .await?;
+
+        let tags = response
+            .json::<TagsResponse>()
+            .await
+            .map_err(|e| ProviderError::parse(format!("Failed to parse model list: {}", e)))?;
+
+        Ok(tags
+            .models
+            .into_iter()
+            .map(|model| ModelInfo {
+                id: model.name,
+                ..Default::default()
+            })
+            .collect())
+    }
+}
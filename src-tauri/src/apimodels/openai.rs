@@ -0,0 +1,701 @@
+use super::error::ProviderError;
+use super::provider::{
+    with_extra_headers, ChatProvider, ContentBlock, Message, ModelInfo, ProviderConfig,
+    ProviderResponse, ResponseSchema, SamplingLimits, SamplingParams, StreamCallback,
+    StreamResponse, ToolCall, ToolDefinition, Usage,
+};
+use super::recording::{self, Recording};
+use super::retry::{Failure, RetryPolicy};
+use super::sse;
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// Provider for any server speaking the OpenAI `/v1/chat/completions` protocol
+/// (OpenAI itself, OpenRouter, llama.cpp, vLLM, LM Studio, ...).
+pub struct OpenAIProvider {
+    api_key: String,
+    model: String,
+    max_tokens: u32,
+    sampling: SamplingParams,
+    base_url: String,
+    extra_headers: HashMap<String, String>,
+    tools: Vec<ToolDefinition>,
+    retry: RetryPolicy,
+    recording: Option<Recording>,
+    response_schema: Option<ResponseSchema>,
+    client: Client,
+}
+
+/// Plain text, or a list of parts once a message has attachments.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum OpenAIContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+    File { file: FilePart },
+}
+
+#[derive(Serialize)]
+struct ImageUrl {
+    url: String,
+}
+
+#[derive(Serialize)]
+struct FilePart {
+    filename: String,
+    file_data: String,
+}
+
+impl From<ContentBlock> for ContentPart {
+    fn from(block: ContentBlock) -> Self {
+        match block {
+            ContentBlock::Text { text } => ContentPart::Text { text },
+            ContentBlock::Image { media_type, data } => ContentPart::ImageUrl {
+                image_url: ImageUrl {
+                    url: format!("data:{};base64,{}", media_type, data),
+                },
+            },
+            // Text files are inlined; other documents (PDFs) go as file parts
+            ContentBlock::Document {
+                media_type,
+                data,
+                filename,
+            } if media_type.starts_with("text/") => {
+                let text = BASE64.decode(&data).unwrap_or_default();
+                ContentPart::Text {
+                    text: format!("{}:\n{}", filename, String::from_utf8_lossy(&text)),
+                }
+            }
+            ContentBlock::Document {
+                media_type,
+                data,
+                filename,
+            } => ContentPart::File {
+                file: FilePart {
+                    filename,
+                    file_data: format!("data:{};base64,{}", media_type, data),
+                },
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct OpenAIMessage {
+    role: String,
+    content: Option<OpenAIContent>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<OpenAIToolCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct OpenAIToolCall {
+    id: String,
+    #[serde(rename = "type")]
+    call_type: String,
+    function: OpenAIFunctionCall,
+}
+
+/// `arguments` is a JSON document encoded as a string.
+#[derive(Serialize, Deserialize, Debug)]
+struct OpenAIFunctionCall {
+    name: String,
+    arguments: String,
+}
+
+#[derive(Serialize)]
+struct OpenAITool {
+    #[serde(rename = "type")]
+    tool_type: &'static str,
+    function: OpenAIFunction,
+}
+
+#[derive(Serialize)]
+struct OpenAIFunction {
+    name: String,
+    description: String,
+    parameters: Value,
+}
+
+#[derive(Serialize)]
+struct OpenAIRequest {
+    model: String,
+    messages: Vec<OpenAIMessage>,
+    max_tokens: u32,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    /// Not part of the OpenAI API; only sent to OpenRouter, which accepts it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<OpenAITool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<ResponseFormat>,
+}
+
+/// Structured Outputs: `{"type": "json_schema", "json_schema": {...}}`.
+#[derive(Serialize)]
+struct ResponseFormat {
+    #[serde(rename = "type")]
+    format_type: &'static str,
+    json_schema: ResponseSchema,
+}
+
+#[derive(Serialize)]
+struct StreamOptions {
+    include_usage: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct StreamChunk {
+    // The final chunk carries `usage` and no choices
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    usage: Option<OpenAIUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAIUsage {
+    #[serde(default)]
+    prompt_tokens: u32,
+    #[serde(default)]
+    completion_tokens: u32,
+    prompt_tokens_details: Option<PromptTokensDetails>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PromptTokensDetails {
+    #[serde(default)]
+    cached_tokens: u32,
+}
+
+impl From<OpenAIUsage> for Usage {
+    fn from(usage: OpenAIUsage) -> Self {
+        // `prompt_tokens` includes cached tokens; `Usage` counts them separately
+        let cached = usage
+            .prompt_tokens_details
+            .map(|details| details.cached_tokens)
+            .unwrap_or(0);
+        Usage {
+            input_tokens: usage.prompt_tokens.saturating_sub(cached),
+            output_tokens: usage.completion_tokens,
+            cache_creation_input_tokens: 0,
+            cache_read_input_tokens: cached,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct StreamChoice {
+    delta: Option<ChoiceDelta>,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChoiceDelta {
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCallDelta>,
+}
+
+/// Tool calls stream in fragments keyed by `index`; only the first fragment
+/// carries the id and name.
+#[derive(Deserialize, Debug)]
+struct ToolCallDelta {
+    index: usize,
+    id: Option<String>,
+    function: Option<FunctionDelta>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FunctionDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+#[derive(Default)]
+struct PendingToolCall {
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct NonStreamingResponse {
+    choices: Vec<Choice>,
+    usage: Option<OpenAIUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Choice {
+    message: ChoiceMessage,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChoiceMessage {
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<OpenAIToolCall>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ErrorResponse {
+    error: OpenAIError,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAIError {
+    message: String,
+    #[serde(rename = "type")]
+    error_type: Option<String>,
+    /// More specific than `type`, e.g. `context_length_exceeded`
+    #[serde(default)]
+    code: Option<String>,
+}
+
+impl OpenAIProvider {
+    pub const SAMPLING_LIMITS: SamplingLimits = SamplingLimits {
+        max_temperature: 2.0,
+        supports_top_k: false,
+        max_stop_sequences: Some(4),
+    };
+
+    pub const OPENROUTER_SAMPLING_LIMITS: SamplingLimits = SamplingLimits {
+        supports_top_k: true,
+        ..Self::SAMPLING_LIMITS
+    };
+
+    pub fn new(config: ProviderConfig) -> Result<Self, ProviderError> {
+        // 2 minute timeout unless the network settings say otherwise
+        let client = config.network.client(Duration::from_secs(120))?;
+
+        let base_url = config
+            .base_url
+            .filter(|url| !url.trim().is_empty())
+            .unwrap_or_else(|| DEFAULT_BASE_URL.to_string());
+
+        Ok(Self {
+            api_key: config.api_key,
+            model: config.model,
+            max_tokens: config.max_tokens,
+            sampling: config.sampling,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            extra_headers: config.extra_headers,
+            tools: config.tools,
+            retry: config.retry,
+            recording: config.recording,
+            response_schema: config.response_schema,
+            client,
+        })
+    }
+
+    fn build_body(&self, messages: Vec<Message>, stream: bool) -> OpenAIRequest {
+        OpenAIRequest {
+            model: self.model.clone(),
+            messages: Self::convert_messages(messages),
+            max_tokens: self.max_tokens,
+            stream,
+            temperature: self.sampling.temperature,
+            top_p: self.sampling.top_p,
+            top_k: self.sampling.top_k,
+            stop: self.sampling.stop_sequences.clone(),
+            tools: self
+                .tools
+                .iter()
+                .map(|tool| OpenAITool {
+                    tool_type: "function",
+                    function: OpenAIFunction {
+                        name: tool.name.clone(),
+                        description: tool.description.clone(),
+                        parameters: tool.input_schema.clone(),
+                    },
+                })
+                .collect(),
+            stream_options: stream.then_some(StreamOptions {
+                include_usage: true,
+            }),
+            response_format: self
+                .response_schema
+                .clone()
+                .map(|json_schema| ResponseFormat {
+                    format_type: "json_schema",
+                    json_schema,
+                }),
+        }
+    }
+
+    fn build_request(&self, request_body: &OpenAIRequest) -> RequestBuilder {
+        self.with_headers(
+            self.client
+                .post(format!("{}/chat/completions", self.base_url)),
+        )
+        .json(request_body)
+    }
+
+    fn with_headers(&self, request: RequestBuilder) -> RequestBuilder {
+        let mut request = request.header("Content-Type", "application/json");
+
+        // Local servers usually run without authentication
+        if !self.api_key.is_empty() {
+            request = request.bearer_auth(&self.api_key);
+        }
+
+        with_extra_headers(request, &self.extra_headers)
+    }
+
+    async fn handle_response_error(
+        response: reqwest::Response,
+    ) -> Result<reqwest::Response, Failure> {
+        if !response.status().is_success() {
+            let status = response.status();
+            let headers = response.headers().clone();
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Failed to read error response".to_string());
+
+            // Try to parse as ErrorResponse
+            let message = match serde_json::from_str::<ErrorResponse>(&error_text) {
+                Ok(error_response) => format!(
+                    "API error ({}): {} - {}",
+                    status,
+                    error_response
+                        .error
+                        .error_type
+                        .unwrap_or_else(|| "error".to_string()),
+                    error_response.error.message
+                ),
+                Err(_) => format!("Request failed ({}): {}", status, error_text),
+            };
+            return Err(Failure::from_status(status, &headers, message));
+        }
+        Ok(response)
+    }
+
+    fn convert_messages(messages: Vec<Message>) -> Vec<OpenAIMessage> {
+        messages
+            .into_iter()
+            .map(|msg| match msg.tool_result {
+                Some(result) => OpenAIMessage {
+                    role: "tool".to_string(),
+                    content: Some(OpenAIContent::Text(msg.content)),
+                    tool_calls: Vec::new(),
+                    tool_call_id: Some(result.tool_use_id),
+                },
+                None if !msg.attachments.is_empty() => OpenAIMessage {
+                    content: Some(OpenAIContent::Parts(
+                        msg.content_blocks()
+                            .into_iter()
+                            .map(ContentPart::from)
+                            .collect(),
+                    )),
+                    role: msg.role,
+                    tool_calls: Vec::new(),
+                    tool_call_id: None,
+                },
+                None => OpenAIMessage {
+                    role: msg.role,
+                    // Assistant turns that only call tools have no content
+                    content: (!msg.content.is_empty() || msg.tool_calls.is_empty())
+                        .then_some(OpenAIContent::Text(msg.content)),
+                    tool_calls: msg
+                        .tool_calls
+                        .into_iter()
+                        .map(|call| OpenAIToolCall {
+                            id: call.id,
+                            call_type: "function".to_string(),
+                            function: OpenAIFunctionCall {
+                                name: call.name,
+                                arguments: call.input.to_string(),
+                            },
+                        })
+                        .collect(),
+                    tool_call_id: None,
+                },
+            })
+            .collect()
+    }
+
+    fn parse_tool_call(
+        id: String,
+        name: String,
+        arguments: &str,
+    ) -> Result<ToolCall, ProviderError> {
+        let input = if arguments.trim().is_empty() {
+            Value::Object(Default::default())
+        } else {
+            serde_json::from_str(arguments).map_err(|e| {
+                ProviderError::parse(format!(
+                    "Failed to parse arguments for tool {}: {}",
+                    name, e
+                ))
+            })?
+        };
+        Ok(ToolCall {
+            id,
+            name,
+            input,
+            signature: None,
+        })
+    }
+
+    /// Maps `finish_reason` onto the Anthropic stop reasons used by `ProviderResponse`.
+    fn normalize_stop_reason(finish_reason: String) -> String {
+        match finish_reason.as_str() {
+            "stop" => "end_turn".to_string(),
+            "length" => "max_tokens".to_string(),
+            "tool_calls" | "function_call" => "tool_use".to_string(),
+            _ => finish_reason,
+        }
+    }
+
+    /// Sends one streaming request and reads it to `[DONE]`.
+    async fn stream_attempt(
+        &self,
+        request_body: &OpenAIRequest,
+        callback: &StreamCallback,
+    ) -> Result<ProviderResponse, Failure> {
+        let response =
+            recording::send(self.recording.as_ref(), self.build_request(request_body)).await?;
+
+        let response = Self::handle_response_error(response).await?;
+
+        let mut result = ProviderResponse::default();
+        let mut pending_tools: BTreeMap<usize, PendingToolCall> = BTreeMap::new();
+
+        let stream_result = sse::for_each_event(response, |event| {
+            let data = event.data.trim();
+            if data == "[DONE]" {
+                return Ok(true);
+            }
+
+            // Some servers report failures inside an otherwise successful stream
+            if let Ok(error_response) = serde_json::from_str::<ErrorResponse>(data) {
+                let error = error_response.error;
+                let message = format!("API error: {}", error.message);
+                let error_type = error.code.or(error.error_type).unwrap_or_default();
+                return Err(ProviderError::from_error_type(&error_type, message).into());
+            }
+
+            let chunk = match serde_json::from_str::<StreamChunk>(data) {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    log::warn!("Skipping unparseable stream chunk: {}", e);
+                    return Ok(false);
+                }
+            };
+
+            if let Some(usage) = chunk.usage {
+                result.usage = Some(usage.into());
+            }
+
+            for choice in chunk.choices {
+                if let Some(finish_reason) = choice.finish_reason {
+                    result.stop_reason = Some(Self::normalize_stop_reason(finish_reason));
+                }
+                let Some(delta) = choice.delta else {
+                    continue;
+                };
+                if let Some(text) = delta.content {
+                    if !text.is_empty() {
+                        result.text.push_str(&text);
+                        callback(StreamResponse {
+                            text,
+                            is_done: false,
+                            thinking: String::new(),
+                        });
+                    }
+                }
+                for tool_delta in delta.tool_calls {
+                    let pending = pending_tools.entry(tool_delta.index).or_default();
+                    if let Some(id) = tool_delta.id {
+                        pending.id = id;
+                    }
+                    if let Some(function) = tool_delta.function {
+                        if let Some(name) = function.name {
+                            pending.name.push_str(&name);
+                        }
+                        if let Some(arguments) = function.arguments {
+                            pending.arguments.push_str(&arguments);
+                        }
+                    }
+                }
+            }
+            Ok(false)
+        })
+        .await;
+
+        let partial = !result.text.is_empty();
+        stream_result.map_err(|failure| failure.with_partial(partial))?;
+
+        for pending in pending_tools.into_values() {
+            result.tool_calls.push(Self::parse_tool_call(
+                pending.id,
+                pending.name,
+                &pending.arguments,
+            )?);
+        }
+
+        Ok(result)
+    }
+}
+
+/// `GET /models`. OpenRouter adds a context length and per-token prices.
+#[derive(Deserialize)]
+struct ModelsResponse {
+    data: Vec<ModelEntry>,
+}
+
+#[derive(Deserialize)]
+struct ModelEntry {
+    id: String,
+    name: Option<String>,
+    context_length: Option<u32>,
+    pricing: Option<ModelEntryPricing>,
+}
+
+/// USD per token, as strings
+#[derive(Deserialize)]
+struct ModelEntryPricing {
+    prompt: Option<String>,
+    completion: Option<String>,
+}
+
+#[async_trait]
+impl ChatProvider for OpenAIProvider {
+    fn supports_streaming(&self) -> bool {
+        true
+    }
+
+    async fn send_message_streaming(
+        &self,
+        messages: Vec<Message>,
+        callback: StreamCallback,
+    ) -> Result<ProviderResponse, ProviderError> {
+        let request_body = self.build_body(messages, true);
+
+        let result = self
+            .retry
+            .run(|| self.stream_attempt(&request_body, &callback))
+            .await?;
+
+        callback(StreamResponse {
+            text: String::new(),
+            is_done: true,
+            thinking: String::new(),
+        });
+
+        Ok(result)
+    }
+
+    async fn send_message_blocking(
+        &self,
+        messages: Vec<Message>,
+    ) -> Result<ProviderResponse, ProviderError> {
+        let request_body = self.build_body(messages, false);
+
+        let response = self
+            .retry
+            .run(|| async {
+                let response =
+                    recording::send(self.recording.as_ref(), self.build_request(&request_body))
+                        .await?;
+                Self::handle_response_error(response).await
+            })
+            .await?;
+
+        let response_data = response
+            .json::<NonStreamingResponse>()
+            .await
+            .map_err(|e| ProviderError::parse(format!("Failed to parse response: {}", e)))?;
+
+        let mut result = ProviderResponse {
+            usage: response_data.usage.map(Usage::from),
+            ..Default::default()
+        };
+        for choice in response_data.choices {
+            if let Some(content) = choice.message.content {
+                result.text.push_str(&content);
+            }
+            for call in choice.message.tool_calls {
+                result.tool_calls.push(Self::parse_tool_call(
+                    call.id,
+                    call.function.name,
+                    &call.function.arguments,
+                )?);
+            }
+            if let Some(finish_reason) = choice.finish_reason {
+                result.stop_reason = Some(Self::normalize_stop_reason(finish_reason));
+            }
+        }
+
+        Ok(result)
+    }
+
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, ProviderError> {
+        let response = self
+            .retry
+            .run(|| async {
+                let response = recording::send(
+                    self.recording.as_ref(),
+                    self.with_headers(self.client.get(format!("{}/models", self.base_url))),
+                )
+                .await?;
+                Self::handle_response_error(response).await
+            })
+            .await?;
+
+        let models = response
+            .json::<ModelsResponse>()
+            .await
+            .map_err(|e| ProviderError::parse(format!("Failed to parse model list: {}", e)))?;
+
+        let per_million = |price: Option<String>| {
+            price
+                .and_then(|price| price.parse::<f64>().ok())
+                .map(|price| price * 1_000_000.0)
+        };
+        Ok(models
+            .data
+            .into_iter()
+            .map(|model| {
+                let (input_price, output_price) = match model.pricing {
+                    Some(pricing) => (per_million(pricing.prompt), per_million(pricing.completion)),
+                    None => (None, None),
+                };
+                ModelInfo {
+                    id: model.id,
+                    display_name: model.name,
+                    context_window: model.context_length,
+                    input_price,
+                    output_price,
+                }
+            })
+            .collect())
+    }
+}
@@ -1,8 +1,17 @@
+use super::error::ProviderError;
+use super::network::NetworkSettings;
+use super::recording::Recording;
+use super::retry::RetryPolicy;
+use super::scheduler::RateLimits;
 use async_trait::async_trait;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::RequestBuilder;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
 use std::fmt::Debug;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Message {
     pub id: String,
     pub role: String,
@@ -11,6 +20,114 @@ pub struct Message {
     #[serde(default)]
     pub reactions: Option<MessageReactions>,
     pub model: Option<String>, // Add model field
+    /// Tools the assistant asked to run in this turn
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// Set on `tool` messages, which carry a tool's output in `content`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_result: Option<ToolResult>,
+    /// Extended thinking that preceded this assistant turn, replayed verbatim
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub thinking: Vec<ThinkingBlock>,
+    /// Files sent along with `content`, in the order they were attached
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    /// The generation was cancelled and `content` is what arrived before that
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub interrupted: bool,
+    /// Set on each reply of a model comparison
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comparison: Option<Comparison>,
+}
+
+impl Message {
+    /// Whether the message is part of the conversation sent to providers.
+    /// Comparison replies that weren't picked are kept for display only.
+    pub fn is_selected(&self) -> bool {
+        self.comparison
+            .as_ref()
+            .is_none_or(|comparison| comparison.selected)
+    }
+
+    /// The message as typed content blocks: attachments first, which models
+    /// handle best, then the text.
+    pub fn content_blocks(&self) -> Vec<ContentBlock> {
+        let mut blocks = self
+            .attachments
+            .iter()
+            .map(Attachment::to_content_block)
+            .collect::<Vec<_>>();
+        if !self.content.is_empty() {
+            blocks.push(ContentBlock::Text {
+                text: self.content.clone(),
+            });
+        }
+        blocks
+    }
+}
+
+/// One typed piece of a message, which each provider converts to its own
+/// wire format. `data` is base64-encoded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentBlock {
+    Text {
+        text: String,
+    },
+    Image {
+        media_type: String,
+        data: String,
+    },
+    Document {
+        media_type: String,
+        data: String,
+        filename: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttachmentKind {
+    Image,
+    Document,
+}
+
+/// A file attached to a message, stored in the `attachments` table.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: String,
+    pub kind: AttachmentKind,
+    pub media_type: String,
+    pub filename: String,
+    /// Base64-encoded contents. Only providers need them, so they're read
+    /// just for the request and are empty in the chat history; they're
+    /// never sent to the frontend.
+    #[serde(default, skip_serializing)]
+    pub data: String,
+}
+
+impl Attachment {
+    pub fn to_content_block(&self) -> ContentBlock {
+        match self.kind {
+            AttachmentKind::Image => ContentBlock::Image {
+                media_type: self.media_type.clone(),
+                data: self.data.clone(),
+            },
+            AttachmentKind::Document => ContentBlock::Document {
+                media_type: self.media_type.clone(),
+                data: self.data.clone(),
+                filename: self.filename.clone(),
+            },
+        }
+    }
+}
+
+/// Ties a reply to the other replies to the same user message in a model
+/// comparison. Only the selected one continues the conversation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Comparison {
+    pub user_message_id: String,
+    pub selected: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,6 +139,119 @@ pub struct MessageReactions {
 pub struct StreamResponse {
     pub text: String,
     pub is_done: bool,
+    /// Extended thinking text, streamed separately from the reply
+    #[serde(default)]
+    pub thinking: String,
+}
+
+/// A reasoning block from extended thinking. The API requires these back
+/// unmodified, signature included, when a turn continues after tool use.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ThinkingBlock {
+    Thinking {
+        thinking: String,
+        signature: String,
+    },
+    /// Thinking flagged by safety systems, returned encrypted
+    RedactedThinking {
+        data: String,
+    },
+}
+
+/// A tool the model may call, described by a JSON Schema for its input.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    pub input_schema: Value,
+}
+
+/// A JSON Schema the reply has to follow. Providers constrain the reply to
+/// it as far as their API allows and return the JSON as the reply text.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseSchema {
+    /// Letters, digits, `_` and `-`; Anthropic uses it as the tool name
+    pub name: String,
+    pub schema: Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub input: Value,
+    /// Opaque token some providers (Gemini) attach to a call and expect
+    /// back with it in later requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolResult {
+    pub tool_use_id: String,
+    #[serde(default)]
+    pub is_error: bool,
+}
+
+/// Token counts for one response. `input_tokens` excludes cached input,
+/// which is counted separately because it is billed differently.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    #[serde(default)]
+    pub input_tokens: u32,
+    #[serde(default)]
+    pub output_tokens: u32,
+    #[serde(default)]
+    pub cache_creation_input_tokens: u32,
+    #[serde(default)]
+    pub cache_read_input_tokens: u32,
+}
+
+impl Usage {
+    /// Takes every non-zero count from `other`. Streams report usage in
+    /// pieces, with later events carrying updated totals.
+    pub fn merge(&mut self, other: Usage) {
+        if other.input_tokens > 0 {
+            self.input_tokens = other.input_tokens;
+        }
+        if other.output_tokens > 0 {
+            self.output_tokens = other.output_tokens;
+        }
+        if other.cache_creation_input_tokens > 0 {
+            self.cache_creation_input_tokens = other.cache_creation_input_tokens;
+        }
+        if other.cache_read_input_tokens > 0 {
+            self.cache_read_input_tokens = other.cache_read_input_tokens;
+        }
+    }
+}
+
+/// A model offered by a provider, as listed by its models endpoint. Most
+/// endpoints return little more than the id.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModelInfo {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_window: Option<u32>,
+    /// USD per million input tokens, if the provider publishes it (OpenRouter)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_price: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_price: Option<f64>,
+}
+
+/// Everything a provider returns for one request. `stop_reason` uses the
+/// Anthropic vocabulary (`end_turn`, `tool_use`, `max_tokens`, ...).
+#[derive(Debug, Clone, Default)]
+pub struct ProviderResponse {
+    pub text: String,
+    pub thinking: Vec<ThinkingBlock>,
+    pub tool_calls: Vec<ToolCall>,
+    pub stop_reason: Option<String>,
+    pub usage: Option<Usage>,
 }
 
 // Type alias for the callback function
@@ -37,17 +267,23 @@ pub trait ChatProvider: Send + Sync {
         &self,
         messages: Vec<Message>,
         callback: StreamCallback,
-    ) -> Result<String, String>;
+    ) -> Result<ProviderResponse, ProviderError>;
 
     // Non-streaming version
-    async fn send_message_blocking(&self, messages: Vec<Message>) -> Result<String, String>;
+    async fn send_message_blocking(
+        &self,
+        messages: Vec<Message>,
+    ) -> Result<ProviderResponse, ProviderError>;
+
+    // Models available with the configured credentials
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, ProviderError>;
 
     // Main entry point that handles both streaming and non-streaming
     async fn send_message(
         &self,
         messages: Vec<Message>,
         callback: Option<StreamCallback>,
-    ) -> Result<String, String> {
+    ) -> Result<ProviderResponse, ProviderError> {
         match (self.supports_streaming(), callback) {
             (true, Some(cb)) => self.send_message_streaming(messages, cb).await,
             _ => self.send_message_blocking(messages).await,
@@ -55,11 +291,117 @@ pub trait ChatProvider: Send + Sync {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Optional sampling parameters; `None`/empty means "use the provider default".
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SamplingParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+}
+
+/// The ranges a provider accepts for each sampling parameter.
+#[derive(Debug, Clone, Copy)]
+pub struct SamplingLimits {
+    pub max_temperature: f32,
+    pub supports_top_k: bool,
+    pub max_stop_sequences: Option<usize>,
+}
+
+impl SamplingParams {
+    pub fn validate(&self, limits: &SamplingLimits) -> Result<(), String> {
+        if let Some(temperature) = self.temperature {
+            if !(0.0..=limits.max_temperature).contains(&temperature) {
+                return Err(format!(
+                    "temperature must be between 0 and {}, got {}",
+                    limits.max_temperature, temperature
+                ));
+            }
+        }
+
+        if let Some(top_p) = self.top_p {
+            if !(0.0..=1.0).contains(&top_p) {
+                return Err(format!("top_p must be between 0 and 1, got {}", top_p));
+            }
+        }
+
+        if let Some(top_k) = self.top_k {
+            if !limits.supports_top_k {
+                return Err("top_k is not supported by this provider".to_string());
+            }
+            if top_k == 0 {
+                return Err("top_k must be at least 1".to_string());
+            }
+        }
+
+        if self.stop_sequences.iter().any(|stop| stop.is_empty()) {
+            return Err("stop sequences must not be empty".to_string());
+        }
+        if let Some(max) = limits.max_stop_sequences {
+            if self.stop_sequences.len() > max {
+                return Err(format!(
+                    "at most {} stop sequences are supported, got {}",
+                    max,
+                    self.stop_sequences.len()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Adds the user's `extra_headers` to a request, replacing any header of
+/// the same name set before (`RequestBuilder::header` would send both).
+/// An invalid name or value fails the request when it is sent.
+pub(super) fn with_extra_headers(
+    mut request: RequestBuilder,
+    headers: &HashMap<String, String>,
+) -> RequestBuilder {
+    let mut extra = HeaderMap::new();
+    for (name, value) in headers {
+        match (
+            HeaderName::try_from(name.as_str()),
+            HeaderValue::try_from(value.as_str()),
+        ) {
+            (Ok(name), Ok(value)) => {
+                extra.insert(name, value);
+            }
+            _ => request = request.header(name, value),
+        }
+    }
+    request.headers(extra)
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct ProviderConfig {
     pub api_key: String,
     pub model: String,
     pub max_tokens: u32,
+    pub base_url: Option<String>,
+    pub num_ctx: Option<u32>,
+    pub sampling: SamplingParams,
+    pub api_version: Option<String>,
+    pub extra_headers: HashMap<String, String>,
+    pub tools: Vec<ToolDefinition>,
+    pub retry: RetryPolicy,
+    /// Token budget for extended thinking; `None` disables it
+    pub thinking_budget: Option<u32>,
+    /// Mark cache breakpoints on the system prompt and history (Anthropic)
+    pub prompt_caching: bool,
+    /// Scripted responses for the `mock` provider
+    pub fixture: Option<String>,
+    /// Save or replay the HTTP exchanges instead of just sending them
+    pub recording: Option<Recording>,
+    /// Ask for JSON following this schema instead of prose
+    pub response_schema: Option<ResponseSchema>,
+    /// Queue requests to stay within the account's limits
+    pub rate_limits: RateLimits,
+    pub network: NetworkSettings,
 }
 
 pub struct ProviderFactory;
@@ -68,11 +410,105 @@ impl ProviderFactory {
     pub fn create_provider(
         provider_type: &str,
         config: ProviderConfig,
-    ) -> Result<Box<dyn ChatProvider>, String> {
-        match provider_type {
-            "anthropic" => Ok(Box::new(super::anthropic::AnthropicProvider::new(config))),
+    ) -> Result<Box<dyn ChatProvider>, ProviderError> {
+        use super::anthropic::AnthropicProvider;
+        use super::gemini::GeminiProvider;
+        use super::mock::MockProvider;
+        use super::ollama::OllamaProvider;
+        use super::openai::OpenAIProvider;
+        use super::scheduler::ScheduledProvider;
+
+        // Kept for the scheduler, since the provider takes the config
+        let scheduled = (!config.rate_limits.is_unlimited()).then(|| config.clone());
+
+        let provider: Box<dyn ChatProvider> = match provider_type {
+            "anthropic" => {
+                config
+                    .sampling
+                    .validate(&AnthropicProvider::SAMPLING_LIMITS)
+                    .map_err(ProviderError::invalid_request)?;
+                AnthropicProvider::validate_thinking(&config)
+                    .map_err(ProviderError::invalid_request)?;
+                Box::new(AnthropicProvider::new(config)?)
+            }
+            "openai" | "openrouter" => {
+                let limits = if provider_type == "openrouter" {
+                    OpenAIProvider::OPENROUTER_SAMPLING_LIMITS
+                } else {
+                    OpenAIProvider::SAMPLING_LIMITS
+                };
+                config
+                    .sampling
+                    .validate(&limits)
+                    .map_err(ProviderError::invalid_request)?;
+                Box::new(OpenAIProvider::new(config)?)
+            }
+            "ollama" => {
+                config
+                    .sampling
+                    .validate(&OllamaProvider::SAMPLING_LIMITS)
+                    .map_err(ProviderError::invalid_request)?;
+                Box::new(OllamaProvider::new(config)?)
+            }
+            "gemini" => {
+                config
+                    .sampling
+                    .validate(&GeminiProvider::SAMPLING_LIMITS)
+                    .map_err(ProviderError::invalid_request)?;
+                Box::new(GeminiProvider::new(config)?)
+            }
+            "mock" => {
+                config
+                    .sampling
+                    .validate(&MockProvider::SAMPLING_LIMITS)
+                    .map_err(ProviderError::invalid_request)?;
+                Box::new(MockProvider::new(config))
+            }
             // Add other providers here
-            _ => Err(format!("Unknown provider type: {}", provider_type)),
-        }
+            _ => {
+                return Err(ProviderError::invalid_request(format!(
+                    "Unknown provider type: {}",
+                    provider_type
+                )))
+            }
+        };
+
+        Ok(match scheduled {
+            Some(config) => Box::new(ScheduledProvider::new(provider, provider_type, &config)),
+            None => provider,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extra_headers_replace_defaults() {
+        let extra = HashMap::from([
+            ("X-API-Key".to_string(), "gateway-key".to_string()),
+            ("anthropic-beta".to_string(), "feature".to_string()),
+        ]);
+        let request = reqwest::Client::new()
+            .post("https://example.com")
+            .header("x-api-key", "default-key")
+            .header("anthropic-version", "2023-06-01");
+        let request = with_extra_headers(request, &extra).build().unwrap();
+
+        let headers = request.headers();
+        let keys: Vec<_> = headers.get_all("x-api-key").iter().collect();
+        assert_eq!(keys, ["gateway-key"]);
+        assert_eq!(headers["anthropic-beta"], "feature");
+        assert_eq!(headers["anthropic-version"], "2023-06-01");
+    }
+
+    #[test]
+    fn invalid_extra_headers_fail_the_request() {
+        let extra = HashMap::from([("bad header".to_string(), "value".to_string())]);
+        let request = reqwest::Client::new().get("https://example.com");
+        let error = with_extra_headers(request, &extra).build().unwrap_err();
+        // A settings mistake: not worth retrying or falling back over
+        assert!(!ProviderError::from_send_error(&error).is_transient());
     }
 }
@@ -0,0 +1,227 @@
+//! Records provider HTTP exchanges to disk and plays them back offline, so
+//! the chat flow can be exercised without network access or API credits.
+//!
+//! Each exchange is saved as a JSON file named after a hash of the request
+//! (method, URL and body), holding the response status, headers and raw
+//! body, which for streaming requests is the SSE text as received. Replayed
+//! responses go through the providers' usual parsing.
+
+use super::error::ProviderError;
+use super::retry::Failure;
+use bytes::Bytes;
+use futures_util::StreamExt;
+use reqwest::header::{CONTENT_LENGTH, SET_COOKIE, TRANSFER_ENCODING};
+use reqwest::RequestBuilder;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordingMode {
+    /// Send requests as usual and save every exchange
+    Record,
+    /// Answer from saved exchanges without touching the network
+    Replay,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recording {
+    pub mode: RecordingMode,
+    /// Where the exchanges are saved
+    pub dir: PathBuf,
+}
+
+/// One saved request and its response.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Exchange {
+    method: String,
+    url: String,
+    /// The request body, for reading; lookups go by the file name
+    #[serde(default)]
+    request: Value,
+    status: u16,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+/// Saves the exchange once the response body is dropped, whether or not it
+/// was read to the end, so a replay sees exactly what the provider read.
+struct ExchangeWriter {
+    path: PathBuf,
+    exchange: Exchange,
+    body: Vec<u8>,
+}
+
+impl ExchangeWriter {
+    fn push(&mut self, chunk: &[u8]) {
+        self.body.extend_from_slice(chunk);
+    }
+}
+
+impl Drop for ExchangeWriter {
+    fn drop(&mut self) {
+        self.exchange.body = String::from_utf8_lossy(&self.body).into_owned();
+        if let Err(e) = save(&self.path, &self.exchange) {
+            log::warn!(
+                "Failed to record exchange to {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+fn save(path: &Path, exchange: &Exchange) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, serde_json::to_vec_pretty(exchange)?)
+}
+
+/// FNV-1a, which unlike `DefaultHasher` is stable across Rust releases.
+fn request_key(method: &str, url: &str, body: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in [method.as_bytes(), b" ", url.as_bytes(), b"\n", body] {
+        for byte in part {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+fn request_body(request: &reqwest::Request) -> &[u8] {
+    request
+        .body()
+        .and_then(|body| body.as_bytes())
+        .unwrap_or_default()
+}
+
+impl Recording {
+    /// Where the exchange for `request` is saved.
+    pub(super) fn exchange_path(&self, request: &reqwest::Request) -> PathBuf {
+        let key = request_key(
+            request.method().as_str(),
+            request.url().as_str(),
+            request_body(request),
+        );
+        self.dir.join(format!("{:016x}.json", key))
+    }
+
+    /// Sends `request` and saves the exchange, or answers it from a saved
+    /// one, depending on the mode. Identical requests share a file; the
+    /// latest recording wins.
+    pub async fn send(&self, request: RequestBuilder) -> Result<reqwest::Response, Failure> {
+        let (client, request) = request.build_split();
+        let request = request.map_err(Failure::from_send_error)?;
+        let path = self.exchange_path(&request);
+
+        match self.mode {
+            RecordingMode::Replay => replay(&path).await,
+            RecordingMode::Record => {
+                let exchange = Exchange {
+                    method: request.method().to_string(),
+                    url: request.url().to_string(),
+                    request: serde_json::from_slice(request_body(&request)).unwrap_or(Value::Null),
+                    ..Default::default()
+                };
+                let response = client
+                    .execute(request)
+                    .await
+                    .map_err(Failure::from_send_error)?;
+                record(path, exchange, response)
+            }
+        }
+    }
+}
+
+/// Sends `request`, through `recording` when one is configured.
+pub async fn send(
+    recording: Option<&Recording>,
+    request: RequestBuilder,
+) -> Result<reqwest::Response, Failure> {
+    match recording {
+        Some(recording) => recording.send(request).await,
+        None => request.send().await.map_err(Failure::from_send_error),
+    }
+}
+
+/// Passes `response` through unchanged while copying its body aside.
+fn record(
+    path: PathBuf,
+    mut exchange: Exchange,
+    response: reqwest::Response,
+) -> Result<reqwest::Response, Failure> {
+    exchange.status = response.status().as_u16();
+    exchange.headers = response
+        .headers()
+        .iter()
+        .filter(|(name, _)| ![SET_COOKIE, CONTENT_LENGTH, TRANSFER_ENCODING].contains(name))
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+
+    let mut builder = http::Response::builder().status(response.status());
+    for (name, value) in response.headers() {
+        builder = builder.header(name, value);
+    }
+
+    let mut writer = ExchangeWriter {
+        path,
+        exchange,
+        body: Vec::new(),
+    };
+    let body = response.bytes_stream().inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            writer.push(chunk);
+        }
+    });
+
+    let response = builder
+        .body(reqwest::Body::wrap_stream(body))
+        .map_err(|e| ProviderError::network(format!("Failed to record response: {}", e)))?;
+    Ok(response.into())
+}
+
+async fn replay(path: &Path) -> Result<reqwest::Response, Failure> {
+    let contents = tokio::fs::read(path).await.map_err(|e| {
+        ProviderError::invalid_request(format!(
+            "No recorded exchange for this request at {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    let exchange: Exchange = serde_json::from_slice(&contents).map_err(|e| {
+        ProviderError::parse(format!(
+            "Failed to parse recorded exchange {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+
+    let mut builder = http::Response::builder().status(exchange.status);
+    for (name, value) in &exchange.headers {
+        builder = builder.header(name, value);
+    }
+
+    // Chunk boundaries aren't saved, so hand the body over one SSE event
+    // (or, for other bodies, all of it) at a time
+    let chunks: Vec<Result<Bytes, std::io::Error>> = exchange
+        .body
+        .split_inclusive("\n\n")
+        .map(|chunk| Ok(Bytes::from(chunk.to_string())))
+        .collect();
+    let response = builder
+        .body(reqwest::Body::wrap_stream(futures_util::stream::iter(
+            chunks,
+        )))
+        .map_err(|e| {
+            ProviderError::parse(format!(
+                "Invalid recorded exchange {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+    Ok(response.into())
+}
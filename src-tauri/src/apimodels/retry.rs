@@ -0,0 +1,334 @@
+//! Retries for transient provider failures: rate limits, overload and
+//! dropped connections.
+//!
+//! Requests are retried with exponential backoff and full jitter, unless the
+//! server says how long to wait with a `retry-after` header. A stream that
+//! fails midway is retried from the start; listeners are told so they can
+//! discard the text streamed by the failed attempt.
+
+use super::error::ProviderError;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Reported before each retry.
+#[derive(Debug, Clone, Serialize)]
+pub struct RetryEvent {
+    /// The attempt that is about to start, counting from 1
+    pub attempt: u32,
+    pub max_attempts: u32,
+    pub delay_ms: u64,
+    pub reason: String,
+    /// Whether the failed attempt had already streamed output, which the
+    /// retry will produce again from scratch
+    pub discard_partial: bool,
+}
+
+pub type RetryCallback = Arc<dyn Fn(RetryEvent) + Send + Sync + 'static>;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Total attempts including the first; 1 disables retries
+    pub max_attempts: u32,
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    #[serde(skip)]
+    pub on_retry: Option<RetryCallback>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            initial_delay_ms: 1_000,
+            max_delay_ms: 60_000,
+            on_retry: None,
+        }
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_delay_ms", &self.initial_delay_ms)
+            .field("max_delay_ms", &self.max_delay_ms)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Why an attempt failed, and whether another one could succeed.
+#[derive(Debug)]
+pub struct Failure {
+    pub error: ProviderError,
+    pub retryable: bool,
+    pub retry_after: Option<Duration>,
+    /// Output was already streamed before the failure
+    pub partial: bool,
+}
+
+impl Failure {
+    /// A non-success HTTP response. 408, 409, 429 and 5xx (including
+    /// Anthropic's 529 "overloaded") are worth retrying.
+    pub fn from_status(status: StatusCode, headers: &HeaderMap, message: String) -> Self {
+        let retry_after = retry_after(headers);
+        Self {
+            error: ProviderError::from_status(status, message, retry_after),
+            retryable: is_retryable_status(status),
+            retry_after,
+            partial: false,
+        }
+    }
+
+    /// A request that never got a response. Connection problems and
+    /// timeouts are transient; anything else (e.g. a bad URL) is not.
+    pub fn from_send_error(error: reqwest::Error) -> Self {
+        Self {
+            retryable: error.is_connect() || error.is_timeout() || error.is_request(),
+            ..Self::from(ProviderError::from_send_error(&error))
+        }
+    }
+
+    pub fn with_partial(mut self, partial: bool) -> Self {
+        self.partial = partial;
+        self
+    }
+}
+
+/// Retried only if the error is transient.
+impl From<ProviderError> for Failure {
+    fn from(error: ProviderError) -> Self {
+        Self {
+            retryable: error.is_transient(),
+            error,
+            retry_after: None,
+            partial: false,
+        }
+    }
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 408 | 409 | 429 | 500..=599)
+}
+
+/// Reads `retry-after` (seconds or an HTTP date), falling back to the
+/// `retry-after-ms` header some OpenAI-compatible servers send.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(ms) = header("retry-after-ms").and_then(|value| value.trim().parse::<f64>().ok()) {
+        return Some(Duration::from_secs_f64(ms.max(0.0) / 1000.0));
+    }
+
+    let value = header("retry-after")?.trim();
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Some(Duration::from_secs_f64(seconds.max(0.0)));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let wait = date.signed_duration_since(chrono::Utc::now());
+    Some(wait.to_std().unwrap_or_default())
+}
+
+impl RetryPolicy {
+    /// Backoff before `attempt` (2 or later): a random delay of up to
+    /// `initial_delay * 2^(attempt - 2)`, capped at `max_delay`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(2).min(20);
+        let ceiling = self
+            .initial_delay_ms
+            .saturating_mul(1u64 << exponent)
+            .min(self.max_delay_ms);
+        // Full jitter so clients that failed together don't retry together
+        let random = RandomState::new().build_hasher().finish();
+        Duration::from_millis(random % (ceiling + 1))
+    }
+
+    /// Runs `attempt` until it succeeds, fails with a non-retryable error,
+    /// or `max_attempts` is used up.
+    pub async fn run<T, F, Fut>(&self, mut attempt: F) -> Result<T, ProviderError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Failure>>,
+    {
+        let max_attempts = self.max_attempts.max(1);
+        let mut number = 1;
+        loop {
+            let failure = match attempt().await {
+                Ok(value) => return Ok(value),
+                Err(failure) => failure,
+            };
+
+            if !failure.retryable || number >= max_attempts {
+                if number > 1 {
+                    return Err(failure
+                        .error
+                        .with_suffix(&format!(" (gave up after {} attempts)", number)));
+                }
+                return Err(failure.error);
+            }
+
+            number += 1;
+            // The server's hint wins, but never wait longer than max_delay
+            let delay = failure
+                .retry_after
+                .unwrap_or_else(|| self.backoff(number))
+                .min(Duration::from_millis(self.max_delay_ms));
+
+            log::warn!(
+                "Attempt {} of {} failed, retrying in {:?}: {}",
+                number - 1,
+                max_attempts,
+                delay,
+                failure.error
+            );
+            if let Some(on_retry) = &self.on_retry {
+                on_retry(RetryEvent {
+                    attempt: number,
+                    max_attempts,
+                    delay_ms: delay.as_millis() as u64,
+                    reason: failure.error.to_string(),
+                    discard_partial: failure.partial,
+                });
+            }
+
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+    use reqwest::header::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn backoff_is_jittered_below_a_doubling_cap() {
+        let policy = RetryPolicy {
+            initial_delay_ms: 100,
+            max_delay_ms: 1_000,
+            ..Default::default()
+        };
+        for (attempt, ceiling) in [
+            (2, 100),
+            (3, 200),
+            (4, 400),
+            (5, 800),
+            (6, 1_000),
+            (60, 1_000),
+        ] {
+            let delays: Vec<Duration> = (0..200).map(|_| policy.backoff(attempt)).collect();
+            assert!(
+                delays
+                    .iter()
+                    .all(|delay| *delay <= Duration::from_millis(ceiling)),
+                "attempt {} waited longer than {}ms",
+                attempt,
+                ceiling
+            );
+            // Full jitter spreads the delays out rather than always waiting the cap
+            assert!(delays.iter().any(|delay| *delay != delays[0]));
+        }
+    }
+
+    #[test]
+    fn retry_after_accepts_seconds_and_http_dates() {
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "120")])),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", " 1.5 ")])),
+            Some(Duration::from_millis(1_500))
+        );
+        // The millisecond header is more precise, so it wins
+        assert_eq!(
+            retry_after(&headers(&[
+                ("retry-after", "120"),
+                ("retry-after-ms", "250")
+            ])),
+            Some(Duration::from_millis(250))
+        );
+
+        let in_30s = (chrono::Utc::now() + chrono::Duration::seconds(30))
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        let wait = retry_after(&headers(&[("retry-after", &in_30s)])).unwrap();
+        assert!(wait > Duration::from_secs(28) && wait <= Duration::from_secs(30));
+        assert_eq!(
+            retry_after(&headers(&[(
+                "retry-after",
+                "Wed, 21 Oct 2015 07:28:00 GMT"
+            )])),
+            Some(Duration::ZERO)
+        );
+
+        assert_eq!(retry_after(&headers(&[("retry-after", "soon")])), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+
+    /// Runs `policy` on an attempt that always fails with `error`, returning
+    /// the final error and the retries reported.
+    async fn run_failing(policy: RetryPolicy, error: fn() -> ProviderError) -> (String, Vec<u32>) {
+        let retries = Arc::new(Mutex::new(Vec::new()));
+        let policy = RetryPolicy {
+            initial_delay_ms: 0,
+            max_delay_ms: 0,
+            on_retry: Some({
+                let retries = Arc::clone(&retries);
+                Arc::new(move |event: RetryEvent| retries.lock().push(event.attempt))
+            }),
+            ..policy
+        };
+        let result = policy
+            .run(|| async { Err::<(), _>(Failure::from(error())) })
+            .await;
+        let retries = retries.lock().clone();
+        (result.unwrap_err().to_string(), retries)
+    }
+
+    #[tokio::test]
+    async fn retries_stop_at_max_attempts() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            ..Default::default()
+        };
+        let (error, retries) =
+            run_failing(policy, || ProviderError::network("connection reset")).await;
+        assert_eq!(retries, vec![2, 3]);
+        assert!(error.contains("gave up after 3 attempts"), "{}", error);
+
+        // 0 still makes the one attempt
+        let policy = RetryPolicy {
+            max_attempts: 0,
+            ..Default::default()
+        };
+        let (_, retries) = run_failing(policy, || ProviderError::network("connection reset")).await;
+        assert!(retries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_are_not_retried() {
+        let (error, retries) = run_failing(RetryPolicy::default(), || {
+            ProviderError::invalid_request("bad model")
+        })
+        .await;
+        assert!(retries.is_empty());
+        assert!(!error.contains("gave up"), "{}", error);
+    }
+}
@@ -0,0 +1,543 @@
+//! Keeps requests to each provider account within its rate limits, however
+//! many windows, comparisons or fallbacks are sending at once.
+//!
+//! Providers configured with limits are wrapped in a `ScheduledProvider`.
+//! Its requests, and calls outside `ChatProvider` made through a
+//! `Scheduler` (like the batch API), wait in a first-come, first-served queue shared by every
+//! provider with the same type and API key in the process, and are sent
+//! once a concurrency slot is free and the requests-per-minute and
+//! tokens-per-minute buckets allow. The buckets refill continuously. A
+//! request's tokens are estimated up front, from the prompt (as estimated
+//! for fitting the context window) plus `max_tokens`, and corrected from the reported usage once it finishes.
+//! Retries of a request don't go through the queue again.
+
+use super::error::ProviderError;
+use super::provider::{
+    ChatProvider, Message, ModelInfo, ProviderConfig, ProviderResponse, StreamCallback, Usage,
+};
+use crate::context;
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Queues by provider type and API key.
+static LANES: LazyLock<Mutex<HashMap<String, Arc<Lane>>>> = LazyLock::new(Default::default);
+
+/// Reported while a request waits for its turn, and once more with
+/// `waiting` false when it is sent.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueEvent {
+    pub provider: String,
+    pub waiting: bool,
+    /// Requests queued ahead of this one
+    pub position: usize,
+    /// Whether the request is waiting on the rate limits rather than on
+    /// the requests ahead of it or the concurrency cap
+    pub rate_limited: bool,
+}
+
+pub type QueueCallback = Arc<dyn Fn(QueueEvent) + Send + Sync + 'static>;
+
+/// Limits for one provider account; `None` leaves that one unlimited.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    pub requests_per_minute: Option<u32>,
+    /// Input plus output tokens
+    pub tokens_per_minute: Option<u32>,
+    /// Requests in flight at once
+    pub max_concurrent: Option<u32>,
+    #[serde(skip)]
+    pub on_queue: Option<QueueCallback>,
+}
+
+impl RateLimits {
+    pub fn is_unlimited(&self) -> bool {
+        self.requests_per_minute.is_none()
+            && self.tokens_per_minute.is_none()
+            && self.max_concurrent.is_none()
+    }
+}
+
+impl fmt::Debug for RateLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimits")
+            .field("requests_per_minute", &self.requests_per_minute)
+            .field("tokens_per_minute", &self.tokens_per_minute)
+            .field("max_concurrent", &self.max_concurrent)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A per-minute allowance that refills continuously, starting full.
+struct Bucket {
+    capacity: f64,
+    available: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(per_minute: u32) -> Self {
+        Self {
+            capacity: f64::from(per_minute.max(1)),
+            available: f64::from(per_minute.max(1)),
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.available = (self.available + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// How long until `amount` is available. More than the capacity is
+    /// never available, so callers ask for at most that.
+    fn wait_for(&self, amount: f64) -> Duration {
+        let missing = amount.min(self.capacity) - self.available;
+        if missing <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing * 60.0 / self.capacity)
+        }
+    }
+}
+
+/// Updates `bucket` to a possibly changed limit, keeping what's been used.
+fn configure(bucket: &mut Option<Bucket>, per_minute: Option<u32>) {
+    match (bucket.as_mut(), per_minute) {
+        (_, None) => *bucket = None,
+        (None, Some(per_minute)) => *bucket = Some(Bucket::new(per_minute)),
+        (Some(bucket), Some(per_minute)) => {
+            bucket.capacity = f64::from(per_minute.max(1));
+            bucket.available = bucket.available.min(bucket.capacity);
+        }
+    }
+}
+
+#[derive(Default)]
+struct LaneState {
+    /// Tickets of the waiting requests, in arrival order
+    queue: VecDeque<u64>,
+    next_ticket: u64,
+    in_flight: u32,
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+}
+
+/// Why the request at the head of the queue can't go yet.
+enum Blocked {
+    Concurrency,
+    /// The buckets will allow it after this long
+    RateLimit(Duration),
+}
+
+impl LaneState {
+    fn configure(&mut self, limits: &RateLimits) {
+        configure(&mut self.requests, limits.requests_per_minute);
+        configure(&mut self.tokens, limits.tokens_per_minute);
+    }
+
+    /// Takes a slot and the request's share of the buckets, if available.
+    fn try_admit(&mut self, tokens: u32, max_concurrent: Option<u32>) -> Result<(), Blocked> {
+        if max_concurrent.is_some_and(|max| self.in_flight >= max.max(1)) {
+            return Err(Blocked::Concurrency);
+        }
+
+        let now = Instant::now();
+        let tokens = f64::from(tokens);
+        let mut wait = Duration::ZERO;
+        if let Some(bucket) = &mut self.requests {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait_for(1.0));
+        }
+        if let Some(bucket) = &mut self.tokens {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait_for(tokens));
+        }
+        if !wait.is_zero() {
+            return Err(Blocked::RateLimit(wait));
+        }
+
+        if let Some(bucket) = &mut self.requests {
+            bucket.available -= 1.0;
+        }
+        if let Some(bucket) = &mut self.tokens {
+            bucket.available -= tokens.min(bucket.capacity);
+        }
+        self.in_flight += 1;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Lane {
+    state: Mutex<LaneState>,
+    /// Signalled whenever a request leaves the queue or finishes
+    changed: Notify,
+}
+
+fn lane(key: &str) -> Arc<Lane> {
+    Arc::clone(LANES.lock().entry(key.to_string()).or_default())
+}
+
+/// Takes a request out of the queue if it's dropped while waiting, e.g.
+/// when the generation is cancelled.
+struct Waiting<'a> {
+    lane: &'a Lane,
+    ticket: u64,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.lane
+            .state
+            .lock()
+            .queue
+            .retain(|&ticket| ticket != self.ticket);
+        self.lane.changed.notify_waiters();
+    }
+}
+
+/// A request's slot; frees it when dropped.
+struct Permit {
+    lane: Arc<Lane>,
+    /// Tokens taken from the bucket up front
+    estimate: u32,
+}
+
+impl Permit {
+    /// Corrects the token bucket with what the request actually used.
+    fn settle(&self, usage: Option<&Usage>) {
+        let Some(usage) = usage else {
+            return;
+        };
+        let used = usage.input_tokens + usage.cache_creation_input_tokens + usage.output_tokens;
+        let mut state = self.lane.state.lock();
+        if let Some(bucket) = &mut state.tokens {
+            let refund = f64::from(self.estimate.min(bucket.capacity as u32)) - f64::from(used);
+            bucket.available = (bucket.available + refund).min(bucket.capacity);
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.lane.state.lock().in_flight -= 1;
+        self.lane.changed.notify_waiters();
+    }
+}
+
+/// Lets requests to one provider account through in turn under its
+/// `RateLimits`.
+pub struct Scheduler {
+    provider: String,
+    lane: Arc<Lane>,
+    limits: RateLimits,
+}
+
+impl Scheduler {
+    pub fn new(provider_type: &str, config: &ProviderConfig) -> Self {
+        // Keys are hashed so they don't linger in the lane table
+        let mut hasher = DefaultHasher::new();
+        config.api_key.hash(&mut hasher);
+        let key = format!("{}:{:016x}", provider_type, hasher.finish());
+        Self {
+            provider: provider_type.to_string(),
+            lane: lane(&key),
+            limits: config.rate_limits.clone(),
+        }
+    }
+
+    /// Sends `request` once it's its turn, counting it as a request without
+    /// tokens of its own.
+    pub async fn run<T>(&self, request: impl Future<Output = T>) -> T {
+        let _permit = self.acquire(0).await;
+        request.await
+    }
+
+    fn report(&self, waiting: bool, position: usize, rate_limited: bool) {
+        if let Some(on_queue) = &self.limits.on_queue {
+            on_queue(QueueEvent {
+                provider: self.provider.clone(),
+                waiting,
+                position,
+                rate_limited,
+            });
+        }
+    }
+
+    /// Waits until the request at the head of the queue is this one and the
+    /// limits allow it.
+    async fn acquire(&self, estimate: u32) -> Permit {
+        let ticket = {
+            let mut state = self.lane.state.lock();
+            state.configure(&self.limits);
+            let ticket = state.next_ticket;
+            state.next_ticket += 1;
+            state.queue.push_back(ticket);
+            ticket
+        };
+        let waiting = Waiting {
+            lane: &self.lane,
+            ticket,
+        };
+
+        let mut reported = None;
+        loop {
+            // Registered before checking, so a change in between isn't missed
+            let changed = self.lane.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            let (position, blocked) = {
+                let mut state = self.lane.state.lock();
+                let position = state
+                    .queue
+                    .iter()
+                    .position(|&queued| queued == ticket)
+                    .unwrap_or_default();
+                let blocked = match position {
+                    0 => match state.try_admit(estimate, self.limits.max_concurrent) {
+                        Ok(()) => {
+                            state.queue.pop_front();
+                            None
+                        }
+                        Err(blocked) => Some(blocked),
+                    },
+                    _ => Some(Blocked::Concurrency),
+                };
+                (position, blocked)
+            };
+
+            let Some(blocked) = blocked else {
+                break;
+            };
+            let rate_limited = matches!(blocked, Blocked::RateLimit(_));
+            if reported != Some((position, rate_limited)) {
+                self.report(true, position, rate_limited);
+                reported = Some((position, rate_limited));
+            }
+            match blocked {
+                Blocked::RateLimit(wait) => {
+                    let _ = tokio::time::timeout(wait, changed).await;
+                }
+                Blocked::Concurrency => changed.await,
+            }
+        }
+
+        // Admitted: the queue no longer holds the ticket
+        std::mem::forget(waiting);
+        // The next request may be able to go right away too
+        self.lane.changed.notify_waiters();
+        if reported.is_some() {
+            self.report(false, 0, false);
+        }
+        Permit {
+            lane: Arc::clone(&self.lane),
+            estimate,
+        }
+    }
+}
+
+/// A provider whose requests wait their turn under `RateLimits`.
+pub struct ScheduledProvider {
+    inner: Box<dyn ChatProvider>,
+    scheduler: Scheduler,
+    max_tokens: u32,
+}
+
+impl ScheduledProvider {
+    pub fn new(inner: Box<dyn ChatProvider>, provider_type: &str, config: &ProviderConfig) -> Self {
+        Self {
+            inner,
+            scheduler: Scheduler::new(provider_type, config),
+            max_tokens: config.max_tokens,
+        }
+    }
+
+    fn estimate(&self, messages: &[Message]) -> u32 {
+        messages.iter().map(context::estimate_tokens).sum::<u32>() + self.max_tokens
+    }
+}
+
+#[async_trait]
+impl ChatProvider for ScheduledProvider {
+    fn supports_streaming(&self) -> bool {
+        self.inner.supports_streaming()
+    }
+
+    async fn send_message_streaming(
+        &self,
+        messages: Vec<Message>,
+        callback: StreamCallback,
+    ) -> Result<ProviderResponse, ProviderError> {
+        let permit = self.scheduler.acquire(self.estimate(&messages)).await;
+        let response = self
+            .inner
+            .send_message_streaming(messages, callback)
+            .await?;
+        permit.settle(response.usage.as_ref());
+        Ok(response)
+    }
+
+    async fn send_message_blocking(
+        &self,
+        messages: Vec<Message>,
+    ) -> Result<ProviderResponse, ProviderError> {
+        let permit = self.scheduler.acquire(self.estimate(&messages)).await;
+        let response = self.inner.send_message_blocking(messages).await?;
+        permit.settle(response.usage.as_ref());
+        Ok(response)
+    }
+
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, ProviderError> {
+        self.scheduler.run(self.inner.list_models()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apimodels::mock::MockProvider;
+    use crate::apimodels::{Attachment, AttachmentKind};
+
+    fn assert_secs(duration: Duration, secs: f64) {
+        assert!(
+            (duration.as_secs_f64() - secs).abs() < 1e-6,
+            "{:?} is not {}s",
+            duration,
+            secs
+        );
+    }
+
+    #[test]
+    fn buckets_refill_continuously_up_to_capacity() {
+        let mut bucket = Bucket::new(60);
+        let start = bucket.updated;
+        bucket.available = 0.0;
+
+        bucket.refill(start + Duration::from_secs(30));
+        assert!((bucket.available - 30.0).abs() < 1e-6);
+        assert_eq!(bucket.wait_for(10.0), Duration::ZERO);
+        assert_secs(bucket.wait_for(40.0), 10.0);
+        // More than the capacity waits only for a full bucket
+        assert_secs(bucket.wait_for(1_000.0), 30.0);
+
+        bucket.refill(start + Duration::from_secs(600));
+        assert_eq!(bucket.available, 60.0);
+    }
+
+    #[test]
+    fn admission_takes_a_slot_and_the_buckets_share() {
+        let mut state = LaneState::default();
+        state.configure(&RateLimits {
+            requests_per_minute: Some(2),
+            tokens_per_minute: Some(1_000),
+            ..Default::default()
+        });
+
+        assert!(state.try_admit(600, Some(1)).is_ok());
+        assert_eq!(state.in_flight, 1);
+        assert!(matches!(
+            state.try_admit(100, Some(1)),
+            Err(Blocked::Concurrency)
+        ));
+
+        state.in_flight = 0;
+        // 400 tokens left, refilling at about 16.7 a second
+        let Err(Blocked::RateLimit(wait)) = state.try_admit(600, None) else {
+            panic!("expected to wait for tokens");
+        };
+        assert!(wait > Duration::from_secs(11) && wait <= Duration::from_secs(12));
+
+        assert!(state.try_admit(400, None).is_ok());
+        // Both requests of the minute are used
+        let Err(Blocked::RateLimit(wait)) = state.try_admit(0, None) else {
+            panic!("expected to wait for a request");
+        };
+        assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn waiting_requests_go_in_arrival_order() {
+        let config = ProviderConfig {
+            api_key: format!("test-{}", ulid::Ulid::new()),
+            rate_limits: RateLimits {
+                max_concurrent: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let scheduler = Arc::new(Scheduler::new("mock", &config));
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let first = tokio::spawn({
+            let scheduler = Arc::clone(&scheduler);
+            async move {
+                scheduler
+                    .run(async {
+                        let _ = released.await;
+                    })
+                    .await
+            }
+        });
+        tokio::task::yield_now().await;
+
+        let mut waiting = Vec::new();
+        for index in 1..=3 {
+            let scheduler = Arc::clone(&scheduler);
+            let order = Arc::clone(&order);
+            waiting.push(tokio::spawn(async move {
+                scheduler.run(async { order.lock().push(index) }).await
+            }));
+            // Let it join the queue before the next one
+            tokio::task::yield_now().await;
+        }
+        assert!(order.lock().is_empty());
+        assert_eq!(scheduler.lane.state.lock().queue.len(), 3);
+
+        release.send(()).unwrap();
+        first.await.unwrap();
+        for handle in waiting {
+            handle.await.unwrap();
+        }
+        assert_eq!(*order.lock(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn estimates_count_attachments() {
+        let config = ProviderConfig {
+            max_tokens: 1_000,
+            ..Default::default()
+        };
+        let provider =
+            ScheduledProvider::new(Box::new(MockProvider::new(config.clone())), "mock", &config);
+        let message = Message {
+            role: "user".to_string(),
+            content: "What's in this picture?".to_string(),
+            attachments: vec![Attachment {
+                id: "1".to_string(),
+                kind: AttachmentKind::Image,
+                media_type: "image/png".to_string(),
+                filename: "photo.png".to_string(),
+                data: String::new(),
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            provider.estimate(std::slice::from_ref(&message)),
+            context::estimate_tokens(&message) + 1_000
+        );
+        assert!(provider.estimate(&[message]) > 1_000 + 1_000);
+    }
+}
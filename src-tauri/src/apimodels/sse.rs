@@ -0,0 +1,258 @@
+//! Server-sent events decoding shared by the streaming providers.
+//!
+//! Follows the WHATWG event stream format: CRLF/LF/CR line endings, comment
+//! lines, multi-line `data:` fields and blank-line dispatch. Input is
+//! buffered as raw bytes so multi-byte characters split across network
+//! chunks are decoded intact.
+
+use super::error::ProviderError;
+use super::retry::Failure;
+use futures_util::StreamExt;
+
+/// One dispatched event. `event` is `None` when the stream didn't name it,
+/// which the spec treats as `message`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+    pub id: Option<String>,
+}
+
+#[derive(Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+    event: Option<String>,
+    data: String,
+    has_data: bool,
+    last_event_id: Option<String>,
+}
+
+impl SseDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds a chunk of the response body, returning every event it completes.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut events = Vec::new();
+        let mut start = 0;
+        while let Some(offset) = self.buffer[start..]
+            .iter()
+            .position(|&b| b == b'\n' || b == b'\r')
+        {
+            let end = start + offset;
+            let next = match self.buffer[end] {
+                b'\r' if end + 1 == self.buffer.len() => {
+                    // Might be the first half of a CRLF split across chunks
+                    break;
+                }
+                b'\r' if self.buffer[end + 1] == b'\n' => end + 2,
+                _ => end + 1,
+            };
+
+            // Line terminators are ASCII, so a complete line is complete UTF-8
+            let line = String::from_utf8_lossy(&self.buffer[start..end]).into_owned();
+            start = next;
+
+            if let Some(event) = self.process_line(&line) {
+                events.push(event);
+            }
+        }
+
+        // Drop consumed bytes once per chunk rather than once per line
+        self.buffer.drain(..start);
+        events
+    }
+
+    /// Flushes a trailing event at end of stream, for servers that close the
+    /// connection without the final blank line.
+    pub fn finish(&mut self) -> Option<SseEvent> {
+        let rest = std::mem::take(&mut self.buffer);
+        let rest = String::from_utf8_lossy(&rest);
+        let rest = rest.trim_end_matches(['\r', '\n']);
+        if !rest.is_empty() {
+            if let Some(event) = self.process_line(rest) {
+                return Some(event);
+            }
+        }
+        self.dispatch()
+    }
+
+    fn process_line(&mut self, line: &str) -> Option<SseEvent> {
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if line.starts_with(':') {
+            // Comment, often used as a keep-alive
+            return None;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+
+        match field {
+            "event" => self.event = Some(value.to_string()),
+            "data" => {
+                if self.has_data {
+                    self.data.push('\n');
+                }
+                self.data.push_str(value);
+                self.has_data = true;
+            }
+            "id" if !value.contains('\0') => self.last_event_id = Some(value.to_string()),
+            // `retry` only matters for EventSource reconnection; unknown fields are ignored
+            _ => {}
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = self.event.take();
+        if !self.has_data {
+            return None;
+        }
+        self.has_data = false;
+
+        Some(SseEvent {
+            event,
+            data: std::mem::take(&mut self.data),
+            id: self.last_event_id.clone(),
+        })
+    }
+}
+
+/// Reads `response` as an event stream, calling `on_event` for each event
+/// until it returns `Ok(true)` or the body ends. A connection dropped
+/// midway is reported as a transient failure.
+pub async fn for_each_event<F>(response: reqwest::Response, mut on_event: F) -> Result<(), Failure>
+where
+    F: FnMut(SseEvent) -> Result<bool, Failure>,
+{
+    let mut stream = response.bytes_stream();
+    let mut decoder = SseDecoder::new();
+
+    while let Some(item) = stream.next().await {
+        let chunk = item.map_err(|e| Failure::from(ProviderError::from_read_error(&e)))?;
+        for event in decoder.push(&chunk) {
+            if on_event(event)? {
+                return Ok(());
+            }
+        }
+    }
+
+    if let Some(event) = decoder.finish() {
+        on_event(event)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(data: &str) -> SseEvent {
+        SseEvent {
+            data: data.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Feeds `input` one byte at a time, the worst case for splits.
+    fn decode_bytewise(input: &[u8]) -> Vec<SseEvent> {
+        let mut decoder = SseDecoder::new();
+        let mut events: Vec<SseEvent> = input
+            .iter()
+            .flat_map(|byte| decoder.push(std::slice::from_ref(byte)))
+            .collect();
+        events.extend(decoder.finish());
+        events
+    }
+
+    #[test]
+    fn events_split_across_chunks_decode_intact() {
+        let input = "event: delta\ndata: {\"text\":\"héllo 👋\"}\nid: 7\n\n";
+        let expected = vec![SseEvent {
+            event: Some("delta".to_string()),
+            data: "{\"text\":\"héllo 👋\"}".to_string(),
+            id: Some("7".to_string()),
+        }];
+        assert_eq!(decode_bytewise(input.as_bytes()), expected);
+
+        let mut decoder = SseDecoder::new();
+        let (first, second) = input.as_bytes().split_at(input.find('👋').unwrap() + 2);
+        assert!(decoder.push(first).is_empty());
+        assert_eq!(decoder.push(second), expected);
+    }
+
+    #[test]
+    fn all_line_endings_are_accepted() {
+        for input in [
+            "data: a\n\ndata: b\n\n",
+            "data: a\r\n\r\ndata: b\r\n\r\n",
+            "data: a\r\rdata: b\r\r",
+            "data: a\r\n\ndata: b\r\r\n",
+        ] {
+            let expected = vec![data("a"), data("b")];
+            // A final CR may be half a CRLF, so it waits for more or the end
+            let mut decoder = SseDecoder::new();
+            let mut events = decoder.push(input.as_bytes());
+            events.extend(decoder.finish());
+            assert_eq!(events, expected, "{:?}", input);
+            assert_eq!(decode_bytewise(input.as_bytes()), expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn a_crlf_split_between_chunks_is_one_line_ending() {
+        let mut decoder = SseDecoder::new();
+        assert!(decoder.push(b"data: a\r").is_empty());
+        assert!(decoder.push(b"\n\r").is_empty());
+        assert_eq!(decoder.push(b"\n"), vec![data("a")]);
+    }
+
+    #[test]
+    fn data_lines_are_joined_and_comments_skipped() {
+        let mut decoder = SseDecoder::new();
+        let events = decoder.push(
+            b": keep-alive\n\
+              data: first\n\
+              : between\n\
+              data:second\n\
+              data\n\
+              \n\
+              event: ping\n\
+              \n",
+        );
+        // An event without data isn't dispatched, nor does its name carry over
+        assert_eq!(events, vec![data("first\nsecond\n")]);
+        assert_eq!(decoder.push(b"data: x\n\n"), vec![data("x")]);
+    }
+
+    #[test]
+    fn ids_carry_over_to_later_events() {
+        let mut decoder = SseDecoder::new();
+        let events = decoder.push(b"id: 1\ndata: a\n\ndata: b\n\nid: 2\0\ndata: c\n\n");
+        let ids: Vec<_> = events.iter().map(|event| event.id.as_deref()).collect();
+        assert_eq!(ids, vec![Some("1"), Some("1"), Some("1")]);
+    }
+
+    #[test]
+    fn finish_flushes_an_unterminated_event() {
+        let mut decoder = SseDecoder::new();
+        assert!(decoder.push(b"data: one\ndata: two").is_empty());
+        assert_eq!(decoder.finish(), Some(data("one\ntwo")));
+        assert_eq!(decoder.finish(), None);
+
+        let mut decoder = SseDecoder::new();
+        assert!(decoder.push(b"data: done\r").is_empty());
+        assert_eq!(decoder.finish(), Some(data("done")));
+
+        let mut decoder = SseDecoder::new();
+        assert!(decoder.push(b": only a comment").is_empty());
+        assert_eq!(decoder.finish(), None);
+    }
+}
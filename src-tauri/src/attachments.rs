@@ -0,0 +1,304 @@
+use crate::apimodels::{Attachment, AttachmentKind, Message};
+use crate::chat::{db_error, ErrorResponse};
+use crate::AppState;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sqlx::{Sqlite, SqlitePool, Transaction};
+use std::collections::HashMap;
+use std::path::Path;
+use tauri::{AppHandle, Manager};
+
+/// Providers reject larger files (Anthropic's limit for PDFs is 32MB).
+/// Files are sent base64-encoded, so this applies to the encoded size.
+const MAX_ATTACHMENT_BYTES: usize = 32 * 1024 * 1024;
+
+/// Works out how a file should be sent from its extension.
+fn classify(filename: &str) -> Result<(AttachmentKind, &'static str), ErrorResponse> {
+    let extension = Path::new(filename)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    let classified = match extension.as_str() {
+        "png" => (AttachmentKind::Image, "image/png"),
+        "jpg" | "jpeg" => (AttachmentKind::Image, "image/jpeg"),
+        "gif" => (AttachmentKind::Image, "image/gif"),
+        "webp" => (AttachmentKind::Image, "image/webp"),
+        "pdf" => (AttachmentKind::Document, "application/pdf"),
+        "txt" | "md" | "csv" | "json" => (AttachmentKind::Document, "text/plain"),
+        _ => {
+            return Err(ErrorResponse {
+                message: "Unsupported attachment".to_string(),
+                details: Some(format!(
+                    "{} is not an image (png, jpeg, gif, webp), PDF or text file",
+                    filename
+                )),
+                provider_error: None,
+            })
+        }
+    };
+    Ok(classified)
+}
+
+fn kind_to_str(kind: AttachmentKind) -> &'static str {
+    match kind {
+        AttachmentKind::Image => "image",
+        AttachmentKind::Document => "document",
+    }
+}
+
+fn kind_from_str(kind: &str) -> AttachmentKind {
+    match kind {
+        "image" => AttachmentKind::Image,
+        _ => AttachmentKind::Document,
+    }
+}
+
+/// Stores a file that isn't part of a message yet.
+async fn store(db: &SqlitePool, filename: &str, data: &[u8]) -> Result<Attachment, ErrorResponse> {
+    let (kind, media_type) = classify(filename)?;
+
+    // Base64 turns every 3 bytes into 4 characters
+    let encoded_len = data.len().div_ceil(3) * 4;
+    if encoded_len > MAX_ATTACHMENT_BYTES {
+        return Err(ErrorResponse {
+            message: "Attachment too large".to_string(),
+            details: Some(format!(
+                "{} is {} bytes encoded for sending; the limit is {} bytes",
+                filename, encoded_len, MAX_ATTACHMENT_BYTES
+            )),
+            provider_error: None,
+        });
+    }
+    if media_type.starts_with("text/") && std::str::from_utf8(data).is_err() {
+        return Err(ErrorResponse {
+            message: "Unsupported attachment".to_string(),
+            details: Some(format!("{} is not valid UTF-8 text", filename)),
+            provider_error: None,
+        });
+    }
+
+    let kind_str = kind_to_str(kind);
+    let id = sqlx::query!(
+        r#"
+        INSERT INTO attachments (kind, media_type, filename, data)
+        VALUES (?, ?, ?, ?)
+        "#,
+        kind_str,
+        media_type,
+        filename,
+        data
+    )
+    .execute(db)
+    .await
+    .map_err(db_error)?
+    .last_insert_rowid();
+
+    Ok(Attachment {
+        id: id.to_string(),
+        kind,
+        media_type: media_type.to_string(),
+        filename: filename.to_string(),
+        data: String::new(),
+    })
+}
+
+/// Attaches a local file to the next message sent.
+#[tauri::command]
+pub async fn attach_file(path: String, app_handle: AppHandle) -> Result<Attachment, ErrorResponse> {
+    let app_state = app_handle.state::<AppState>();
+
+    let filename = Path::new(&path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(&path)
+        .to_string();
+    let data = tokio::fs::read(&path).await.map_err(|e| ErrorResponse {
+        message: "Failed to read attachment".to_string(),
+        details: Some(format!("{}: {}", path, e)),
+        provider_error: None,
+    })?;
+
+    store(&app_state.db, &filename, &data).await
+}
+
+/// Like `attach_file`, for file contents the frontend already has in memory
+/// (picked with a file input, pasted or dropped).
+#[tauri::command]
+pub async fn attach_file_data(
+    filename: String,
+    data: Vec<u8>,
+    app_handle: AppHandle,
+) -> Result<Attachment, ErrorResponse> {
+    let app_state = app_handle.state::<AppState>();
+    store(&app_state.db, &filename, &data).await
+}
+
+/// Discards an attachment that hasn't been sent. Sent attachments are part
+/// of the conversation and are only removed along with their message.
+#[tauri::command]
+pub async fn remove_attachment(
+    attachment_id: i64,
+    app_handle: AppHandle,
+) -> Result<(), ErrorResponse> {
+    let app_state = app_handle.state::<AppState>();
+
+    sqlx::query!(
+        "DELETE FROM attachments WHERE id = ? AND message_id IS NULL",
+        attachment_id
+    )
+    .execute(&app_state.db)
+    .await
+    .map_err(db_error)?;
+
+    Ok(())
+}
+
+/// Loads attachments that are waiting to be sent, in the order given,
+/// without their contents.
+pub(crate) async fn load_pending(
+    db: &SqlitePool,
+    attachment_ids: &[i64],
+) -> Result<Vec<Attachment>, ErrorResponse> {
+    let mut attachments = Vec::with_capacity(attachment_ids.len());
+    for &attachment_id in attachment_ids {
+        let row = sqlx::query!(
+            r#"
+            SELECT
+                id as "id!",
+                kind,
+                media_type,
+                filename
+            FROM attachments
+            WHERE id = ? AND message_id IS NULL
+            "#,
+            attachment_id
+        )
+        .fetch_optional(db)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| ErrorResponse {
+            message: "Attachment not found".to_string(),
+            details: Some(format!(
+                "Attachment {} doesn't exist or was already sent",
+                attachment_id
+            )),
+            provider_error: None,
+        })?;
+
+        attachments.push(Attachment {
+            id: row.id.to_string(),
+            kind: kind_from_str(&row.kind),
+            media_type: row.media_type,
+            filename: row.filename,
+            data: String::new(),
+        });
+    }
+    Ok(attachments)
+}
+
+/// Reads the contents of the attachments in `messages`. They're only
+/// loaded into the prompt of a request, never kept in the chat history.
+pub(crate) async fn load_data(
+    db: &SqlitePool,
+    messages: &mut [Message],
+) -> Result<(), ErrorResponse> {
+    for attachment in messages
+        .iter_mut()
+        .flat_map(|message| message.attachments.iter_mut())
+        .filter(|attachment| attachment.data.is_empty())
+    {
+        let attachment_id = attachment.id.parse::<i64>().unwrap_or_default();
+        let row = sqlx::query!("SELECT data FROM attachments WHERE id = ?", attachment_id)
+            .fetch_optional(db)
+            .await
+            .map_err(db_error)?
+            .ok_or_else(|| ErrorResponse {
+                message: "Attachment not found".to_string(),
+                details: Some(format!("{} was deleted", attachment.filename)),
+                provider_error: None,
+            })?;
+        attachment.data = BASE64.encode(&row.data);
+    }
+    Ok(())
+}
+
+/// Ties pending attachments to the message they were sent with. Fails if
+/// one of them was removed or sent with another message in the meantime.
+pub(crate) async fn link_to_message(
+    tx: &mut Transaction<'_, Sqlite>,
+    attachment_ids: &[i64],
+    message_id: i64,
+) -> Result<(), ErrorResponse> {
+    for attachment_id in attachment_ids {
+        let linked = sqlx::query!(
+            "UPDATE attachments SET message_id = ? WHERE id = ? AND message_id IS NULL",
+            message_id,
+            attachment_id
+        )
+        .execute(&mut **tx)
+        .await
+        .map_err(db_error)?
+        .rows_affected();
+        if linked != 1 {
+            return Err(ErrorResponse {
+                message: "Attachment not found".to_string(),
+                details: Some(format!(
+                    "Attachment {} doesn't exist or was already sent",
+                    attachment_id
+                )),
+                provider_error: None,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Fills in `attachments` on messages loaded from a conversation, without
+/// their contents.
+pub(crate) async fn load_into_messages(
+    db: &SqlitePool,
+    conversation_id: i64,
+    messages: &mut [Message],
+) -> Result<(), ErrorResponse> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+            a.id as "id!",
+            a.message_id as "message_id!",
+            a.kind,
+            a.media_type,
+            a.filename
+        FROM attachments a
+        JOIN messages m ON m.id = a.message_id
+        WHERE m.conversation_id = ?
+        ORDER BY a.id ASC
+        "#,
+        conversation_id
+    )
+    .fetch_all(db)
+    .await
+    .map_err(db_error)?;
+
+    let mut by_message: HashMap<String, Vec<Attachment>> = HashMap::new();
+    for row in rows {
+        by_message
+            .entry(row.message_id.to_string())
+            .or_default()
+            .push(Attachment {
+                id: row.id.to_string(),
+                kind: kind_from_str(&row.kind),
+                media_type: row.media_type,
+                filename: row.filename,
+                data: String::new(),
+            });
+    }
+
+    for message in messages {
+        if let Some(attachments) = by_message.remove(&message.id) {
+            message.attachments = attachments;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,497 @@
+//! Bulk prompting through Anthropic's Message Batches API, for evaluations
+//! that don't need answers right away. Jobs are tracked in the `batches`
+//! table; once a batch has ended, each answered prompt is imported as its
+//! own conversation.
+
+use crate::apimodels::anthropic::{AnthropicProvider, BatchRequestCounts, BatchStatus};
+use crate::apimodels::{Message, ProviderError, Scheduler};
+use crate::chat::{
+    db_error, insert_conversation_in, register_conversation_model, save_message,
+    ConversationSettings, ErrorResponse, MessageMetadata,
+};
+use crate::config::{ConfigState, GenerationParams};
+use crate::AppState;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::collections::{HashMap, HashSet};
+use tauri::{AppHandle, Manager, State};
+
+/// Batches are only offered by Anthropic for now.
+const BATCH_PROVIDER: &str = "anthropic";
+
+/// One prompt of a batch, sent as a single user message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchPrompt {
+    /// Identifies the prompt in the results; defaults to `prompt-<n>`
+    #[serde(default)]
+    pub custom_id: Option<String>,
+    pub message: String,
+}
+
+/// A batch as shown to the UI.
+#[derive(Debug, Serialize)]
+pub struct BatchJob {
+    id: i64,
+    provider_batch_id: String,
+    provider: String,
+    model: String,
+    status: String,
+    request_counts: BatchRequestCounts,
+    created_at: String,
+    updated_at: String,
+    ended_at: Option<String>,
+    expires_at: Option<String>,
+    imported_at: Option<String>,
+}
+
+#[derive(Debug)]
+struct BatchRow {
+    id: i64,
+    provider_batch_id: String,
+    provider: String,
+    model: String,
+    status: String,
+    request_counts: String,
+    requests: String,
+    settings: String,
+    created_at: String,
+    updated_at: String,
+    ended_at: Option<String>,
+    expires_at: Option<String>,
+    imported_at: Option<String>,
+}
+
+impl From<BatchRow> for BatchJob {
+    fn from(row: BatchRow) -> Self {
+        Self {
+            id: row.id,
+            provider_batch_id: row.provider_batch_id,
+            provider: row.provider,
+            model: row.model,
+            status: row.status,
+            request_counts: serde_json::from_str(&row.request_counts).unwrap_or_default(),
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            ended_at: row.ended_at,
+            expires_at: row.expires_at,
+            imported_at: row.imported_at,
+        }
+    }
+}
+
+/// A prompt that got no answer.
+#[derive(Debug, Serialize)]
+pub struct BatchFailure {
+    custom_id: String,
+    error: ProviderError,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchImport {
+    /// One per answered prompt, in the order the prompts were submitted
+    conversation_ids: Vec<i64>,
+    failures: Vec<BatchFailure>,
+}
+
+fn json_error(e: serde_json::Error) -> ErrorResponse {
+    ErrorResponse {
+        message: "Failed to serialize batch".to_string(),
+        details: Some(e.to_string()),
+        provider_error: None,
+    }
+}
+
+/// A provider built from the current Anthropic settings, and the scheduler
+/// its calls wait in along with the account's other requests. Tools aren't
+/// offered: a batch request can't run them and continue.
+fn batch_provider(
+    config_state: &ConfigState,
+) -> Result<(AnthropicProvider, Scheduler, ConversationSettings), ErrorResponse> {
+    let config = config_state.0.lock();
+    let settings = config
+        .providers
+        .get(BATCH_PROVIDER)
+        .ok_or_else(|| ErrorResponse {
+            message: "Provider configuration error".to_string(),
+            details: Some(format!("Provider {} is not configured", BATCH_PROVIDER)),
+            provider_error: None,
+        })?;
+
+    let mut provider_config = settings.to_provider_config();
+    provider_config.retry = config.retry.clone();
+    provider_config.recording = config.recording.clone();
+    // The same checks `ProviderFactory` makes
+    provider_config
+        .sampling
+        .validate(&AnthropicProvider::SAMPLING_LIMITS)
+        .and_then(|()| AnthropicProvider::validate_thinking(&provider_config))
+        .map_err(|details| ErrorResponse {
+            message: "Invalid provider settings".to_string(),
+            details: Some(details),
+            provider_error: None,
+        })?;
+
+    let conversation_settings = ConversationSettings {
+        system_prompt: config.system_prompt.clone(),
+        provider: Some(BATCH_PROVIDER.to_string()),
+        model: Some(settings.model.clone()),
+        params: GenerationParams::from_settings(settings),
+        ..Default::default()
+    };
+    Ok((
+        AnthropicProvider::new(provider_config.clone())?,
+        Scheduler::new(BATCH_PROVIDER, &provider_config),
+        conversation_settings,
+    ))
+}
+
+/// The API accepts 1 to 64 letters, digits, `_` and `-`.
+fn validate_custom_id(custom_id: &str) -> Result<(), ErrorResponse> {
+    let valid = (1..=64).contains(&custom_id.len())
+        && custom_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if valid {
+        Ok(())
+    } else {
+        Err(ErrorResponse {
+            message: "Invalid batch prompt".to_string(),
+            details: Some(format!(
+                "custom_id {:?} must be 1 to 64 letters, digits, '_' or '-'",
+                custom_id
+            )),
+            provider_error: None,
+        })
+    }
+}
+
+/// The messages sent for `prompt`.
+fn prompt_messages(system_prompt: Option<&str>, prompt: &BatchPrompt) -> Vec<Message> {
+    let mut messages: Vec<Message> = system_prompt
+        .map(|system_prompt| Message {
+            id: "system".to_string(),
+            role: "system".to_string(),
+            content: system_prompt.to_string(),
+            ..Default::default()
+        })
+        .into_iter()
+        .collect();
+    messages.push(Message {
+        id: "user".to_string(),
+        role: "user".to_string(),
+        content: prompt.message.clone(),
+        ..Default::default()
+    });
+    messages
+}
+
+async fn load_batch(db: &SqlitePool, batch_id: i64) -> Result<BatchRow, ErrorResponse> {
+    sqlx::query_as!(
+        BatchRow,
+        r#"
+        SELECT
+            id as "id!", provider_batch_id, provider, model, status, request_counts,
+            requests, settings, created_at, updated_at, ended_at, expires_at, imported_at
+        FROM batches
+        WHERE id = ?
+        "#,
+        batch_id
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(db_error)?
+    .ok_or_else(|| ErrorResponse {
+        message: "Batch not found".to_string(),
+        details: Some(format!("No batch with id {}", batch_id)),
+        provider_error: None,
+    })
+}
+
+/// Saves what the provider reported about the batch.
+async fn update_status(
+    db: &SqlitePool,
+    batch_id: i64,
+    status: &BatchStatus,
+) -> Result<(), ErrorResponse> {
+    let request_counts = serde_json::to_string(&status.request_counts).map_err(json_error)?;
+    sqlx::query!(
+        r#"
+        UPDATE batches
+        SET status = ?, request_counts = ?, ended_at = ?, expires_at = ?,
+            updated_at = datetime('now')
+        WHERE id = ?
+        "#,
+        status.processing_status,
+        request_counts,
+        status.ended_at,
+        status.expires_at,
+        batch_id
+    )
+    .execute(db)
+    .await
+    .map_err(db_error)?;
+    Ok(())
+}
+
+/// Submits `prompts` as one batch, each answered independently with the
+/// current Anthropic settings and `system_prompt` (or the default one).
+#[tauri::command]
+pub async fn submit_batch(
+    prompts: Vec<BatchPrompt>,
+    system_prompt: Option<String>,
+    app_handle: AppHandle,
+    config_state: State<'_, ConfigState>,
+) -> Result<BatchJob, ErrorResponse> {
+    if prompts.is_empty() {
+        return Err(ErrorResponse {
+            message: "Nothing to submit".to_string(),
+            details: Some("A batch needs at least one prompt".to_string()),
+            provider_error: None,
+        });
+    }
+
+    let (provider, scheduler, mut settings) = batch_provider(&config_state)?;
+    if system_prompt.is_some() {
+        settings.system_prompt = system_prompt;
+    }
+    settings.system_prompt = settings
+        .system_prompt
+        .filter(|prompt| !prompt.trim().is_empty());
+
+    let mut seen = HashSet::new();
+    let prompts = prompts
+        .into_iter()
+        .enumerate()
+        .map(|(index, prompt)| {
+            let custom_id = prompt
+                .custom_id
+                .unwrap_or_else(|| format!("prompt-{}", index + 1));
+            validate_custom_id(&custom_id)?;
+            if !seen.insert(custom_id.clone()) {
+                return Err(ErrorResponse {
+                    message: "Invalid batch prompt".to_string(),
+                    details: Some(format!("custom_id {:?} is used twice", custom_id)),
+                    provider_error: None,
+                });
+            }
+            Ok(BatchPrompt {
+                custom_id: Some(custom_id),
+                message: prompt.message,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let requests = prompts
+        .iter()
+        .map(|prompt| {
+            (
+                prompt.custom_id.clone().unwrap_or_default(),
+                prompt_messages(settings.system_prompt.as_deref(), prompt),
+            )
+        })
+        .collect();
+    let status = scheduler.run(provider.create_batch(requests)).await?;
+
+    let app_state = app_handle.state::<AppState>();
+    let db = &app_state.db;
+    let model = settings.model.clone().unwrap_or_default();
+    let request_counts = serde_json::to_string(&status.request_counts).map_err(json_error)?;
+    let requests = serde_json::to_string(&prompts).map_err(json_error)?;
+    let settings = serde_json::to_string(&settings).map_err(json_error)?;
+    let id = sqlx::query!(
+        r#"
+        INSERT INTO batches (
+            provider_batch_id, provider, model, status, request_counts, requests, settings,
+            ended_at, expires_at
+        )
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
+        status.id,
+        BATCH_PROVIDER,
+        model,
+        status.processing_status,
+        request_counts,
+        requests,
+        settings,
+        status.ended_at,
+        status.expires_at
+    )
+    .execute(db)
+    .await
+    .map_err(db_error)?
+    .last_insert_rowid();
+
+    Ok(load_batch(db, id).await?.into())
+}
+
+/// All batches, newest first, as last polled.
+#[tauri::command]
+pub async fn list_batches(app_handle: AppHandle) -> Result<Vec<BatchJob>, ErrorResponse> {
+    let app_state = app_handle.state::<AppState>();
+    let rows = sqlx::query_as!(
+        BatchRow,
+        r#"
+        SELECT
+            id as "id!", provider_batch_id, provider, model, status, request_counts,
+            requests, settings, created_at, updated_at, ended_at, expires_at, imported_at
+        FROM batches
+        ORDER BY created_at DESC, id DESC
+        "#
+    )
+    .fetch_all(&app_state.db)
+    .await
+    .map_err(db_error)?;
+
+    Ok(rows.into_iter().map(BatchJob::from).collect())
+}
+
+/// Asks the provider how the batch is doing and saves the answer.
+#[tauri::command]
+pub async fn poll_batch(
+    batch_id: i64,
+    app_handle: AppHandle,
+    config_state: State<'_, ConfigState>,
+) -> Result<BatchJob, ErrorResponse> {
+    let app_state = app_handle.state::<AppState>();
+    let db = &app_state.db;
+    let row = load_batch(db, batch_id).await?;
+    let (provider, scheduler, _) = batch_provider(&config_state)?;
+
+    let status = scheduler
+        .run(provider.get_batch(&row.provider_batch_id))
+        .await?;
+    update_status(db, batch_id, &status).await?;
+    Ok(load_batch(db, batch_id).await?.into())
+}
+
+/// Stops the batch; prompts already answered can still be imported once it
+/// has ended.
+#[tauri::command]
+pub async fn cancel_batch(
+    batch_id: i64,
+    app_handle: AppHandle,
+    config_state: State<'_, ConfigState>,
+) -> Result<BatchJob, ErrorResponse> {
+    let app_state = app_handle.state::<AppState>();
+    let db = &app_state.db;
+    let row = load_batch(db, batch_id).await?;
+    let (provider, scheduler, _) = batch_provider(&config_state)?;
+
+    let status = scheduler
+        .run(provider.cancel_batch(&row.provider_batch_id))
+        .await?;
+    update_status(db, batch_id, &status).await?;
+    Ok(load_batch(db, batch_id).await?.into())
+}
+
+/// Saves each answered prompt of an ended batch as a conversation holding
+/// the prompt and its reply. A batch can only be imported once.
+#[tauri::command]
+pub async fn import_batch_results(
+    batch_id: i64,
+    app_handle: AppHandle,
+    config_state: State<'_, ConfigState>,
+) -> Result<BatchImport, ErrorResponse> {
+    let app_state = app_handle.state::<AppState>();
+    let db = &app_state.db;
+    let row = load_batch(db, batch_id).await?;
+    if row.imported_at.is_some() {
+        return Err(ErrorResponse {
+            message: "Batch already imported".to_string(),
+            details: Some(format!("Batch {} has already been imported", batch_id)),
+            provider_error: None,
+        });
+    }
+
+    let (provider, scheduler, _) = batch_provider(&config_state)?;
+    let status = scheduler
+        .run(provider.get_batch(&row.provider_batch_id))
+        .await?;
+    update_status(db, batch_id, &status).await?;
+    if !status.has_ended() {
+        return Err(ErrorResponse {
+            message: "Batch still processing".to_string(),
+            details: Some(format!(
+                "{} of the batch's requests are still being processed",
+                status.request_counts.processing
+            )),
+            provider_error: None,
+        });
+    }
+
+    let prompts: Vec<BatchPrompt> = serde_json::from_str(&row.requests).map_err(json_error)?;
+    let settings: ConversationSettings = serde_json::from_str(&row.settings).map_err(json_error)?;
+    let mut results: HashMap<String, _> = scheduler
+        .run(provider.batch_results(&row.provider_batch_id))
+        .await?
+        .into_iter()
+        .map(|result| (result.custom_id, result.outcome))
+        .collect();
+
+    let model_id = register_conversation_model(db, &settings).await?;
+    let mut tx = db.begin().await.map_err(db_error)?;
+    // Claimed in the same transaction as the conversations, so a failed or
+    // concurrent import can't leave any of them behind twice
+    let claimed = sqlx::query!(
+        "UPDATE batches SET imported_at = datetime('now') WHERE id = ? AND imported_at IS NULL",
+        batch_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(db_error)?
+    .rows_affected();
+    if claimed != 1 {
+        return Err(ErrorResponse {
+            message: "Batch already imported".to_string(),
+            details: Some(format!("Batch {} has already been imported", batch_id)),
+            provider_error: None,
+        });
+    }
+
+    let mut import = BatchImport {
+        conversation_ids: Vec::new(),
+        failures: Vec::new(),
+    };
+    for prompt in prompts {
+        let custom_id = prompt.custom_id.clone().unwrap_or_default();
+        let response = match results.remove(&custom_id) {
+            Some(Ok(response)) => response,
+            Some(Err(error)) => {
+                import.failures.push(BatchFailure { custom_id, error });
+                continue;
+            }
+            None => {
+                import.failures.push(BatchFailure {
+                    custom_id,
+                    error: ProviderError::parse("The batch returned no result for this prompt"),
+                });
+                continue;
+            }
+        };
+
+        let metadata = MessageMetadata {
+            model: settings.model.clone(),
+            provider: settings.provider.clone(),
+            max_tokens: settings.params.max_tokens,
+            thinking: response.thinking,
+            usage: response.usage,
+            batch: true,
+            ..Default::default()
+        };
+        let conversation_id =
+            insert_conversation_in(&mut tx, model_id.as_deref(), &settings).await?;
+        save_message(&mut tx, conversation_id, "user", &prompt.message, None).await?;
+        save_message(
+            &mut tx,
+            conversation_id,
+            "assistant",
+            &response.text,
+            Some(&metadata),
+        )
+        .await?;
+        import.conversation_ids.push(conversation_id);
+    }
+    tx.commit().await.map_err(db_error)?;
+
+    Ok(import)
+}
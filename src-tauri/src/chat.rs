@@ -1,24 +1,55 @@
 use crate::apimodels::{
-    Message, MessageReactions, ProviderConfig, ProviderFactory, StreamResponse,
+    Attachment, ChatProvider, Comparison, Message, MessageReactions, ProviderConfig, ProviderError,
+    ProviderFactory, QueueEvent, RateLimits, ResponseSchema, RetryEvent, RetryPolicy,
+    SamplingParams, StreamResponse, ThinkingBlock, ToolCall, ToolResult, Usage,
 };
-use crate::config::ConfigState;
+use crate::attachments;
+use crate::config::{AppConfig, ConfigState, GenerationParams, ProviderSettings};
+use crate::context::{self, ContextReport, ContextSettings, ContextSummary, FittedPrompt};
+use crate::models;
+use crate::schema;
+use crate::tools::ToolRegistry;
 use crate::AppState;
 use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use sqlx::{Sqlite, Transaction};
+use sqlx::{Sqlite, SqlitePool, Transaction};
+use std::collections::HashMap;
 use std::sync::Arc;
 use tauri::{AppHandle, Emitter, Manager, State};
+use tokio_util::sync::CancellationToken;
+
+/// Upper bound on model round-trips for one user message, so a model that
+/// keeps calling tools can't loop forever.
+const MAX_TOOL_ITERATIONS: usize = 10;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Response {
     reply: String,
+    /// Set when `cancel_generation` stopped the request early
+    interrupted: bool,
+    /// The reply parsed as JSON, when a response schema was requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    structured: Option<Value>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ErrorResponse {
-    message: String,
-    details: Option<String>,
+    pub(crate) message: String,
+    pub(crate) details: Option<String>,
+    /// Set when a provider failed, so the UI can react to the cause
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) provider_error: Option<ProviderError>,
+}
+
+impl From<ProviderError> for ErrorResponse {
+    fn from(error: ProviderError) -> Self {
+        Self {
+            message: error.title().to_string(),
+            details: Some(error.to_string()),
+            provider_error: Some(error),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -52,18 +83,364 @@ struct ConversationRow {
     timestamp: String, // COALESCE ensures non-null
 }
 
+/// JSON stored in `messages.metadata`, recording how a message was produced.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct MessageMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) provider: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_tokens: Option<u32>,
+    /// Sampling parameters actually sent, so results can be reproduced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sampling: Option<SamplingParams>,
+    /// Extended thinking blocks, kept with their signatures for replay.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) thinking: Vec<ThinkingBlock>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) tool_calls: Vec<ToolCall>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) tool_result: Option<ToolResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) usage: Option<Usage>,
+    /// Generation was cancelled; the content is the partial reply
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) interrupted: bool,
+    /// Messages left out of the prompt to fit the context window
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) context: Option<ContextReport>,
+    /// Providers in the fallback chain that failed before `provider` answered
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) failed_providers: Vec<FailedAttempt>,
+    /// Set on the replies of a model comparison
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) comparison: Option<Comparison>,
+    /// The reply parsed as JSON, once it matched the requested schema
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) structured: Option<Value>,
+    /// Why the reply still didn't match the requested schema after the
+    /// configured re-asks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) schema_error: Option<String>,
+    /// Answered through the Message Batches API, which is billed at half
+    /// the usual rate
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) batch: bool,
+}
+
+/// A provider that failed with a transient error before the next one in
+/// the fallback chain was tried.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct FailedAttempt {
+    provider: String,
+    model: String,
+    error: ProviderError,
+}
+
+/// Emitted as `provider-fallback` when moving down the fallback chain.
+/// Text streamed by the failed provider is discarded.
+#[derive(Debug, Clone, Serialize)]
+struct FallbackEvent {
+    from_provider: String,
+    from_model: String,
+    to_provider: String,
+    to_model: String,
+    reason: String,
+}
+
+/// Per-conversation settings, stored as JSON in `conversations.settings`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConversationSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    /// Written by the `summarize` context strategy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_summary: Option<ContextSummary>,
+    /// Provider and model this conversation uses; `None` (conversations
+    /// from before these were stored) follows the global active provider
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub params: GenerationParams,
+}
+
+/// The provider, model and parameters a conversation runs with.
+#[derive(Debug, Serialize)]
+pub struct ConversationModel {
+    provider: String,
+    model: String,
+    params: GenerationParams,
+    /// Whether replies are streamed, per the provider's settings
+    streaming: bool,
+}
+
+/// The provider settings a conversation runs with: the provider's global
+/// settings (credentials, endpoint, ...) with the conversation's model and
+/// parameters on top.
+fn resolve_provider(
+    config: &AppConfig,
+    settings: &ConversationSettings,
+) -> Result<(String, ProviderSettings), ErrorResponse> {
+    let provider = settings
+        .provider
+        .clone()
+        .unwrap_or_else(|| config.active_provider.clone());
+    let mut provider_settings =
+        config
+            .providers
+            .get(&provider)
+            .cloned()
+            .ok_or_else(|| ErrorResponse {
+                message: "Provider configuration error".to_string(),
+                details: Some(format!("Provider {} is not configured", provider)),
+                provider_error: None,
+            })?;
+
+    if let Some(model) = &settings.model {
+        provider_settings.model = model.clone();
+    }
+    settings.params.apply(&mut provider_settings);
+    Ok((provider, provider_settings))
+}
+
+/// The conversation so far plus a new user message, as sent to providers.
+/// Comparison replies that weren't picked are left out. The system prompt
+/// is sent with every request but never stored as a message.
+pub(crate) fn build_prompt(
+    chat_history: &ChatHistory,
+    system_prompt: Option<String>,
+    message: &str,
+    attachments: Vec<Attachment>,
+) -> Vec<Message> {
+    let mut prompt: Vec<Message> = system_prompt
+        .map(|system_prompt| Message {
+            id: "system".to_string(),
+            role: "system".to_string(),
+            content: system_prompt,
+            ..Default::default()
+        })
+        .into_iter()
+        .collect();
+    prompt.extend(
+        chat_history
+            .0
+            .lock()
+            .iter()
+            .filter(|message| message.is_selected())
+            .cloned(),
+    );
+    prompt.push(Message {
+        role: "user".to_string(),
+        content: message.to_string(),
+        attachments,
+        ..Default::default()
+    });
+    prompt
+}
+
+/// Settings for each usable entry of the fallback chain, skipping the
+/// conversation's own provider and model and providers that aren't
+/// configured.
+fn fallback_settings(
+    config: &AppConfig,
+    provider: &str,
+    model: &str,
+) -> Vec<(String, ProviderSettings)> {
+    config
+        .fallback_chain
+        .iter()
+        .filter(|entry| !(entry.provider == provider && entry.model == model))
+        .filter_map(|entry| {
+            let Some(settings) = config.providers.get(&entry.provider) else {
+                log::warn!(
+                    "Skipping fallback to unconfigured provider {}",
+                    entry.provider
+                );
+                return None;
+            };
+            Some((
+                entry.provider.clone(),
+                ProviderSettings {
+                    model: entry.model.clone(),
+                    ..settings.clone()
+                },
+            ))
+        })
+        .collect()
+}
+
+/// A provider `process_message` can send to: the conversation's own or a
+/// fallback.
+pub(crate) struct Candidate {
+    pub(crate) provider_type: String,
+    pub(crate) config: ProviderConfig,
+    pub(crate) streaming: bool,
+    /// `None` when the model's context window isn't known
+    prompt_budget: Option<u32>,
+    pub(crate) provider: Box<dyn ChatProvider>,
+    /// The same model without tools or a response schema, for summarizing
+    /// turns that no longer fit
+    summarizer: Box<dyn ChatProvider>,
+}
+
+impl Candidate {
+    pub(crate) async fn new(
+        db: &SqlitePool,
+        provider_type: String,
+        config: ProviderConfig,
+        streaming: bool,
+        context_settings: &ContextSettings,
+    ) -> Result<Self, ErrorResponse> {
+        let provider = ProviderFactory::create_provider(&provider_type, config.clone())?;
+        let summarizer = ProviderFactory::create_provider(
+            &provider_type,
+            ProviderConfig {
+                tools: Vec::new(),
+                response_schema: None,
+                ..config.clone()
+            },
+        )?;
+        let registered_window = models::lookup(db, &provider_type, &config.model)
+            .await?
+            .and_then(|args| args.context_window);
+        let prompt_budget = context::prompt_budget(
+            context_settings,
+            &config.model,
+            registered_window,
+            config.num_ctx,
+            config.max_tokens,
+        );
+        Ok(Self {
+            streaming: provider.supports_streaming() && streaming,
+            provider_type,
+            config,
+            prompt_budget,
+            provider,
+            summarizer,
+        })
+    }
+
+    /// Fits `messages` into the model's context window, when it's known.
+    pub(crate) async fn fit(
+        &self,
+        messages: Vec<Message>,
+        context_settings: &ContextSettings,
+        previous: Option<&ContextSummary>,
+    ) -> Result<FittedPrompt, ProviderError> {
+        match self.prompt_budget {
+            Some(budget) => {
+                context::fit(
+                    messages,
+                    &context_settings.strategy,
+                    budget,
+                    self.summarizer.as_ref(),
+                    previous,
+                )
+                .await
+            }
+            None => Ok(FittedPrompt {
+                messages,
+                report: None,
+                summary: None,
+            }),
+        }
+    }
+
+    /// How this candidate's replies were produced.
+    pub(crate) fn metadata(&self) -> MessageMetadata {
+        MessageMetadata {
+            model: Some(self.config.model.clone()),
+            provider: Some(self.provider_type.clone()),
+            max_tokens: Some(self.config.max_tokens),
+            sampling: Some(self.config.sampling.clone()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Creates a conversation with a snapshot of the global provider, model and
+/// parameters, so later changes to the defaults don't affect it.
+async fn create_conversation(
+    db: &SqlitePool,
+    config_state: &ConfigState,
+) -> Result<i64, ErrorResponse> {
+    let settings = {
+        let config = config_state.0.lock();
+        let provider_settings = config.providers.get(&config.active_provider);
+        ConversationSettings {
+            provider: Some(config.active_provider.clone()),
+            model: provider_settings.map(|settings| settings.model.clone()),
+            params: provider_settings
+                .map(GenerationParams::from_settings)
+                .unwrap_or_default(),
+            ..Default::default()
+        }
+    };
+    insert_conversation(db, &settings).await
+}
+
+/// Creates a conversation that runs with `settings`.
+pub(crate) async fn insert_conversation(
+    db: &SqlitePool,
+    settings: &ConversationSettings,
+) -> Result<i64, ErrorResponse> {
+    let model_id = register_conversation_model(db, settings).await?;
+    let mut tx = db.begin().await.map_err(db_error)?;
+    let id = insert_conversation_in(&mut tx, model_id.as_deref(), settings).await?;
+    tx.commit().await.map_err(db_error)?;
+    Ok(id)
+}
+
+/// Adds the model of `settings` to the registry, returning its id.
+pub(crate) async fn register_conversation_model(
+    db: &SqlitePool,
+    settings: &ConversationSettings,
+) -> Result<Option<String>, ErrorResponse> {
+    match (&settings.provider, &settings.model) {
+        (Some(provider), Some(model)) => {
+            Ok(Some(models::ensure_registered(db, provider, model).await?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Creates a conversation as part of `tx`, for callers that add several
+/// at once. `model_id` comes from `register_conversation_model`.
+pub(crate) async fn insert_conversation_in(
+    tx: &mut Transaction<'_, Sqlite>,
+    model_id: Option<&str>,
+    settings: &ConversationSettings,
+) -> Result<i64, ErrorResponse> {
+    let id = sqlx::query!(
+        r#"
+        INSERT INTO conversations (created_at, updated_at, model_id)
+        VALUES (datetime('now'), datetime('now'), ?)
+        "#,
+        model_id
+    )
+    .execute(&mut **tx)
+    .await
+    .map_err(db_error)?
+    .last_insert_rowid();
+
+    save_conversation_settings(&mut **tx, id, settings).await?;
+    Ok(id)
+}
+
 impl From<DbMessage> for Message {
     fn from(db_msg: DbMessage) -> Self {
         let timestamp = DateTime::parse_from_rfc3339(&db_msg.created_at)
             .map(|dt| dt.with_timezone(&Local).format("%I:%M %p").to_string())
             .unwrap_or_else(|_| Local::now().format("%I:%M %p").to_string());
 
-        // Extract model from metadata if it exists
-        let model = db_msg.metadata.and_then(|metadata| {
-            serde_json::from_str::<Value>(&metadata)
-                .ok()
-                .and_then(|v| v.get("model").and_then(|m| m.as_str()).map(String::from))
-        });
+        // Model and tool details live in the metadata JSON
+        let metadata = db_msg
+            .metadata
+            .and_then(|metadata| serde_json::from_str::<MessageMetadata>(&metadata).ok())
+            .unwrap_or_default();
 
         Message {
             id: db_msg.id.to_string(),
@@ -71,23 +448,63 @@ impl From<DbMessage> for Message {
             content: db_msg.content,
             timestamp,
             reactions: Some(MessageReactions { thumbs_up: 0 }),
-            model,
+            model: metadata.model,
+            tool_calls: metadata.tool_calls,
+            tool_result: metadata.tool_result,
+            thinking: metadata.thinking,
+            // Loaded separately from the `attachments` table
+            attachments: Vec::new(),
+            interrupted: metadata.interrupted,
+            comparison: metadata.comparison,
         }
     }
 }
 
 pub struct ChatHistory(pub Arc<parking_lot::Mutex<Vec<Message>>>);
 
-fn db_error(e: sqlx::Error) -> ErrorResponse {
+/// Cancellation tokens of the `process_message` calls still running, by request id.
+#[derive(Default)]
+pub struct ActiveGenerations(parking_lot::Mutex<HashMap<String, CancellationToken>>);
+
+impl ActiveGenerations {
+    pub(crate) fn start(&self, request_id: String) -> GenerationGuard<'_> {
+        let token = CancellationToken::new();
+        self.0.lock().insert(request_id.clone(), token.clone());
+        GenerationGuard {
+            generations: self,
+            request_id,
+            token,
+        }
+    }
+}
+
+/// Unregisters a generation when `process_message` returns, however it returns.
+pub(crate) struct GenerationGuard<'a> {
+    generations: &'a ActiveGenerations,
+    request_id: String,
+    pub(crate) token: CancellationToken,
+}
+
+impl Drop for GenerationGuard<'_> {
+    fn drop(&mut self) {
+        self.generations.0.lock().remove(&self.request_id);
+    }
+}
+
+pub(crate) fn db_error(e: sqlx::Error) -> ErrorResponse {
     ErrorResponse {
         message: "Database error".to_string(),
         details: Some(e.to_string()),
+        provider_error: None,
     }
 }
 
 /// Gets the latest conversation ID or creates a new one if none exists.
 /// Caches the result in AppState.
-async fn get_or_create_conversation_cached(app_state: &AppState) -> Result<i64, ErrorResponse> {
+pub(crate) async fn get_or_create_conversation_cached(
+    app_state: &AppState,
+    config_state: &ConfigState,
+) -> Result<i64, ErrorResponse> {
     let db = &app_state.db;
 
     // First check if the cached conversation still exists
@@ -127,21 +544,7 @@ async fn get_or_create_conversation_cached(app_state: &AppState) -> Result<i64,
     }
 
     // No existing conversation found, create a new one
-    sqlx::query!(
-        r#"
-        INSERT INTO conversations (created_at, updated_at)
-        VALUES (datetime('now'), datetime('now'))
-        "#
-    )
-    .execute(db)
-    .await
-    .map_err(db_error)?;
-
-    let id: i64 = sqlx::query_scalar!("SELECT last_insert_rowid()")
-        .fetch_one(db)
-        .await
-        .map_err(db_error)?
-        .into();
+    let id = create_conversation(db, config_state).await?;
 
     {
         let mut guard = app_state.conversation_id.lock();
@@ -151,12 +554,58 @@ async fn get_or_create_conversation_cached(app_state: &AppState) -> Result<i64,
     Ok(id)
 }
 
-async fn save_message(
+pub(crate) async fn load_conversation_settings(
+    db: &SqlitePool,
+    conversation_id: i64,
+) -> Result<ConversationSettings, ErrorResponse> {
+    let settings = sqlx::query_scalar!(
+        "SELECT settings FROM conversations WHERE id = ?",
+        conversation_id
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(db_error)?
+    .ok_or_else(|| ErrorResponse {
+        message: "Conversation not found".to_string(),
+        details: Some(format!("No conversation with id {}", conversation_id)),
+        provider_error: None,
+    })?;
+
+    // Unreadable settings shouldn't make the conversation unusable
+    Ok(settings
+        .and_then(|settings| serde_json::from_str(&settings).ok())
+        .unwrap_or_default())
+}
+
+async fn save_conversation_settings(
+    db: impl sqlx::SqliteExecutor<'_>,
+    conversation_id: i64,
+    settings: &ConversationSettings,
+) -> Result<(), ErrorResponse> {
+    let settings = serde_json::to_string(settings).map_err(|e| ErrorResponse {
+        message: "Failed to serialize conversation settings".to_string(),
+        details: Some(e.to_string()),
+        provider_error: None,
+    })?;
+
+    sqlx::query!(
+        "UPDATE conversations SET settings = ? WHERE id = ?",
+        settings,
+        conversation_id
+    )
+    .execute(db)
+    .await
+    .map_err(db_error)?;
+
+    Ok(())
+}
+
+pub(crate) async fn save_message(
     tx: &mut Transaction<'_, Sqlite>,
     conversation_id: i64,
     role: &str,
     content: &str,
-    model: Option<&str>,
+    metadata: Option<&MessageMetadata>,
 ) -> Result<Message, ErrorResponse> {
     // Update conversation timestamp
     sqlx::query!(
@@ -171,8 +620,24 @@ async fn save_message(
     .await
     .map_err(db_error)?;
 
-    // Create metadata JSON if model is provided
-    let metadata = model.map(|m| format!(r#"{{"model":"{}"}}"#, m));
+    let model = metadata.and_then(|metadata| metadata.model.clone());
+    let tool_calls = metadata
+        .map(|metadata| metadata.tool_calls.clone())
+        .unwrap_or_default();
+    let tool_result = metadata.and_then(|metadata| metadata.tool_result.clone());
+    let thinking = metadata
+        .map(|metadata| metadata.thinking.clone())
+        .unwrap_or_default();
+    let interrupted = metadata.is_some_and(|metadata| metadata.interrupted);
+    let comparison = metadata.and_then(|metadata| metadata.comparison.clone());
+    let metadata = metadata
+        .map(serde_json::to_string)
+        .transpose()
+        .map_err(|e| ErrorResponse {
+            message: "Failed to serialize message metadata".to_string(),
+            details: Some(e.to_string()),
+            provider_error: None,
+        })?;
 
     // Insert the message
     sqlx::query!(
@@ -203,136 +668,539 @@ async fn save_message(
         content: content.to_string(),
         timestamp,
         reactions: Some(MessageReactions { thumbs_up: 0 }),
-        model: model.map(String::from),
+        model,
+        tool_calls,
+        tool_result,
+        thinking,
+        attachments: Vec::new(),
+        interrupted,
+        comparison,
     };
 
     Ok(msg)
 }
 
+/// Saves a message in its own short transaction, so no DB lock is held
+/// while waiting on the provider, and appends it to the in-memory history.
+pub(crate) async fn record_message(
+    db: &SqlitePool,
+    chat_history: &ChatHistory,
+    conversation_id: i64,
+    role: &str,
+    content: &str,
+    metadata: Option<&MessageMetadata>,
+) -> Result<Message, ErrorResponse> {
+    let mut tx = db.begin().await.map_err(db_error)?;
+    let message = save_message(&mut tx, conversation_id, role, content, metadata).await?;
+    tx.commit().await.map_err(db_error)?;
+
+    chat_history.0.lock().push(message.clone());
+    Ok(message)
+}
+
+/// Like `record_message` for the user's turn, also linking the files that
+/// were attached to it.
+pub(crate) async fn record_user_message(
+    db: &SqlitePool,
+    chat_history: &ChatHistory,
+    conversation_id: i64,
+    content: &str,
+    attachment_ids: &[i64],
+    attachments: Vec<Attachment>,
+) -> Result<Message, ErrorResponse> {
+    let mut tx = db.begin().await.map_err(db_error)?;
+    let mut message = save_message(&mut tx, conversation_id, "user", content, None).await?;
+    let message_id = message.id.parse::<i64>().unwrap_or_default();
+    attachments::link_to_message(&mut tx, attachment_ids, message_id).await?;
+    tx.commit().await.map_err(db_error)?;
+
+    message.attachments = attachments;
+    chat_history.0.lock().push(message.clone());
+    Ok(message)
+}
+
+// Tauri injects each managed state as its own argument
+#[allow(clippy::too_many_arguments)]
 #[tauri::command]
 pub async fn process_message(
     message: String,
     app_handle: AppHandle,
     chat_history: State<'_, ChatHistory>,
     config_state: State<'_, ConfigState>,
+    tool_registry: State<'_, ToolRegistry>,
+    active_generations: State<'_, ActiveGenerations>,
     window: tauri::Window,
+    request_id: Option<String>,
+    attachment_ids: Option<Vec<i64>>,
+    response_schema: Option<ResponseSchema>,
 ) -> Result<Response, ErrorResponse> {
     println!("Received message: {}", message);
 
+    if let Some(response_schema) = &response_schema {
+        schema::validate_schema(response_schema).map_err(|details| ErrorResponse {
+            message: "Invalid response schema".to_string(),
+            details: Some(details),
+            provider_error: None,
+        })?;
+    }
+
+    let generation =
+        active_generations.start(request_id.unwrap_or_else(|| ulid::Ulid::new().to_string()));
+
     let app_state = app_handle.state::<AppState>();
     let db = &app_state.db;
 
     // Get or create conversation only once
-    let conversation_id = get_or_create_conversation_cached(&app_state).await?;
+    let conversation_id = get_or_create_conversation_cached(&app_state, &config_state).await?;
+    let mut conversation_settings = load_conversation_settings(db, conversation_id).await?;
 
     // Extract provider configuration once
-    let (provider_type, provider_config, streaming_enabled) = {
+    let (
+        provider_type,
+        mut provider_config,
+        streaming_enabled,
+        fallbacks,
+        default_system_prompt,
+        tools_enabled,
+        retry_policy,
+        context_settings,
+        schema_retries,
+    ) = {
         let config = config_state.0.lock();
-        let provider_settings = config
-            .providers
-            .get(&config.active_provider)
-            .ok_or_else(|| ErrorResponse {
-                message: "Provider configuration error".to_string(),
-                details: Some("No provider configured".to_string()),
-            })?;
+        let (provider_type, provider_settings) = resolve_provider(&config, &conversation_settings)?;
+        let mut provider_config = provider_settings.to_provider_config();
+        provider_config.recording = config.recording.clone();
+        let fallbacks = fallback_settings(&config, &provider_type, &provider_settings.model);
 
         (
-            config.active_provider.clone(),
-            ProviderConfig {
-                api_key: provider_settings.api_key.clone(),
-                model: provider_settings.model.clone(),
-                max_tokens: provider_settings.max_tokens,
-            },
+            provider_type,
+            provider_config,
             provider_settings.streaming,
+            fallbacks,
+            config.system_prompt.clone(),
+            config.tools_enabled,
+            config.retry.clone(),
+            config.context.clone(),
+            // Every re-ask takes one of the loop's iterations
+            config.schema_retries.min(MAX_TOOL_ITERATIONS as u32 - 1),
         )
     };
 
-    // Short transaction for user message
-    {
-        let mut tx = db.begin().await.map_err(db_error)?;
-        let user_message = save_message(&mut tx, conversation_id, "user", &message, None).await?;
-        tx.commit().await.map_err(db_error)?;
+    // A structured reply is the whole answer, so tools aren't offered
+    if tools_enabled && response_schema.is_none() {
+        provider_config.tools = tool_registry.definitions();
+    }
+    provider_config.response_schema = response_schema.clone();
+
+    // For now, we do not optimize the emit calls as requested.
+    let window = Arc::new(parking_lot::Mutex::new(window));
+
+    // Text streamed so far in the current round-trip, saved if it is cancelled
+    let partial_reply = Arc::new(parking_lot::Mutex::new(String::new()));
+
+    // Let the UI show "retrying in Ns" and drop text from a failed stream
+    provider_config.retry = RetryPolicy {
+        on_retry: Some({
+            let window = Arc::clone(&window);
+            let partial_reply = Arc::clone(&partial_reply);
+            Arc::new(move |event: RetryEvent| {
+                if event.discard_partial {
+                    partial_reply.lock().clear();
+                }
+                let _ = window.lock().emit("stream-retry", event);
+            })
+        }),
+        ..retry_policy
+    };
 
+    // Let the UI show how many requests are ahead when the provider is busy
+    provider_config.rate_limits.on_queue = Some({
+        let window = Arc::clone(&window);
+        Arc::new(move |event: QueueEvent| {
+            let _ = window.lock().emit("stream-queued", event);
+        })
+    });
+
+    let system_prompt = conversation_settings
+        .system_prompt
+        .clone()
+        .or(default_system_prompt)
+        .filter(|prompt| !prompt.trim().is_empty());
+
+    // Create the providers first so invalid settings are rejected before
+    // anything is persisted. A broken fallback only loses its place in line.
+    let mut candidates = vec![
+        Candidate::new(
+            db,
+            provider_type,
+            provider_config.clone(),
+            streaming_enabled,
+            &context_settings,
+        )
+        .await?,
+    ];
+    for (fallback_type, settings) in fallbacks {
+        let fallback_config = ProviderConfig {
+            tools: provider_config.tools.clone(),
+            retry: provider_config.retry.clone(),
+            recording: provider_config.recording.clone(),
+            response_schema: provider_config.response_schema.clone(),
+            rate_limits: RateLimits {
+                on_queue: provider_config.rate_limits.on_queue.clone(),
+                ..settings.rate_limits.clone()
+            },
+            ..settings.to_provider_config()
+        };
+        match Candidate::new(
+            db,
+            fallback_type.clone(),
+            fallback_config,
+            settings.streaming,
+            &context_settings,
+        )
+        .await
         {
-            let mut history = chat_history.0.lock();
-            history.push(user_message);
+            Ok(candidate) => candidates.push(candidate),
+            Err(e) => log::warn!(
+                "Skipping fallback {}:{}: {}",
+                fallback_type,
+                settings.model,
+                e.details.unwrap_or(e.message)
+            ),
         }
     }
 
-    // Call provider outside of a transaction to avoid holding DB locks
-    let provider = ProviderFactory::create_provider(&provider_type, provider_config.clone())
-        .map_err(|e| ErrorResponse {
-            message: "Provider initialization failed".to_string(),
-            details: Some(e),
-        })?;
+    // The user message is only persisted once the provider has answered, so
+    // a failed request can be resent without leaving a duplicate behind
+    let attachment_ids = attachment_ids.unwrap_or_default();
+    let attachments = attachments::load_pending(db, &attachment_ids).await?;
 
-    let history_snapshot = {
-        // Lock once for reading
-        let history = chat_history.0.lock();
-        history.clone()
-    };
+    let mut pending_user_message = Some(message.as_str());
+    let mut history_snapshot =
+        build_prompt(&chat_history, system_prompt, &message, attachments.clone());
+    attachments::load_data(db, &mut history_snapshot).await?;
 
-    let full_response = if provider.supports_streaming() && streaming_enabled {
-        // For now, we do not optimize the emit calls as requested.
-        let window = Arc::new(parking_lot::Mutex::new(window));
-        let callback = {
+    let make_callback = |streaming: bool| {
+        streaming.then(|| {
             let window = Arc::clone(&window);
+            let partial_reply = Arc::clone(&partial_reply);
             Box::new(move |response: StreamResponse| {
+                if !response.thinking.is_empty() {
+                    let _ = window.lock().emit("stream-thinking", &response.thinking);
+                }
                 if !response.text.is_empty() {
+                    partial_reply.lock().push_str(&response.text);
                     let _ = window.lock().emit("stream-response", &response.text);
                 }
             }) as Box<dyn Fn(StreamResponse) + Send + Sync + 'static>
+        })
+    };
+
+    // Index of the candidate in use; once a fallback answers it keeps the
+    // rest of the turn
+    let mut active = 0;
+    // Replies that don't match the response schema are sent back this
+    // many times, each re-ask taking one of the iterations below
+    let mut reasks = 0;
+    let mut structured_reply = None;
+    // Keep going until the model ends its turn instead of asking for tools
+    let mut reply = String::new();
+    for iteration in 1..=MAX_TOOL_ITERATIONS {
+        if generation.token.is_cancelled() {
+            return Ok(Response {
+                reply,
+                interrupted: true,
+                structured: None,
+            });
+        }
+
+        partial_reply.lock().clear();
+
+        // Fit the conversation into the context window, then send what's
+        // left, moving down the fallback chain on transient failures
+        let mut failed_providers = Vec::new();
+        let request = async {
+            loop {
+                let candidate = &candidates[active];
+                let result = async {
+                    let fitted = candidate
+                        .fit(
+                            history_snapshot.clone(),
+                            &context_settings,
+                            conversation_settings.context_summary.as_ref(),
+                        )
+                        .await?;
+                    if let Some(report) = &fitted.report {
+                        let _ = window.lock().emit("context-trimmed", report);
+                    }
+                    let response = candidate
+                        .provider
+                        .send_message(fitted.messages, make_callback(candidate.streaming))
+                        .await?;
+                    Ok::<_, ProviderError>((response, fitted.report, fitted.summary))
+                }
+                .await;
+
+                match result {
+                    Err(error) if error.is_transient() && active + 1 < candidates.len() => {
+                        let next = &candidates[active + 1];
+                        log::warn!(
+                            "{}:{} failed, falling back to {}:{}: {}",
+                            candidate.provider_type,
+                            candidate.config.model,
+                            next.provider_type,
+                            next.config.model,
+                            error
+                        );
+                        partial_reply.lock().clear();
+                        let _ = window.lock().emit(
+                            "provider-fallback",
+                            FallbackEvent {
+                                from_provider: candidate.provider_type.clone(),
+                                from_model: candidate.config.model.clone(),
+                                to_provider: next.provider_type.clone(),
+                                to_model: next.config.model.clone(),
+                                reason: error.to_string(),
+                            },
+                        );
+                        failed_providers.push(FailedAttempt {
+                            provider: candidate.provider_type.clone(),
+                            model: candidate.config.model.clone(),
+                            error,
+                        });
+                        active += 1;
+                    }
+                    result => return result,
+                }
+            }
         };
 
-        provider
-            .send_message(history_snapshot, Some(callback))
-            .await
-            .map_err(|e| ErrorResponse {
-                message: "API request failed".to_string(),
-                details: Some(e),
-            })?
-    } else {
-        provider
-            .send_message(history_snapshot, None)
-            .await
-            .map_err(|e| ErrorResponse {
-                message: "API request failed".to_string(),
-                details: Some(e),
-            })?
-    };
+        let (response, context_report) = tokio::select! {
+            result = request => {
+                let (response, report, summary) = result?;
+                if let Some(summary) = summary {
+                    conversation_settings.context_summary = Some(summary);
+                    save_conversation_settings(db, conversation_id, &conversation_settings)
+                        .await?;
+                }
+                (response, report)
+            }
+            // Dropping the request future closes the HTTP stream right away
+            _ = generation.token.cancelled() => {
+                let partial = std::mem::take(&mut *partial_reply.lock());
+                if let Some(content) = pending_user_message.take() {
+                    record_user_message(
+                        db,
+                        &chat_history,
+                        conversation_id,
+                        content,
+                        &attachment_ids,
+                        attachments.clone(),
+                    )
+                    .await?;
+                }
+                // An empty assistant turn would be rejected on the next request
+                if !partial.is_empty() {
+                    record_message(
+                        db,
+                        &chat_history,
+                        conversation_id,
+                        "assistant",
+                        &partial,
+                        Some(&MessageMetadata {
+                            interrupted: true,
+                            failed_providers: std::mem::take(&mut failed_providers),
+                            ..candidates[active].metadata()
+                        }),
+                    )
+                    .await?;
+                }
+                return Ok(Response {
+                    reply: partial,
+                    interrupted: true,
+                    structured: None,
+                });
+            }
+        };
 
-    // Short transaction for assistant message
-    {
-        let mut tx = db.begin().await.map_err(db_error)?;
-        let assistant_message = save_message(
-            &mut tx,
+        if let Some(content) = pending_user_message.take() {
+            record_user_message(
+                db,
+                &chat_history,
+                conversation_id,
+                content,
+                &attachment_ids,
+                attachments.clone(),
+            )
+            .await?;
+        }
+
+        // Only a reply that matches the schema is kept; the others go back
+        // to the model with what was wrong, without being saved. Re-asks
+        // share the loop's iterations, so the last one can't be a re-ask.
+        let (structured, schema_error) = match &response_schema {
+            Some(response_schema) => match schema::check(&response.text, &response_schema.schema) {
+                Ok(value) => (Some(value), None),
+                Err(error) if reasks < schema_retries && iteration < MAX_TOOL_ITERATIONS => {
+                    reasks += 1;
+                    log::warn!("Reply didn't match the response schema: {}", error);
+                    let _ = window.lock().emit(
+                        "stream-retry",
+                        RetryEvent {
+                            attempt: reasks + 1,
+                            max_attempts: schema_retries + 1,
+                            delay_ms: 0,
+                            reason: format!("the reply didn't match the schema: {}", error),
+                            discard_partial: true,
+                        },
+                    );
+                    history_snapshot.push(Message {
+                        id: format!("reask-{}-reply", reasks),
+                        role: "assistant".to_string(),
+                        content: response.text,
+                        ..Default::default()
+                    });
+                    history_snapshot.push(Message {
+                        id: format!("reask-{}", reasks),
+                        role: "user".to_string(),
+                        content: schema::reask_prompt(&error),
+                        ..Default::default()
+                    });
+                    continue;
+                }
+                Err(error) => (None, Some(error)),
+            },
+            None => (None, None),
+        };
+
+        let assistant_message = record_message(
+            db,
+            &chat_history,
             conversation_id,
             "assistant",
-            &full_response,
-            Some(&provider_config.model),
+            &response.text,
+            Some(&MessageMetadata {
+                thinking: response.thinking.clone(),
+                tool_calls: response.tool_calls.clone(),
+                usage: response.usage,
+                context: context_report,
+                failed_providers,
+                structured: structured.clone(),
+                schema_error: schema_error.clone(),
+                ..candidates[active].metadata()
+            }),
         )
         .await?;
-        tx.commit().await.map_err(db_error)?;
+        history_snapshot.push(assistant_message);
+        if let Some(error) = schema_error {
+            return Err(ErrorResponse {
+                message: "Reply didn't match the schema".to_string(),
+                details: Some(format!(
+                    "{} (after {} re-ask{})",
+                    error,
+                    reasks,
+                    if reasks == 1 { "" } else { "s" }
+                )),
+                provider_error: None,
+            });
+        }
+        reply = response.text;
+        structured_reply = structured;
 
-        {
-            let mut history = chat_history.0.lock();
-            history.push(assistant_message);
+        if response.tool_calls.is_empty() || response.stop_reason.as_deref() != Some("tool_use") {
+            break;
+        }
+        // Out of iterations: the calls are answered without being run, so
+        // the conversation can still be continued, and the turn ends here
+        let out_of_iterations = iteration == MAX_TOOL_ITERATIONS;
+
+        for call in &response.tool_calls {
+            // Tool failures go back to the model so it can recover
+            let (output, is_error) = if out_of_iterations {
+                (
+                    format!(
+                        "Not run: the limit of {} tool rounds per message was reached",
+                        MAX_TOOL_ITERATIONS
+                    ),
+                    true,
+                )
+            } else {
+                match tool_registry.call(call).await {
+                    Ok(output) => (output, false),
+                    Err(e) => (e, true),
+                }
+            };
+
+            let tool_message = record_message(
+                db,
+                &chat_history,
+                conversation_id,
+                "tool",
+                &output,
+                Some(&MessageMetadata {
+                    tool_result: Some(ToolResult {
+                        tool_use_id: call.id.clone(),
+                        is_error,
+                    }),
+                    ..Default::default()
+                }),
+            )
+            .await?;
+            history_snapshot.push(tool_message);
+        }
+
+        if out_of_iterations {
+            log::warn!(
+                "Stopping after {} tool iterations without a final answer",
+                MAX_TOOL_ITERATIONS
+            );
+            return Err(ErrorResponse {
+                message: "Stopped before the model finished using tools".to_string(),
+                details: Some(format!(
+                    "It was still calling tools after {} rounds; send another message to let it continue",
+                    MAX_TOOL_ITERATIONS
+                )),
+                provider_error: None,
+            });
         }
     }
 
     Ok(Response {
-        reply: full_response,
+        reply,
+        interrupted: false,
+        structured: structured_reply,
     })
 }
 
+/// Stops a running `process_message`, saving whatever it had generated.
+/// Without a `request_id` every running generation is cancelled. Returns
+/// whether anything was cancelled.
+#[tauri::command]
+pub async fn cancel_generation(
+    request_id: Option<String>,
+    active_generations: State<'_, ActiveGenerations>,
+) -> Result<bool, ErrorResponse> {
+    let generations = active_generations.0.lock();
+    let tokens = match &request_id {
+        Some(request_id) => generations.get(request_id).into_iter().collect::<Vec<_>>(),
+        None => generations.values().collect(),
+    };
+
+    for token in &tokens {
+        token.cancel();
+    }
+    Ok(!tokens.is_empty())
+}
+
 #[tauri::command]
 pub async fn get_chat_history(
     app_handle: AppHandle,
     chat_history: State<'_, ChatHistory>,
+    config_state: State<'_, ConfigState>,
 ) -> Result<Vec<Message>, ErrorResponse> {
     let app_state = app_handle.state::<AppState>();
     let db = &app_state.db;
-    let conversation_id = get_or_create_conversation_cached(&app_state).await?;
+    let conversation_id = get_or_create_conversation_cached(&app_state, &config_state).await?;
 
     // Check in-memory first
     {
@@ -343,7 +1211,7 @@ pub async fn get_chat_history(
     }
 
     // If empty, load from DB
-    let messages = sqlx::query_as!(
+    let mut messages = sqlx::query_as!(
         DbMessage,
         r#"
             SELECT 
@@ -365,6 +1233,7 @@ pub async fn get_chat_history(
     .into_iter()
     .map(Message::from)
     .collect::<Vec<Message>>();
+    attachments::load_into_messages(db, conversation_id, &mut messages).await?;
 
     {
         let mut history = chat_history.0.lock();
@@ -378,28 +1247,14 @@ pub async fn get_chat_history(
 pub async fn clear_chat_history(
     app_handle: AppHandle,
     chat_history: State<'_, ChatHistory>,
+    config_state: State<'_, ConfigState>,
 ) -> Result<i64, ErrorResponse> {
     // Changed return type to return the new ID
     let app_state = app_handle.state::<AppState>();
     let db = &app_state.db;
 
     // Create a new conversation
-    sqlx::query!(
-        r#"
-        INSERT INTO conversations (created_at, updated_at)
-        VALUES (datetime('now'), datetime('now'))
-        "#
-    )
-    .execute(db)
-    .await
-    .map_err(db_error)?;
-
-    // Get the new conversation ID
-    let new_id: i64 = sqlx::query_scalar!("SELECT last_insert_rowid()")
-        .fetch_one(db)
-        .await
-        .map_err(db_error)?
-        .into();
+    let new_id = create_conversation(db, &config_state).await?;
 
     // Update cached conversation_id
     {
@@ -497,7 +1352,7 @@ pub async fn load_conversation_messages(
     }
 
     // Load messages for the conversation
-    let messages = sqlx::query_as!(
+    let mut messages = sqlx::query_as!(
         DbMessage,
         r#"
             SELECT 
@@ -519,6 +1374,7 @@ pub async fn load_conversation_messages(
     .into_iter()
     .map(Message::from)
     .collect::<Vec<Message>>();
+    attachments::load_into_messages(db, conversation_id, &mut messages).await?;
 
     // Update in-memory history
     {
@@ -561,3 +1417,115 @@ pub async fn delete_conversation(
 
     Ok(())
 }
+
+#[tauri::command]
+pub async fn get_system_prompt(
+    conversation_id: i64,
+    app_handle: AppHandle,
+) -> Result<Option<String>, ErrorResponse> {
+    let app_state = app_handle.state::<AppState>();
+    let settings = load_conversation_settings(&app_state.db, conversation_id).await?;
+    Ok(settings.system_prompt)
+}
+
+#[tauri::command]
+pub async fn set_system_prompt(
+    conversation_id: i64,
+    system_prompt: Option<String>,
+    app_handle: AppHandle,
+) -> Result<(), ErrorResponse> {
+    let app_state = app_handle.state::<AppState>();
+    let db = &app_state.db;
+
+    let mut settings = load_conversation_settings(db, conversation_id).await?;
+    // An empty prompt falls back to the global default
+    settings.system_prompt = system_prompt.filter(|prompt| !prompt.trim().is_empty());
+    save_conversation_settings(db, conversation_id, &settings).await
+}
+
+#[tauri::command]
+pub async fn get_conversation_model(
+    conversation_id: i64,
+    app_handle: AppHandle,
+    config_state: State<'_, ConfigState>,
+) -> Result<ConversationModel, ErrorResponse> {
+    let app_state = app_handle.state::<AppState>();
+    let settings = load_conversation_settings(&app_state.db, conversation_id).await?;
+
+    let config = config_state.0.lock();
+    let (provider, provider_settings) = resolve_provider(&config, &settings)?;
+    Ok(ConversationModel {
+        provider,
+        params: GenerationParams::from_settings(&provider_settings),
+        streaming: provider_settings.streaming,
+        model: provider_settings.model,
+    })
+}
+
+/// Switches a conversation to another provider or model. Without `params`
+/// the conversation keeps its parameters, or takes the new provider's
+/// defaults when the provider changes. A parameter left out of `params`
+/// follows the provider's setting, and a `null` one clears it.
+#[tauri::command]
+pub async fn set_conversation_model(
+    conversation_id: i64,
+    provider: String,
+    model: String,
+    params: Option<GenerationParams>,
+    app_handle: AppHandle,
+    config_state: State<'_, ConfigState>,
+) -> Result<(), ErrorResponse> {
+    let app_state = app_handle.state::<AppState>();
+    switch_conversation_model(
+        &app_state.db,
+        &config_state,
+        conversation_id,
+        provider,
+        model,
+        params,
+    )
+    .await
+}
+
+/// Does the work of `set_conversation_model`.
+pub(crate) async fn switch_conversation_model(
+    db: &SqlitePool,
+    config_state: &ConfigState,
+    conversation_id: i64,
+    provider: String,
+    model: String,
+    params: Option<GenerationParams>,
+) -> Result<(), ErrorResponse> {
+    let mut settings = load_conversation_settings(db, conversation_id).await?;
+    let provider_changed = settings.provider.as_deref() != Some(provider.as_str());
+    let provider_config = {
+        let config = config_state.0.lock();
+        settings.params = match params {
+            Some(params) => params,
+            None if provider_changed => config
+                .providers
+                .get(&provider)
+                .map(GenerationParams::from_settings)
+                .unwrap_or_default(),
+            None => settings.params.clone(),
+        };
+        settings.provider = Some(provider.clone());
+        settings.model = Some(model.clone());
+        resolve_provider(&config, &settings)?.1.to_provider_config()
+    };
+
+    // Reject parameters the provider wouldn't accept before saving them
+    ProviderFactory::create_provider(&provider, provider_config)?;
+
+    let model_id = models::ensure_registered(db, &provider, &model).await?;
+    sqlx::query!(
+        "UPDATE conversations SET model_id = ? WHERE id = ?",
+        model_id,
+        conversation_id
+    )
+    .execute(db)
+    .await
+    .map_err(db_error)?;
+
+    save_conversation_settings(db, conversation_id, &settings).await
+}
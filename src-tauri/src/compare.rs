@@ -0,0 +1,393 @@
+//! Sends one user message to several models at once and keeps every reply
+//! as a sibling answer to it. The first target, in the order requested,
+//! that answers successfully continues the conversation until another reply
+//! is picked with `select_response`.
+//!
+//! Comparisons are a single round-trip: tools aren't offered, so each reply
+//! is a plain answer that can stand in for the others.
+
+use crate::apimodels::{
+    Comparison, Message, ProviderError, QueueEvent, RetryEvent, RetryPolicy, StreamResponse,
+};
+use crate::attachments;
+use crate::chat::{
+    build_prompt, db_error, get_or_create_conversation_cached, load_conversation_settings,
+    record_message, record_user_message, switch_conversation_model, ActiveGenerations, Candidate,
+    ChatHistory, ErrorResponse, MessageMetadata,
+};
+use crate::config::ConfigState;
+use crate::AppState;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tauri::{AppHandle, Emitter, Manager, State};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompareTarget {
+    pub provider: String,
+    pub model: String,
+}
+
+/// Emitted as `compare-stream` while the replies arrive. `index` is the
+/// target's position in the request.
+#[derive(Debug, Clone, Serialize)]
+struct CompareChunk {
+    index: usize,
+    text: String,
+    thinking: String,
+    /// The target is retrying from scratch; drop what it streamed so far
+    restart: bool,
+}
+
+/// How one target fared.
+#[derive(Debug, Serialize)]
+pub struct CompareResult {
+    index: usize,
+    provider: String,
+    model: String,
+    /// The saved reply, if the target answered
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ProviderError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CompareResponse {
+    /// `None` when the comparison was cancelled, in which case nothing is saved
+    user_message: Option<Message>,
+    results: Vec<CompareResult>,
+    interrupted: bool,
+}
+
+/// Sends `message` to every target concurrently and saves each reply as a
+/// sibling answer to it. Fails, saving nothing, only if no target answers.
+// Tauri injects each managed state as its own argument
+#[allow(clippy::too_many_arguments)]
+#[tauri::command]
+pub async fn compare_models(
+    message: String,
+    targets: Vec<CompareTarget>,
+    app_handle: AppHandle,
+    chat_history: State<'_, ChatHistory>,
+    config_state: State<'_, ConfigState>,
+    active_generations: State<'_, ActiveGenerations>,
+    window: tauri::Window,
+    request_id: Option<String>,
+    attachment_ids: Option<Vec<i64>>,
+) -> Result<CompareResponse, ErrorResponse> {
+    if targets.is_empty() {
+        return Err(ErrorResponse {
+            message: "Nothing to compare".to_string(),
+            details: Some("Pick at least one model".to_string()),
+            provider_error: None,
+        });
+    }
+
+    let generation =
+        active_generations.start(request_id.unwrap_or_else(|| ulid::Ulid::new().to_string()));
+
+    let app_state = app_handle.state::<AppState>();
+    let db = &app_state.db;
+    let conversation_id = get_or_create_conversation_cached(&app_state, &config_state).await?;
+    let conversation_settings = load_conversation_settings(db, conversation_id).await?;
+
+    let window = Arc::new(parking_lot::Mutex::new(window));
+    let emit_chunk = {
+        let window = Arc::clone(&window);
+        move |chunk: CompareChunk| {
+            let _ = window.lock().emit("compare-stream", chunk);
+        }
+    };
+
+    let (targets, default_system_prompt, context_settings) = {
+        let config = config_state.0.lock();
+        let mut resolved = Vec::with_capacity(targets.len());
+        for (index, target) in targets.into_iter().enumerate() {
+            let mut settings =
+                config
+                    .providers
+                    .get(&target.provider)
+                    .cloned()
+                    .ok_or_else(|| ErrorResponse {
+                        message: "Provider configuration error".to_string(),
+                        details: Some(format!("Provider {} is not configured", target.provider)),
+                        provider_error: None,
+                    })?;
+            // Compared with the sampling the conversation itself uses
+            conversation_settings.params.apply(&mut settings);
+            let mut provider_config = settings.to_provider_config();
+            provider_config.model = target.model;
+            provider_config.recording = config.recording.clone();
+            // Each target restarts on its own, so tell the UI which one
+            provider_config.retry = RetryPolicy {
+                on_retry: Some({
+                    let emit_chunk = emit_chunk.clone();
+                    Arc::new(move |event: RetryEvent| {
+                        if event.discard_partial {
+                            emit_chunk(CompareChunk {
+                                index,
+                                text: String::new(),
+                                thinking: String::new(),
+                                restart: true,
+                            });
+                        }
+                    })
+                }),
+                ..config.retry.clone()
+            };
+            provider_config.rate_limits.on_queue = Some({
+                let window = Arc::clone(&window);
+                Arc::new(move |event: QueueEvent| {
+                    let _ = window.lock().emit("stream-queued", event);
+                })
+            });
+            resolved.push((target.provider, provider_config, settings.streaming));
+        }
+        (
+            resolved,
+            config.system_prompt.clone(),
+            config.context.clone(),
+        )
+    };
+
+    // Reject invalid settings before anything is sent or saved
+    let mut candidates = Vec::with_capacity(targets.len());
+    for (provider_type, provider_config, streaming) in targets {
+        candidates.push(
+            Candidate::new(
+                db,
+                provider_type,
+                provider_config,
+                streaming,
+                &context_settings,
+            )
+            .await?,
+        );
+    }
+
+    let system_prompt = conversation_settings
+        .system_prompt
+        .clone()
+        .or(default_system_prompt)
+        .filter(|prompt| !prompt.trim().is_empty());
+    let attachment_ids = attachment_ids.unwrap_or_default();
+    let attachments = attachments::load_pending(db, &attachment_ids).await?;
+    let mut prompt = build_prompt(&chat_history, system_prompt, &message, attachments.clone());
+    attachments::load_data(db, &mut prompt).await?;
+
+    let requests = candidates.iter().enumerate().map(|(index, candidate)| {
+        let callback = candidate.streaming.then(|| {
+            let emit_chunk = emit_chunk.clone();
+            Box::new(move |response: StreamResponse| {
+                if !response.text.is_empty() || !response.thinking.is_empty() {
+                    emit_chunk(CompareChunk {
+                        index,
+                        text: response.text,
+                        thinking: response.thinking,
+                        restart: false,
+                    });
+                }
+            }) as Box<dyn Fn(StreamResponse) + Send + Sync + 'static>
+        });
+        let prompt = prompt.clone();
+        let context_settings = &context_settings;
+        let previous = conversation_settings.context_summary.as_ref();
+        async move {
+            let fitted = candidate.fit(prompt, context_settings, previous).await?;
+            let response = candidate
+                .provider
+                .send_message(fitted.messages, callback)
+                .await?;
+            Ok::<_, ProviderError>((response, fitted.report))
+        }
+    });
+
+    let outcomes = tokio::select! {
+        outcomes = futures::future::join_all(requests) => outcomes,
+        _ = generation.token.cancelled() => {
+            return Ok(CompareResponse {
+                user_message: None,
+                results: Vec::new(),
+                interrupted: true,
+            });
+        }
+    };
+
+    if outcomes.iter().all(Result::is_err) {
+        return Err(match outcomes.into_iter().find_map(Result::err) {
+            Some(error) => error.into(),
+            // Only without targets, which were rejected above
+            None => ErrorResponse {
+                message: "Nothing to compare".to_string(),
+                details: Some("Pick at least one model".to_string()),
+                provider_error: None,
+            },
+        });
+    }
+
+    let user_message = record_user_message(
+        db,
+        &chat_history,
+        conversation_id,
+        &message,
+        &attachment_ids,
+        attachments,
+    )
+    .await?;
+
+    let mut selected = false;
+    let mut results = Vec::with_capacity(outcomes.len());
+    for (index, (candidate, outcome)) in candidates.iter().zip(outcomes).enumerate() {
+        let mut result = CompareResult {
+            index,
+            provider: candidate.provider_type.clone(),
+            model: candidate.config.model.clone(),
+            message: None,
+            error: None,
+        };
+        match outcome {
+            Ok((response, context_report)) => {
+                let metadata = MessageMetadata {
+                    thinking: response.thinking,
+                    usage: response.usage,
+                    context: context_report,
+                    comparison: Some(Comparison {
+                        user_message_id: user_message.id.clone(),
+                        selected: !selected,
+                    }),
+                    ..candidate.metadata()
+                };
+                selected = true;
+                result.message = Some(
+                    record_message(
+                        db,
+                        &chat_history,
+                        conversation_id,
+                        "assistant",
+                        &response.text,
+                        Some(&metadata),
+                    )
+                    .await?,
+                );
+            }
+            Err(error) => result.error = Some(error),
+        }
+        results.push(result);
+    }
+
+    Ok(CompareResponse {
+        user_message: Some(user_message),
+        results,
+        interrupted: false,
+    })
+}
+
+/// Makes `message_id`, one reply of a comparison, the one the conversation
+/// continues with, and switches the conversation to the model that wrote it.
+#[tauri::command]
+pub async fn select_response(
+    message_id: i64,
+    app_handle: AppHandle,
+    chat_history: State<'_, ChatHistory>,
+    config_state: State<'_, ConfigState>,
+) -> Result<(), ErrorResponse> {
+    let app_state = app_handle.state::<AppState>();
+    let db = &app_state.db;
+
+    let row = sqlx::query!(
+        "SELECT conversation_id, metadata FROM messages WHERE id = ?",
+        message_id
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(db_error)?
+    .ok_or_else(|| ErrorResponse {
+        message: "Message not found".to_string(),
+        details: Some(format!("No message with id {}", message_id)),
+        provider_error: None,
+    })?;
+    let metadata: MessageMetadata = row
+        .metadata
+        .and_then(|metadata| serde_json::from_str(&metadata).ok())
+        .unwrap_or_default();
+    let Some(comparison) = metadata.comparison.clone() else {
+        return Err(ErrorResponse {
+            message: "Not a comparison reply".to_string(),
+            details: Some(format!(
+                "Message {} has no siblings to pick from",
+                message_id
+            )),
+            provider_error: None,
+        });
+    };
+
+    let siblings = sqlx::query!(
+        r#"
+        SELECT id as "id!", metadata
+        FROM messages
+        WHERE conversation_id = ? AND role = 'assistant'
+        "#,
+        row.conversation_id
+    )
+    .fetch_all(db)
+    .await
+    .map_err(db_error)?;
+
+    let mut tx = db.begin().await.map_err(db_error)?;
+    for sibling in siblings {
+        let Some(mut sibling_metadata) = sibling
+            .metadata
+            .and_then(|metadata| serde_json::from_str::<MessageMetadata>(&metadata).ok())
+        else {
+            continue;
+        };
+        match &mut sibling_metadata.comparison {
+            Some(sibling_comparison)
+                if sibling_comparison.user_message_id == comparison.user_message_id =>
+            {
+                sibling_comparison.selected = sibling.id == message_id;
+            }
+            _ => continue,
+        }
+        let sibling_metadata =
+            serde_json::to_string(&sibling_metadata).map_err(|e| ErrorResponse {
+                message: "Failed to serialize message metadata".to_string(),
+                details: Some(e.to_string()),
+                provider_error: None,
+            })?;
+        sqlx::query!(
+            "UPDATE messages SET metadata = ? WHERE id = ?",
+            sibling_metadata,
+            sibling.id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    }
+    tx.commit().await.map_err(db_error)?;
+
+    // The in-memory history mirrors the current conversation only
+    if *app_state.conversation_id.lock() == Some(row.conversation_id) {
+        let message_id = message_id.to_string();
+        for message in chat_history.0.lock().iter_mut() {
+            if let Some(sibling_comparison) = &mut message.comparison {
+                if sibling_comparison.user_message_id == comparison.user_message_id {
+                    sibling_comparison.selected = message.id == message_id;
+                }
+            }
+        }
+    }
+
+    if let (Some(provider), Some(model)) = (metadata.provider, metadata.model) {
+        switch_conversation_model(
+            db,
+            &config_state,
+            row.conversation_id,
+            provider,
+            model,
+            None,
+        )
+        .await?;
+    }
+    Ok(())
+}
@@ -1,21 +1,211 @@
+use crate::apimodels::{
+    NetworkSettings, ProviderConfig, RateLimits, Recording, RetryPolicy, SamplingParams,
+};
+use crate::context::ContextSettings;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
 use tauri::State;
 use tauri_plugin_store::StoreExt;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct ProviderSettings {
+    /// Empty for providers that run locally without authentication (Ollama).
+    #[serde(default)]
     pub api_key: String,
     pub model: String,
     pub max_tokens: u32,
     pub streaming: bool,
+    /// Overrides the provider's default API endpoint, e.g. a local
+    /// llama.cpp or vLLM server for OpenAI-compatible providers.
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// Context window size requested from local runtimes (Ollama `num_ctx`).
+    #[serde(default)]
+    pub num_ctx: Option<u32>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub top_k: Option<u32>,
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
+    /// Overrides the provider's default API version header (`anthropic-version`).
+    #[serde(default)]
+    pub api_version: Option<String>,
+    /// Sent with every request, e.g. `anthropic-beta` flags or gateway credentials.
+    #[serde(default)]
+    pub extra_headers: HashMap<String, String>,
+    /// Enables Anthropic extended thinking with this many `budget_tokens`.
+    #[serde(default)]
+    pub thinking_budget_tokens: Option<u32>,
+    /// Cache the system prompt and conversation prefix between turns (Anthropic).
+    #[serde(default)]
+    pub prompt_caching: bool,
+    /// JSON file of scripted responses for the `mock` provider.
+    #[serde(default)]
+    pub fixture: Option<String>,
+    /// Requests and tokens per minute and concurrent requests allowed for
+    /// this API key; requests beyond them wait in a queue.
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+    /// Proxy, extra root certificates and timeouts for reaching the API.
+    #[serde(default)]
+    pub network: NetworkSettings,
+}
+
+impl ProviderSettings {
+    pub fn to_provider_config(&self) -> ProviderConfig {
+        ProviderConfig {
+            api_key: self.api_key.clone(),
+            model: self.model.clone(),
+            max_tokens: self.max_tokens,
+            base_url: self.base_url.clone(),
+            num_ctx: self.num_ctx,
+            sampling: SamplingParams {
+                temperature: self.temperature,
+                top_p: self.top_p,
+                top_k: self.top_k,
+                stop_sequences: self.stop_sequences.clone(),
+            },
+            api_version: self.api_version.clone(),
+            extra_headers: self.extra_headers.clone(),
+            tools: Vec::new(),
+            retry: RetryPolicy::default(),
+            thinking_budget: self.thinking_budget_tokens,
+            prompt_caching: self.prompt_caching,
+            fixture: self.fixture.clone(),
+            recording: None,
+            response_schema: None,
+            rate_limits: self.rate_limits.clone(),
+            network: self.network.clone(),
+        }
+    }
+}
+
+/// Generation parameters a conversation keeps for itself. `None` (a missing
+/// field) uses the provider's setting. The parameters a provider may leave
+/// unset can also be `Some(None)` (`null`), which clears the provider's
+/// setting so the API's default applies.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct GenerationParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(
+        default,
+        deserialize_with = "nullable",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub temperature: Option<Option<f32>>,
+    #[serde(
+        default,
+        deserialize_with = "nullable",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub top_p: Option<Option<f32>>,
+    #[serde(
+        default,
+        deserialize_with = "nullable",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub top_k: Option<Option<u32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>,
+    #[serde(
+        default,
+        deserialize_with = "nullable",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub thinking_budget_tokens: Option<Option<u32>>,
+}
+
+/// Reads a present field, `null` included, as `Some`; `default` covers a
+/// missing one.
+fn nullable<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+impl GenerationParams {
+    /// Snapshots a provider's current parameters.
+    pub fn from_settings(settings: &ProviderSettings) -> Self {
+        Self {
+            max_tokens: Some(settings.max_tokens),
+            temperature: Some(settings.temperature),
+            top_p: Some(settings.top_p),
+            top_k: Some(settings.top_k),
+            stop_sequences: Some(settings.stop_sequences.clone()),
+            thinking_budget_tokens: Some(settings.thinking_budget_tokens),
+        }
+    }
+
+    pub fn apply(&self, settings: &mut ProviderSettings) {
+        if let Some(max_tokens) = self.max_tokens {
+            settings.max_tokens = max_tokens;
+        }
+        if let Some(temperature) = self.temperature {
+            settings.temperature = temperature;
+        }
+        if let Some(top_p) = self.top_p {
+            settings.top_p = top_p;
+        }
+        if let Some(top_k) = self.top_k {
+            settings.top_k = top_k;
+        }
+        if let Some(stop_sequences) = &self.stop_sequences {
+            settings.stop_sequences = stop_sequences.clone();
+        }
+        if let Some(thinking_budget_tokens) = self.thinking_budget_tokens {
+            settings.thinking_budget_tokens = thinking_budget_tokens;
+        }
+    }
+}
+
+/// A provider and model to try when the ones before it in the chain fail.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FallbackEntry {
+    pub provider: String,
+    pub model: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AppConfig {
     pub active_provider: String,
     pub providers: HashMap<String, ProviderSettings>,
+    /// Default system prompt for conversations that don't set their own.
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    /// Offer the tools in `tools::ToolRegistry` to the model.
+    #[serde(default)]
+    pub tools_enabled: bool,
+    /// How rate-limited, overloaded or dropped requests are retried.
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    /// Tried in order after the conversation's own provider, each once the
+    /// one before has failed with a transient error (overloaded, rate
+    /// limited, unreachable) despite retries. Entries use their provider's
+    /// settings with the given model.
+    #[serde(default)]
+    pub fallback_chain: Vec<FallbackEntry>,
+    /// How conversations that outgrow the context window are shortened.
+    #[serde(default)]
+    pub context: ContextSettings,
+    /// Saves provider exchanges to disk, or answers from saved ones, so the
+    /// chat flow can be tested offline.
+    #[serde(default)]
+    pub recording: Option<Recording>,
+    /// How many times a reply that doesn't match the requested JSON schema
+    /// is sent back to the model to be fixed, up to 9.
+    #[serde(default = "default_schema_retries")]
+    pub schema_retries: u32,
+}
+
+fn default_schema_retries() -> u32 {
+    2
 }
 
 impl Default for AppConfig {
@@ -24,16 +214,62 @@ impl Default for AppConfig {
         providers.insert(
             "anthropic".to_string(),
             ProviderSettings {
-                api_key: String::new(),
                 model: "claude-3-5-sonnet-20240620".to_string(),
                 max_tokens: 1024,
                 streaming: true,
+                ..Default::default()
+            },
+        );
+        providers.insert(
+            "openai".to_string(),
+            ProviderSettings {
+                model: "gpt-4-turbo-preview".to_string(),
+                max_tokens: 1024,
+                streaming: true,
+                ..Default::default()
+            },
+        );
+        providers.insert(
+            "openrouter".to_string(),
+            ProviderSettings {
+                model: "anthropic/claude-3-opus".to_string(),
+                max_tokens: 1024,
+                streaming: true,
+                base_url: Some("https://openrouter.ai/api/v1".to_string()),
+                ..Default::default()
+            },
+        );
+        providers.insert(
+            "gemini".to_string(),
+            ProviderSettings {
+                model: "gemini-2.5-pro".to_string(),
+                max_tokens: 1024,
+                streaming: true,
+                ..Default::default()
+            },
+        );
+        providers.insert(
+            "ollama".to_string(),
+            ProviderSettings {
+                model: "llama3.1".to_string(),
+                max_tokens: 1024,
+                streaming: true,
+                base_url: Some("http://localhost:11434".to_string()),
+                num_ctx: Some(8192),
+                ..Default::default()
             },
         );
 
         Self {
             active_provider: "anthropic".to_string(),
             providers,
+            system_prompt: None,
+            tools_enabled: false,
+            retry: RetryPolicy::default(),
+            fallback_chain: Vec::new(),
+            context: ContextSettings::default(),
+            recording: None,
+            schema_retries: default_schema_retries(),
         }
     }
 }
@@ -56,7 +292,11 @@ pub async fn get_config(
             // Update in-memory config with stored values
             match serde_json::from_value(stored_config.clone()) {
                 Ok(config_value) => {
-                    let config_value: AppConfig = config_value;
+                    let mut config_value: AppConfig = config_value;
+                    // Providers added since the config was saved start from their defaults
+                    for (name, settings) in AppConfig::default().providers {
+                        config_value.providers.entry(name).or_insert(settings);
+                    }
                     *config.0.lock() = config_value.clone();
                     Ok(config_value)
                 }
@@ -143,3 +383,33 @@ pub async fn set_active_provider(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn params_inherit_override_or_clear_the_provider_settings() {
+        let params: GenerationParams =
+            serde_json::from_value(json!({ "temperature": 0.2, "top_p": null })).unwrap();
+        assert_eq!(params.temperature, Some(Some(0.2)));
+        assert_eq!(params.top_p, Some(None));
+        assert_eq!(params.top_k, None);
+        // Stored the same way
+        assert_eq!(
+            serde_json::to_value(&params).unwrap(),
+            json!({ "temperature": 0.2f32, "top_p": null })
+        );
+
+        let mut settings = ProviderSettings {
+            temperature: Some(1.0),
+            top_p: Some(0.9),
+            top_k: Some(40),
+            ..Default::default()
+        };
+        params.apply(&mut settings);
+        assert_eq!(settings.temperature, Some(0.2));
+        assert_eq!(settings.top_p, None);
+        assert_eq!(settings.top_k, Some(40));
+    }
+}
@@ -0,0 +1,596 @@
+//! Keeps long conversations within the model's context window.
+//!
+//! Token counts are estimated (about four characters per token) rather than
+//! measured, so the budget leaves room for the reply. Messages are dropped
+//! a whole turn at a time (a user message and everything up to the next
+//! one), which keeps tool calls next to their results and every prompt
+//! starting with a user message.
+
+use crate::apimodels::{AttachmentKind, ChatProvider, Message, ProviderError, ThinkingBlock};
+use serde::{Deserialize, Serialize};
+
+const CHARS_PER_TOKEN: usize = 4;
+/// Role markers and separators the API adds around each message
+const MESSAGE_OVERHEAD_TOKENS: u32 = 4;
+/// Roughly what a typical screenshot or photo costs
+const IMAGE_TOKENS: u32 = 1_600;
+/// Room kept free for the summary of dropped turns
+const SUMMARY_RESERVE_TOKENS: u32 = 1_024;
+/// Shortest transcript chunk sent for summarizing, however small the budget
+const MIN_SUMMARY_CHUNK_CHARS: usize = 4_096;
+
+const SUMMARY_INSTRUCTIONS: &str =
+    "Summarize the conversation below so the summary can stand in for it. \
+     Keep facts, decisions, names, numbers and open questions; leave out pleasantries. \
+     Reply with the summary only.\n\n";
+
+/// Known context windows, matched by model name prefix like the price table.
+const CONTEXT_WINDOWS: &[(&str, u32)] = &[
+    ("claude", 200_000),
+    ("gpt-4.1", 1_047_576),
+    ("gpt-4o", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4-0125-preview", 128_000),
+    ("gpt-4", 8_192),
+    ("gpt-3.5-turbo", 16_385),
+    ("o1", 200_000),
+    ("o3", 200_000),
+    ("o4-mini", 200_000),
+    ("gemini-1.5-pro", 2_097_152),
+    ("gemini-1.5-flash", 1_048_576),
+    ("gemini-2", 1_048_576),
+];
+
+/// What to do when a conversation no longer fits.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum ContextStrategy {
+    /// Drop the oldest turns until the rest fits
+    #[default]
+    DropOldest,
+    /// Keep the first `keep_first` turns (which often set up the task) and
+    /// the last `keep_last`, dropping the ones in between
+    KeepFirstLast { keep_first: usize, keep_last: usize },
+    /// Replace everything before the last `keep_last` turns with a summary
+    /// written by the model
+    Summarize { keep_last: usize },
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ContextSettings {
+    #[serde(flatten)]
+    pub strategy: ContextStrategy,
+    /// Overrides the prompt budget worked out from the model's context
+    /// window; needed for models we don't know the window of
+    #[serde(default)]
+    pub max_input_tokens: Option<u32>,
+}
+
+/// Summary of the turns before (and including) `through_message_id`,
+/// kept in the conversation settings so it's only extended, not rewritten,
+/// as the conversation grows.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContextSummary {
+    pub through_message_id: String,
+    pub text: String,
+}
+
+/// What was left out of a prompt, stored with the reply it produced.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContextReport {
+    #[serde(flatten)]
+    pub strategy: ContextStrategy,
+    pub budget_tokens: u32,
+    /// Estimated size of the conversation before and after fitting
+    pub original_tokens: u32,
+    pub sent_tokens: u32,
+    pub dropped_message_ids: Vec<String>,
+    /// Sent in place of the dropped messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+}
+
+/// The prompt to send, and what was done to get it within budget.
+pub struct FittedPrompt {
+    pub messages: Vec<Message>,
+    /// `None` when everything fit
+    pub report: Option<ContextReport>,
+    /// Set when a new or extended summary was written
+    pub summary: Option<ContextSummary>,
+}
+
+pub fn estimate_tokens(message: &Message) -> u32 {
+    let mut chars = message.content.len();
+    for block in &message.thinking {
+        chars += match block {
+            ThinkingBlock::Thinking { thinking, .. } => thinking.len(),
+            ThinkingBlock::RedactedThinking { data } => data.len(),
+        };
+    }
+    for call in &message.tool_calls {
+        chars += call.name.len() + call.input.to_string().len();
+    }
+
+    let mut tokens = (chars / CHARS_PER_TOKEN) as u32 + MESSAGE_OVERHEAD_TOKENS;
+    for attachment in &message.attachments {
+        tokens += match attachment.kind {
+            AttachmentKind::Image => IMAGE_TOKENS,
+            // Base64 is 4/3 the size of the file; PDFs come out overestimated
+            AttachmentKind::Document => (attachment.data.len() * 3 / 4 / CHARS_PER_TOKEN) as u32,
+        };
+    }
+    tokens
+}
+
+fn estimate_all(messages: &[Message]) -> u32 {
+    messages.iter().map(estimate_tokens).sum()
+}
+
+fn estimate_all_turns(turns: &[Vec<Message>]) -> u32 {
+    turns.iter().map(|turn| estimate_all(turn)).sum()
+}
+
+/// How many tokens the prompt may use: the context window less the room
+/// reserved for the reply. `registered_window` is what the models registry
+/// recorded. `None` when the window isn't known.
+pub fn prompt_budget(
+    settings: &ContextSettings,
+    model: &str,
+    registered_window: Option<u32>,
+    num_ctx: Option<u32>,
+    max_tokens: u32,
+) -> Option<u32> {
+    if let Some(max_input_tokens) = settings.max_input_tokens {
+        return Some(max_input_tokens);
+    }
+    // Local runtimes truncate to `num_ctx`, whatever the model supports
+    let window = num_ctx
+        .or(registered_window)
+        .or_else(|| context_window(model))?;
+    Some(window.saturating_sub(max_tokens))
+}
+
+/// Looks up the context window of `model`, preferring the longest
+/// matching prefix.
+pub fn context_window(model: &str) -> Option<u32> {
+    // OpenRouter names models `vendor/model`
+    let model = model.rsplit('/').next().unwrap_or(model);
+    CONTEXT_WINDOWS
+        .iter()
+        .filter(|(prefix, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, window)| *window)
+}
+
+/// Splits a prompt into the system message (if any) and its turns.
+fn split_turns(messages: Vec<Message>) -> (Option<Message>, Vec<Vec<Message>>) {
+    let mut system = None;
+    let mut turns: Vec<Vec<Message>> = Vec::new();
+    for message in messages {
+        match message.role.as_str() {
+            "system" => system = Some(message),
+            "user" if message.tool_result.is_none() => turns.push(vec![message]),
+            _ => match turns.last_mut() {
+                Some(turn) => turn.push(message),
+                None => turns.push(vec![message]),
+            },
+        }
+    }
+    (system, turns)
+}
+
+/// Index of the oldest turn from which the rest of `turns` fits in
+/// `budget`. The latest turn is always kept, even if it alone is too big.
+fn fit_tail(turns: &[Vec<Message>], budget: u32) -> usize {
+    let mut used = 0;
+    for (index, turn) in turns.iter().enumerate().rev() {
+        used += estimate_all(turn);
+        if used > budget && index + 1 < turns.len() {
+            return index + 1;
+        }
+    }
+    0
+}
+
+fn message_ids(turns: &[Vec<Message>]) -> Vec<String> {
+    turns
+        .iter()
+        .flatten()
+        .map(|message| message.id.clone())
+        .filter(|id| !id.is_empty())
+        .collect()
+}
+
+/// Fits `messages` into `budget` tokens with `strategy`. Summarizing asks
+/// `summarizer`, which shouldn't offer tools, to summarize the dropped
+/// turns, extending `previous` when it covers the start of them.
+pub async fn fit(
+    messages: Vec<Message>,
+    strategy: &ContextStrategy,
+    budget: u32,
+    summarizer: &dyn ChatProvider,
+    previous: Option<&ContextSummary>,
+) -> Result<FittedPrompt, ProviderError> {
+    let original_tokens = estimate_all(&messages);
+    if original_tokens <= budget {
+        return Ok(FittedPrompt {
+            messages,
+            report: None,
+            summary: None,
+        });
+    }
+
+    let (mut system, turns) = split_turns(messages);
+    let available = budget.saturating_sub(system.as_ref().map_or(0, estimate_tokens));
+
+    let (kept, dropped, summary) = match strategy {
+        ContextStrategy::DropOldest => {
+            let start = fit_tail(&turns, available);
+            let mut turns = turns;
+            let kept = turns.split_off(start);
+            (kept, turns, None)
+        }
+        ContextStrategy::KeepFirstLast {
+            keep_first,
+            keep_last,
+        } => {
+            let last_start = turns.len().saturating_sub((*keep_last).max(1));
+            let first_end = (*keep_first).min(last_start);
+            // The latest turns take priority over the first ones
+            let tail_start = last_start + fit_tail(&turns[last_start..], available);
+            let tail_tokens = estimate_all_turns(&turns[tail_start..]);
+            let mut head_end = first_end;
+            while head_end > 0 && estimate_all_turns(&turns[..head_end]) + tail_tokens > available {
+                head_end -= 1;
+            }
+
+            let mut kept = Vec::new();
+            let mut dropped = Vec::new();
+            for (index, turn) in turns.into_iter().enumerate() {
+                if index < head_end || index >= tail_start {
+                    kept.push(turn);
+                } else {
+                    dropped.push(turn);
+                }
+            }
+            (kept, dropped, None)
+        }
+        ContextStrategy::Summarize { keep_last } => {
+            let last_start = turns.len().saturating_sub((*keep_last).max(1));
+            let start = last_start
+                + fit_tail(
+                    &turns[last_start..],
+                    available.saturating_sub(SUMMARY_RESERVE_TOKENS),
+                );
+            let mut turns = turns;
+            let kept = turns.split_off(start);
+            let summary = summarize(&turns, summarizer, budget, previous).await?;
+            (kept, turns, summary)
+        }
+    };
+
+    if let Some(summary) = &summary {
+        let note = format!("Summary of the earlier conversation:\n{}", summary.text);
+        match &mut system {
+            Some(system) => system.content = format!("{}\n\n{}", system.content, note),
+            None => {
+                system = Some(Message {
+                    id: "system".to_string(),
+                    role: "system".to_string(),
+                    content: note,
+                    ..Default::default()
+                })
+            }
+        }
+    }
+
+    let messages: Vec<Message> = system
+        .into_iter()
+        .chain(kept.into_iter().flatten())
+        .collect();
+    let report = ContextReport {
+        strategy: strategy.clone(),
+        budget_tokens: budget,
+        original_tokens,
+        sent_tokens: estimate_all(&messages),
+        dropped_message_ids: message_ids(&dropped),
+        summary: summary.as_ref().map(|summary| summary.text.clone()),
+    };
+    Ok(FittedPrompt {
+        messages,
+        report: Some(report),
+        summary: summary.filter(|summary| Some(summary) != previous),
+    })
+}
+
+/// Summarizes `turns`. When `previous` ends partway through them only the
+/// turns after it are sent, along with the previous summary. Each request
+/// stays within `budget`: a long transcript is summarized a chunk at a
+/// time, each chunk extending the summary of the ones before, and a single
+/// message too long for a chunk is cut short.
+async fn summarize(
+    turns: &[Vec<Message>],
+    summarizer: &dyn ChatProvider,
+    budget: u32,
+    previous: Option<&ContextSummary>,
+) -> Result<Option<ContextSummary>, ProviderError> {
+    // Worked out up front so no borrow of `turns` is held across the
+    // requests, which would keep the future from being `Send`
+    let (through_message_id, mut text, entries) = {
+        let messages: Vec<&Message> = turns.iter().flatten().collect();
+        let Some(through_message_id) = messages
+            .iter()
+            .rev()
+            .map(|message| message.id.clone())
+            .find(|id| !id.is_empty())
+        else {
+            return Ok(None);
+        };
+
+        let covered = previous.and_then(|previous| {
+            messages
+                .iter()
+                .position(|message| message.id == previous.through_message_id)
+                .map(|index| (previous, index))
+        });
+        let (text, new_messages) = match covered {
+            Some((previous, index)) if index + 1 == messages.len() => {
+                return Ok(Some(previous.clone()));
+            }
+            Some((previous, index)) => (Some(previous.text.clone()), &messages[index + 1..]),
+            None => (None, &messages[..]),
+        };
+        let entries: Vec<String> = new_messages
+            .iter()
+            .map(|message| transcript_entry(message))
+            .collect();
+        (through_message_id, text, entries)
+    };
+
+    let limit = budget as usize * CHARS_PER_TOKEN;
+    let mut entries = entries.into_iter().peekable();
+    while entries.peek().is_some() {
+        let mut prompt = String::from(SUMMARY_INSTRUCTIONS);
+        if let Some(text) = &text {
+            prompt.push_str(&format!("Summary so far:\n{}\n\nContinued:\n", text));
+        }
+        let room = limit
+            .saturating_sub(prompt.len())
+            .max(MIN_SUMMARY_CHUNK_CHARS);
+        let mut chunk = String::new();
+        // Always take at least one entry, so every request makes progress
+        while let Some(entry) =
+            entries.next_if(|entry| chunk.is_empty() || chunk.len() + entry.len() <= room)
+        {
+            chunk.push_str(truncate(&entry, room));
+        }
+        prompt.push_str(&chunk);
+
+        let response = summarizer
+            .send_message(
+                vec![Message {
+                    role: "user".to_string(),
+                    content: prompt,
+                    ..Default::default()
+                }],
+                None,
+            )
+            .await?;
+        text = Some(response.text.trim().to_string());
+    }
+
+    Ok(text.map(|text| ContextSummary {
+        through_message_id,
+        text,
+    }))
+}
+
+/// How `message` appears in the transcript sent for summarizing.
+fn transcript_entry(message: &Message) -> String {
+    let mut entry = format!("{}: {}\n", message.role, message.content);
+    for attachment in &message.attachments {
+        entry.push_str(&format!("[attached {}]\n", attachment.filename));
+    }
+    for call in &message.tool_calls {
+        entry.push_str(&format!(
+            "[called tool {} with {}]\n",
+            call.name, call.input
+        ));
+    }
+    entry
+}
+
+/// The longest prefix of `text` of at most `max` bytes.
+fn truncate(text: &str, max: usize) -> &str {
+    let mut end = max.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apimodels::provider::{ProviderResponse, StreamCallback};
+    use crate::apimodels::{ModelInfo, ToolResult};
+    use async_trait::async_trait;
+    use parking_lot::Mutex;
+
+    /// Answers each summary request with "summary N", keeping the prompts.
+    #[derive(Default)]
+    struct Summarizer {
+        prompts: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl ChatProvider for Summarizer {
+        fn supports_streaming(&self) -> bool {
+            false
+        }
+
+        async fn send_message_streaming(
+            &self,
+            messages: Vec<Message>,
+            _callback: StreamCallback,
+        ) -> Result<ProviderResponse, ProviderError> {
+            self.send_message_blocking(messages).await
+        }
+
+        async fn send_message_blocking(
+            &self,
+            messages: Vec<Message>,
+        ) -> Result<ProviderResponse, ProviderError> {
+            let mut prompts = self.prompts.lock();
+            prompts.push(messages[0].content.clone());
+            Ok(ProviderResponse {
+                text: format!("summary {}", prompts.len()),
+                ..Default::default()
+            })
+        }
+
+        async fn list_models(&self) -> Result<Vec<ModelInfo>, ProviderError> {
+            Ok(Vec::new())
+        }
+    }
+
+    fn message(id: &str, role: &str, content: &str) -> Message {
+        Message {
+            id: id.to_string(),
+            role: role.to_string(),
+            content: content.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// A one-message turn estimated at `tokens`.
+    fn turn(id: &str, tokens: u32) -> Vec<Message> {
+        let content = "x".repeat((tokens - MESSAGE_OVERHEAD_TOKENS) as usize * CHARS_PER_TOKEN);
+        vec![message(id, "user", &content)]
+    }
+
+    fn ids(messages: &[Message]) -> Vec<&str> {
+        messages.iter().map(|message| message.id.as_str()).collect()
+    }
+
+    #[test]
+    fn turns_start_at_user_messages() {
+        let tool_result = Message {
+            tool_result: Some(ToolResult {
+                tool_use_id: "call_0".to_string(),
+                is_error: false,
+            }),
+            ..message("3", "user", "42")
+        };
+        let (system, turns) = split_turns(vec![
+            message("0", "system", "Be brief"),
+            message("1", "user", "What is 6 times 7?"),
+            message("2", "assistant", ""),
+            tool_result,
+            message("4", "assistant", "42"),
+            message("5", "user", "Thanks"),
+        ]);
+        assert_eq!(system.map(|system| system.id).as_deref(), Some("0"));
+        let turns: Vec<Vec<&str>> = turns.iter().map(|turn| ids(turn)).collect();
+        assert_eq!(turns, vec![vec!["1", "2", "3", "4"], vec!["5"]]);
+    }
+
+    #[test]
+    fn fit_tail_keeps_what_fits_and_always_the_latest_turn() {
+        let turns = vec![turn("0", 10), turn("1", 10), turn("2", 10)];
+        assert_eq!(fit_tail(&turns, 100), 0);
+        assert_eq!(fit_tail(&turns, 30), 0);
+        assert_eq!(fit_tail(&turns, 25), 1);
+        assert_eq!(fit_tail(&turns, 5), 2);
+    }
+
+    #[tokio::test]
+    async fn keep_first_last_drops_first_turns_before_last_ones() {
+        let messages: Vec<Message> = (0..5).flat_map(|i| turn(&i.to_string(), 10)).collect();
+        let strategy = ContextStrategy::KeepFirstLast {
+            keep_first: 2,
+            keep_last: 2,
+        };
+        // Both last turns and one of the first two fit
+        let fitted = fit(messages, &strategy, 35, &Summarizer::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(ids(&fitted.messages), vec!["0", "3", "4"]);
+        let report = fitted.report.unwrap();
+        assert_eq!(report.original_tokens, 50);
+        assert_eq!(report.sent_tokens, 30);
+        assert_eq!(report.dropped_message_ids, vec!["1", "2"]);
+    }
+
+    #[tokio::test]
+    async fn long_transcripts_are_summarized_in_chunks_within_budget() {
+        let messages = vec![
+            // Too long for one request on its own
+            message("0", "user", &"a".repeat(20_000)),
+            message("1", "user", &"b".repeat(3_000)),
+            message("2", "user", &"c".repeat(3_000)),
+            message("3", "user", "Latest"),
+        ];
+        let summarizer = Summarizer::default();
+        let budget = 2_000;
+        let fitted = fit(
+            messages,
+            &ContextStrategy::Summarize { keep_last: 1 },
+            budget,
+            &summarizer,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let prompts = summarizer.prompts.lock();
+        assert_eq!(prompts.len(), 2);
+        for prompt in prompts.iter() {
+            assert!(prompt.len() <= budget as usize * CHARS_PER_TOKEN);
+        }
+        assert!(prompts[1].contains("Summary so far:\nsummary 1"));
+        assert!(prompts[1].contains("bbb") && prompts[1].contains("ccc"));
+
+        let summary = fitted.summary.unwrap();
+        assert_eq!(summary.through_message_id, "2");
+        assert_eq!(summary.text, "summary 2");
+        assert_eq!(ids(&fitted.messages), vec!["system", "3"]);
+    }
+
+    #[test]
+    fn prompt_budget_leaves_room_for_the_reply() {
+        let settings = ContextSettings::default();
+        assert_eq!(
+            prompt_budget(&settings, "claude-sonnet-4-5", None, None, 8_000),
+            Some(192_000)
+        );
+        // OpenRouter names carry a vendor prefix
+        assert_eq!(
+            prompt_budget(&settings, "openai/gpt-4o", None, None, 8_000),
+            Some(120_000)
+        );
+        // The registry beats the table, and a local num_ctx beats both
+        assert_eq!(
+            prompt_budget(&settings, "gpt-4o", Some(64_000), None, 4_000),
+            Some(60_000)
+        );
+        assert_eq!(
+            prompt_budget(&settings, "gpt-4o", Some(64_000), Some(8_192), 4_000),
+            Some(4_192)
+        );
+        assert_eq!(
+            prompt_budget(&settings, "llama3", None, Some(2_048), 4_000),
+            Some(0)
+        );
+        assert_eq!(prompt_budget(&settings, "llama3", None, None, 4_000), None);
+
+        let settings = ContextSettings {
+            max_input_tokens: Some(10_000),
+            ..Default::default()
+        };
+        assert_eq!(
+            prompt_budget(&settings, "llama3", None, None, 4_000),
+            Some(10_000)
+        );
+    }
+}
@@ -15,6 +15,14 @@ mod config;
 mod daemon;
 mod routes;
 mod jupyter;
+mod attachments;
+mod tools;
+mod usage;
+mod context;
+mod models;
+mod compare;
+mod batches;
+mod schema;
 
 use tauri::State;
 use crate::jupyter::{JupyterClient, JupyterClientMessage};
@@ -128,11 +136,28 @@ pub fn run() {
         .plugin(tauri_plugin_store::Builder::default().build())
         .invoke_handler(tauri::generate_handler![
             chat::process_message,
+            chat::cancel_generation,
+            attachments::attach_file,
+            attachments::attach_file_data,
+            attachments::remove_attachment,
             chat::get_chat_history,
             chat::clear_chat_history,
             chat::get_conversations,
             chat::load_conversation_messages,
             chat::delete_conversation,
+            chat::get_system_prompt,
+            chat::set_system_prompt,
+            chat::get_conversation_model,
+            chat::set_conversation_model,
+            usage::get_usage_summary,
+            models::list_models,
+            compare::compare_models,
+            compare::select_response,
+            batches::submit_batch,
+            batches::list_batches,
+            batches::poll_batch,
+            batches::cancel_batch,
+            batches::import_batch_results,
             config::get_config,
             config::update_config,
             config::update_provider_settings,
@@ -160,6 +185,8 @@ pub fn run() {
             app.manage(config::ConfigState(parking_lot::Mutex::new(
                 config::AppConfig::default(),
             )));
+            app.manage(tools::ToolRegistry::default());
+            app.manage(chat::ActiveGenerations::default());
             app.manage(AppState {
                 db,
                 conversation_id: parking_lot::Mutex::new(None),
@@ -0,0 +1,339 @@
+//! Registry of the models each provider offers, cached in the `models`
+//! table. Rows are keyed `provider:model`, with the provider as `parent`
+//! and what we know about the model as JSON in `args`.
+
+use crate::apimodels::{ModelInfo, ProviderFactory};
+use crate::chat::{db_error, ErrorResponse};
+use crate::config::ConfigState;
+use crate::context;
+use crate::usage::{pricing_for, ModelPricing};
+use crate::AppState;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tauri::{AppHandle, Manager, State};
+
+/// Cached lists older than this are refreshed on the next request.
+const CACHE_TTL_HOURS: i64 = 24;
+
+/// Shown when a provider can't be reached and nothing is cached yet.
+const BUNDLED_MODELS: &[(&str, &[&str])] = &[
+    (
+        "anthropic",
+        &[
+            "claude-sonnet-4-20250514",
+            "claude-opus-4-20250514",
+            "claude-3-7-sonnet-20250219",
+            "claude-3-5-sonnet-20241022",
+            "claude-3-5-sonnet-20240620",
+            "claude-3-5-haiku-20241022",
+            "claude-3-opus-20240229",
+            "claude-3-haiku-20240307",
+        ],
+    ),
+    (
+        "openai",
+        &[
+            "gpt-4o",
+            "gpt-4o-mini",
+            "gpt-4-turbo",
+            "gpt-4-turbo-preview",
+            "gpt-4",
+            "gpt-3.5-turbo",
+        ],
+    ),
+    (
+        "openrouter",
+        &[
+            "anthropic/claude-3.5-sonnet",
+            "anthropic/claude-3-opus",
+            "openai/gpt-4o",
+            "google/gemini-pro",
+            "meta-llama/llama-3-70b-instruct",
+        ],
+    ),
+    (
+        "gemini",
+        &[
+            "gemini-2.5-pro",
+            "gemini-2.5-flash",
+            "gemini-2.0-flash",
+            "gemini-1.5-pro",
+            "gemini-1.5-flash",
+        ],
+    ),
+    ("ollama", &["llama3.1", "mistral", "qwen2.5", "gemma2"]),
+];
+
+/// JSON stored in `models.args`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModelArgs {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_window: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pricing: Option<ModelPricing>,
+    /// When the provider last listed this model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fetched_at: Option<DateTime<Utc>>,
+}
+
+impl ModelArgs {
+    /// Fills in what the provider didn't tell us from the bundled tables.
+    fn from_info(info: &ModelInfo, fetched_at: DateTime<Utc>) -> Self {
+        let bundled = pricing_for(&info.id);
+        let pricing = match (info.input_price, info.output_price) {
+            // Only input and output prices are published, so cache rates
+            // come from the bundled table, or are charged as plain input
+            // for models it doesn't know
+            (Some(input), Some(output)) => Some(ModelPricing {
+                input,
+                output,
+                cache_write: bundled.map_or(input, |pricing| pricing.cache_write),
+                cache_read: bundled.map_or(input, |pricing| pricing.cache_read),
+            }),
+            _ => bundled,
+        };
+        Self {
+            display_name: info.display_name.clone(),
+            context_window: info
+                .context_window
+                .or_else(|| context::context_window(&info.id)),
+            pricing,
+            fetched_at: Some(fetched_at),
+        }
+    }
+
+    fn bundled(model: &str) -> Self {
+        Self {
+            context_window: context::context_window(model),
+            pricing: pricing_for(model),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelEntry {
+    /// The name sent to the provider
+    pub name: String,
+    #[serde(flatten)]
+    pub args: ModelArgs,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelSource {
+    /// Just fetched from the provider
+    Live,
+    Cache,
+    /// The provider couldn't be reached and nothing was cached
+    Bundled,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModelList {
+    pub provider: String,
+    pub source: ModelSource,
+    pub models: Vec<ModelEntry>,
+    /// Why the provider couldn't be queried, when it couldn't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub fn model_id(provider: &str, model: &str) -> String {
+    format!("{}:{}", provider, model)
+}
+
+async fn load_cached(db: &SqlitePool, provider: &str) -> Result<Vec<ModelEntry>, ErrorResponse> {
+    let rows = sqlx::query!(
+        "SELECT name, args FROM models WHERE parent = ? ORDER BY name",
+        provider
+    )
+    .fetch_all(db)
+    .await
+    .map_err(db_error)?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| ModelEntry {
+            name: row.name,
+            args: row
+                .args
+                .and_then(|args| serde_json::from_str(&args).ok())
+                .unwrap_or_default(),
+        })
+        .collect())
+}
+
+/// Inserts or refreshes a model. Rows are never deleted, since
+/// conversations reference them.
+async fn upsert(
+    db: &SqlitePool,
+    provider: &str,
+    model: &str,
+    args: &ModelArgs,
+) -> Result<String, ErrorResponse> {
+    let id = model_id(provider, model);
+    let args = serde_json::to_string(args).map_err(|e| ErrorResponse {
+        message: "Failed to serialize model details".to_string(),
+        details: Some(e.to_string()),
+        provider_error: None,
+    })?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO models (id, name, args, parent)
+        VALUES (?, ?, ?, ?)
+        ON CONFLICT(id) DO UPDATE SET args = excluded.args
+        "#,
+        id,
+        model,
+        args,
+        provider
+    )
+    .execute(db)
+    .await
+    .map_err(db_error)?;
+
+    Ok(id)
+}
+
+/// Makes sure `model` has a row, so it can be referenced by
+/// `conversations.model_id`, and returns its id.
+pub(crate) async fn ensure_registered(
+    db: &SqlitePool,
+    provider: &str,
+    model: &str,
+) -> Result<String, ErrorResponse> {
+    let id = model_id(provider, model);
+    let exists = sqlx::query_scalar!("SELECT COUNT(*) FROM models WHERE id = ?", id)
+        .fetch_one(db)
+        .await
+        .map_err(db_error)?
+        > 0;
+    if exists {
+        return Ok(id);
+    }
+    upsert(db, provider, model, &ModelArgs::bundled(model)).await
+}
+
+/// What the registry knows about `model`, if anything.
+pub(crate) async fn lookup(
+    db: &SqlitePool,
+    provider: &str,
+    model: &str,
+) -> Result<Option<ModelArgs>, ErrorResponse> {
+    let id = model_id(provider, model);
+    let args = sqlx::query_scalar!("SELECT args FROM models WHERE id = ?", id)
+        .fetch_optional(db)
+        .await
+        .map_err(db_error)?
+        .flatten();
+    Ok(args.and_then(|args| serde_json::from_str(&args).ok()))
+}
+
+fn is_fresh(models: &[ModelEntry]) -> bool {
+    let cutoff = Utc::now() - Duration::hours(CACHE_TTL_HOURS);
+    !models.is_empty()
+        && models
+            .iter()
+            .filter_map(|model| model.args.fetched_at)
+            .max()
+            .is_some_and(|fetched_at| fetched_at > cutoff)
+}
+
+/// Lists the models of `provider`. Served from the cache unless it's stale
+/// or `refresh` is set; falls back to the cache, then to a bundled list,
+/// when the provider can't be reached.
+#[tauri::command]
+pub async fn list_models(
+    provider: String,
+    refresh: Option<bool>,
+    app_handle: AppHandle,
+    config_state: State<'_, ConfigState>,
+) -> Result<ModelList, ErrorResponse> {
+    let app_state = app_handle.state::<AppState>();
+    let db = &app_state.db;
+
+    let cached = load_cached(db, &provider).await?;
+    if !refresh.unwrap_or(false) && is_fresh(&cached) {
+        return Ok(ModelList {
+            provider,
+            source: ModelSource::Cache,
+            models: cached,
+            error: None,
+        });
+    }
+
+    let provider_config = {
+        let config = config_state.0.lock();
+        config.providers.get(&provider).map(|settings| {
+            let mut provider_config = settings.to_provider_config();
+            provider_config.recording = config.recording.clone();
+            provider_config
+        })
+    };
+
+    let fetched = match provider_config {
+        Some(provider_config) => {
+            match ProviderFactory::create_provider(&provider, provider_config) {
+                Ok(client) => client.list_models().await.map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+        None => Err(format!("Provider {} is not configured", provider)),
+    };
+
+    match fetched {
+        Ok(infos) => {
+            let fetched_at = Utc::now();
+            let mut models = Vec::with_capacity(infos.len());
+            for info in &infos {
+                let args = ModelArgs::from_info(info, fetched_at);
+                upsert(db, &provider, &info.id, &args).await?;
+                models.push(ModelEntry {
+                    name: info.id.clone(),
+                    args,
+                });
+            }
+            models.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(ModelList {
+                provider,
+                source: ModelSource::Live,
+                models,
+                error: None,
+            })
+        }
+        Err(error) if !cached.is_empty() => {
+            log::warn!("Listing {} models failed, using cache: {}", provider, error);
+            Ok(ModelList {
+                provider,
+                source: ModelSource::Cache,
+                models: cached,
+                error: Some(error),
+            })
+        }
+        Err(error) => {
+            log::warn!("Listing {} models failed: {}", provider, error);
+            let models = BUNDLED_MODELS
+                .iter()
+                .find(|(name, _)| *name == provider)
+                .map(|(_, models)| *models)
+                .unwrap_or_default()
+                .iter()
+                .map(|model| ModelEntry {
+                    name: model.to_string(),
+                    args: ModelArgs::bundled(model),
+                })
+                .collect();
+            Ok(ModelList {
+                provider,
+                source: ModelSource::Bundled,
+                models,
+                error: Some(error),
+            })
+        }
+    }
+}
@@ -0,0 +1,434 @@
+//! Checks replies requested with a `ResponseSchema`. Providers constrain
+//! the reply as far as their API allows, but none of them guarantee it, so
+//! the reply is parsed with `serde_json` and checked against the schema
+//! here before it is accepted.
+//!
+//! The common JSON Schema keywords are checked: `type`, `enum`, `const`,
+//! `properties`, `required`, `additionalProperties`, `items`, `anyOf`,
+//! `oneOf`, `allOf` and the length and range bounds. Schemas using any
+//! other keyword, such as `$ref` or `pattern`, are rejected up front rather
+//! than letting every reply through.
+
+use crate::apimodels::ResponseSchema;
+use serde_json::{Map, Value};
+
+/// Keywords `validate` checks.
+const SUPPORTED_KEYWORDS: &[&str] = &[
+    "type",
+    "enum",
+    "const",
+    "properties",
+    "required",
+    "additionalProperties",
+    "items",
+    "anyOf",
+    "oneOf",
+    "allOf",
+    "minLength",
+    "maxLength",
+    "minItems",
+    "maxItems",
+    "minimum",
+    "maximum",
+];
+
+/// Keywords that don't constrain the reply, so they're safe to ignore.
+const ANNOTATIONS: &[&str] = &[
+    "$schema",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+];
+
+/// Rejects names the providers won't accept and schemas `check` can't
+/// enforce, before anything is sent.
+pub fn validate_schema(schema: &ResponseSchema) -> Result<(), String> {
+    let valid = (1..=64).contains(&schema.name.len())
+        && schema
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(format!(
+            "schema name {:?} must be 1 to 64 letters, digits, '_' or '-'",
+            schema.name
+        ));
+    }
+    if !schema.schema.is_object() {
+        return Err("the schema must be a JSON object".to_string());
+    }
+    check_supported(&schema.schema, "$")
+}
+
+/// Fails on the first keyword under `schema` that `validate` would ignore.
+fn check_supported(schema: &Value, path: &str) -> Result<(), String> {
+    let schema = match schema {
+        Value::Bool(_) => return Ok(()),
+        Value::Object(schema) => schema,
+        _ => return Err(format!("{}: a schema must be an object or a boolean", path)),
+    };
+
+    for (keyword, value) in schema {
+        if ANNOTATIONS.contains(&keyword.as_str()) {
+            continue;
+        }
+        if !SUPPORTED_KEYWORDS.contains(&keyword.as_str()) {
+            return Err(format!(
+                "{}: the {:?} keyword is not supported in response schemas",
+                path, keyword
+            ));
+        }
+        match (keyword.as_str(), value) {
+            ("properties", Value::Object(properties)) => {
+                for (name, property) in properties {
+                    check_supported(property, &format!("{}.properties.{}", path, name))?;
+                }
+            }
+            ("additionalProperties" | "items", _) => {
+                check_supported(value, &format!("{}.{}", path, keyword))?;
+            }
+            ("anyOf" | "oneOf" | "allOf", Value::Array(subschemas)) => {
+                for (index, subschema) in subschemas.iter().enumerate() {
+                    check_supported(subschema, &format!("{}.{}[{}]", path, keyword, index))?;
+                }
+            }
+            ("properties" | "anyOf" | "oneOf" | "allOf", _) => {
+                return Err(format!("{}: {:?} has the wrong type", path, keyword));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Parses `reply` and checks it against `schema`. A Markdown code fence
+/// around the JSON is tolerated.
+pub fn check(reply: &str, schema: &Value) -> Result<Value, String> {
+    let json = strip_fence(reply.trim());
+    let value: Value =
+        serde_json::from_str(json).map_err(|e| format!("the reply is not valid JSON: {}", e))?;
+    validate(&value, schema, "$")?;
+    Ok(value)
+}
+
+/// Sent back to the model when its reply didn't pass `check`.
+pub fn reask_prompt(error: &str) -> String {
+    format!(
+        "Your reply did not match the required JSON schema: {}. \
+         Reply again with only the corrected JSON.",
+        error
+    )
+}
+
+fn strip_fence(reply: &str) -> &str {
+    let Some(rest) = reply.strip_prefix("```") else {
+        return reply;
+    };
+    // Skip the language tag, if any
+    let rest = rest.split_once('\n').map_or("", |(_, body)| body);
+    rest.trim_end().strip_suffix("```").unwrap_or(rest).trim()
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_i64() || number.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn has_type(value: &Value, expected: &str) -> bool {
+    let actual = type_name(value);
+    actual == expected
+        || (expected == "number" && actual == "integer")
+        // 1.0 is an integer as far as JSON Schema is concerned
+        || (expected == "integer" && value.as_f64().is_some_and(|n| n.fract() == 0.0))
+}
+
+fn validate(value: &Value, schema: &Value, path: &str) -> Result<(), String> {
+    let schema = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => return Err(format!("{}: no value is allowed here", path)),
+        Value::Object(schema) => schema,
+        _ => return Ok(()),
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let types: Vec<&str> = match expected {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !types.is_empty() && !types.iter().any(|name| has_type(value, name)) {
+            return Err(format!(
+                "{}: expected {}, got {}",
+                path,
+                types.join(" or "),
+                type_name(value)
+            ));
+        }
+    }
+
+    if let Some(Value::Array(options)) = schema.get("enum") {
+        if !options.contains(value) {
+            return Err(format!(
+                "{}: {} is not one of {}",
+                path,
+                value,
+                Value::Array(options.clone())
+            ));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if value != expected {
+            return Err(format!("{}: expected {}", path, expected));
+        }
+    }
+
+    let subschemas = |keyword: &str| match schema.get(keyword) {
+        Some(Value::Array(schemas)) => schemas.as_slice(),
+        _ => &[],
+    };
+    for subschema in subschemas("allOf") {
+        validate(value, subschema, path)?;
+    }
+    let any_of = subschemas("anyOf");
+    if !any_of.is_empty() {
+        let errors: Vec<String> = any_of
+            .iter()
+            .filter_map(|subschema| validate(value, subschema, path).err())
+            .collect();
+        if errors.len() == any_of.len() {
+            return Err(format!(
+                "{}: matches none of anyOf ({})",
+                path,
+                errors.join("; ")
+            ));
+        }
+    }
+    let one_of = subschemas("oneOf");
+    if !one_of.is_empty() {
+        let matched = one_of
+            .iter()
+            .filter(|subschema| validate(value, subschema, path).is_ok())
+            .count();
+        if matched != 1 {
+            return Err(format!(
+                "{}: matches {} of oneOf instead of exactly one",
+                path, matched
+            ));
+        }
+    }
+
+    match value {
+        Value::Object(object) => validate_object(object, schema, path),
+        Value::Array(items) => {
+            check_bounds(items.len(), schema, "minItems", "maxItems", "items", path)?;
+            if let Some(item_schema) = schema.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    validate(item, item_schema, &format!("{}[{}]", path, index))?;
+                }
+            }
+            Ok(())
+        }
+        Value::String(text) => check_bounds(
+            text.chars().count(),
+            schema,
+            "minLength",
+            "maxLength",
+            "characters",
+            path,
+        ),
+        Value::Number(number) => {
+            let number = number.as_f64().unwrap_or_default();
+            if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64) {
+                if number < minimum {
+                    return Err(format!("{}: {} is less than {}", path, number, minimum));
+                }
+            }
+            if let Some(maximum) = schema.get("maximum").and_then(Value::as_f64) {
+                if number > maximum {
+                    return Err(format!("{}: {} is more than {}", path, number, maximum));
+                }
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn validate_object(
+    object: &Map<String, Value>,
+    schema: &Map<String, Value>,
+    path: &str,
+) -> Result<(), String> {
+    if let Some(Value::Array(required)) = schema.get("required") {
+        for name in required.iter().filter_map(Value::as_str) {
+            if !object.contains_key(name) {
+                return Err(format!("{}: missing required property {:?}", path, name));
+            }
+        }
+    }
+
+    let properties = schema.get("properties").and_then(Value::as_object);
+    for (name, property) in object {
+        let property_path = format!("{}.{}", path, name);
+        match properties.and_then(|properties| properties.get(name)) {
+            Some(property_schema) => validate(property, property_schema, &property_path)?,
+            None => match schema.get("additionalProperties") {
+                Some(Value::Bool(false)) => {
+                    return Err(format!("{}: unexpected property {:?}", path, name))
+                }
+                Some(additional) => validate(property, additional, &property_path)?,
+                None => {}
+            },
+        }
+    }
+    Ok(())
+}
+
+fn check_bounds(
+    len: usize,
+    schema: &Map<String, Value>,
+    min_keyword: &str,
+    max_keyword: &str,
+    unit: &str,
+    path: &str,
+) -> Result<(), String> {
+    if let Some(min) = schema.get(min_keyword).and_then(Value::as_u64) {
+        if (len as u64) < min {
+            return Err(format!(
+                "{}: expected at least {} {}, got {}",
+                path, min, unit, len
+            ));
+        }
+    }
+    if let Some(max) = schema.get(max_keyword).and_then(Value::as_u64) {
+        if len as u64 > max {
+            return Err(format!(
+                "{}: expected at most {} {}, got {}",
+                path, max, unit, len
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn response_schema(schema: Value) -> ResponseSchema {
+        ResponseSchema {
+            name: "answer".to_string(),
+            schema,
+        }
+    }
+
+    #[test]
+    fn strips_code_fences() {
+        assert_eq!(strip_fence("```json\n{\"a\": 1}\n```"), "{\"a\": 1}");
+        assert_eq!(strip_fence("```\n[1]\n```  "), "[1]");
+        assert_eq!(strip_fence("{\"a\": 1}"), "{\"a\": 1}");
+        // An unterminated fence still yields the body
+        assert_eq!(strip_fence("```json\n{}"), "{}");
+    }
+
+    #[test]
+    fn whole_floats_are_integers() {
+        let schema = json!({ "type": "integer" });
+        assert!(check("1.0", &schema).is_ok());
+        assert!(check("1", &schema).is_ok());
+        assert!(check("1.5", &schema).is_err());
+        assert!(check("1", &json!({ "type": "number" })).is_ok());
+    }
+
+    #[test]
+    fn one_of_needs_exactly_one_match() {
+        let schema = json!({ "oneOf": [{ "type": "integer" }, { "type": "number" }] });
+        // 1.5 is only a number
+        assert!(check("1.5", &schema).is_ok());
+        // 1 is both an integer and a number
+        let error = check("1", &schema).unwrap_err();
+        assert!(error.contains("matches 2 of oneOf"), "{}", error);
+        // A string is neither
+        let error = check("\"a\"", &schema).unwrap_err();
+        assert!(error.contains("matches 0 of oneOf"), "{}", error);
+    }
+
+    #[test]
+    fn checks_required_and_additional_properties() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "name": { "type": "string" }, "age": { "type": "integer" } },
+            "required": ["name"],
+            "additionalProperties": false,
+        });
+        assert!(check(r#"{"name": "Ada"}"#, &schema).is_ok());
+        assert!(check(r#"{"name": "Ada", "age": 36}"#, &schema).is_ok());
+
+        let error = check(r#"{"age": 36}"#, &schema).unwrap_err();
+        assert!(
+            error.contains("missing required property \"name\""),
+            "{}",
+            error
+        );
+        let error = check(r#"{"name": "Ada", "email": "a@b.c"}"#, &schema).unwrap_err();
+        assert!(error.contains("unexpected property \"email\""), "{}", error);
+        let error = check(r#"{"name": 1}"#, &schema).unwrap_err();
+        assert!(error.starts_with("$.name: expected string"), "{}", error);
+
+        // Extra properties are checked against a schema-valued keyword
+        let schema = json!({ "type": "object", "additionalProperties": { "type": "integer" } });
+        assert!(check(r#"{"a": 1}"#, &schema).is_ok());
+        assert!(check(r#"{"a": "1"}"#, &schema).is_err());
+    }
+
+    #[test]
+    fn rejects_unsupported_keywords() {
+        let supported = json!({
+            "title": "Person",
+            "type": "object",
+            "properties": {
+                // A property may be called like a keyword
+                "pattern": { "type": "string", "description": "Not a keyword here" },
+                "tags": { "type": "array", "items": { "type": "string", "maxLength": 10 } },
+            },
+            "anyOf": [{ "required": ["pattern"] }, { "required": ["tags"] }],
+        });
+        assert!(validate_schema(&response_schema(supported)).is_ok());
+
+        for schema in [
+            json!({ "$ref": "#/$defs/person", "$defs": { "person": { "type": "object" } } }),
+            json!({ "type": "object", "properties": { "id": { "type": "string", "pattern": "^a" } } }),
+            json!({ "type": "array", "prefixItems": [{ "type": "string" }] }),
+            json!({ "type": "array", "items": [{ "type": "string" }] }),
+            json!({ "oneOf": [{ "type": "number", "exclusiveMinimum": 0 }] }),
+            json!({ "type": "string", "format": "email" }),
+        ] {
+            let error = validate_schema(&response_schema(schema.clone())).unwrap_err();
+            assert!(
+                error.contains("not supported") || error.contains("must be"),
+                "{}: {}",
+                schema,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_names() {
+        assert!(validate_schema(&ResponseSchema {
+            name: "has space".to_string(),
+            schema: json!({}),
+        })
+        .is_err());
+    }
+}
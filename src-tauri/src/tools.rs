@@ -0,0 +1,80 @@
+use crate::apimodels::{ToolCall, ToolDefinition};
+use async_trait::async_trait;
+use chrono::Local;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// A Rust function the model can call while `chat::process_message` runs.
+#[async_trait]
+pub trait Tool: Send + Sync {
+    fn definition(&self) -> ToolDefinition;
+
+    /// Runs the tool. An `Err` is reported back to the model as a failed
+    /// tool result rather than aborting the conversation.
+    async fn call(&self, input: Value) -> Result<String, String>;
+}
+
+pub struct ToolRegistry {
+    tools: HashMap<String, Box<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        Self {
+            tools: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, tool: impl Tool + 'static) {
+        self.tools.insert(tool.definition().name, Box::new(tool));
+    }
+
+    /// Definitions of all registered tools, sorted by name so requests are stable.
+    pub fn definitions(&self) -> Vec<ToolDefinition> {
+        let mut definitions = self
+            .tools
+            .values()
+            .map(|tool| tool.definition())
+            .collect::<Vec<_>>();
+        definitions.sort_by(|a, b| a.name.cmp(&b.name));
+        definitions
+    }
+
+    pub async fn call(&self, call: &ToolCall) -> Result<String, String> {
+        let tool = self
+            .tools
+            .get(&call.name)
+            .ok_or_else(|| format!("Unknown tool: {}", call.name))?;
+        tool.call(call.input.clone()).await
+    }
+}
+
+impl Default for ToolRegistry {
+    /// A registry with the built-in tools.
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry.register(CurrentTime);
+        registry
+    }
+}
+
+/// Tells the model the local date and time, which it otherwise can't know.
+struct CurrentTime;
+
+#[async_trait]
+impl Tool for CurrentTime {
+    fn definition(&self) -> ToolDefinition {
+        ToolDefinition {
+            name: "current_time".to_string(),
+            description: "Returns the current local date and time in RFC 3339 format.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {},
+            }),
+        }
+    }
+
+    async fn call(&self, _input: Value) -> Result<String, String> {
+        Ok(Local::now().to_rfc3339())
+    }
+}
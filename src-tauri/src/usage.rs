@@ -0,0 +1,187 @@
+use crate::apimodels::Usage;
+use crate::chat::{db_error, ErrorResponse};
+use crate::AppState;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tauri::{AppHandle, Manager};
+
+/// Prices in USD per million tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ModelPricing {
+    pub input: f64,
+    pub output: f64,
+    pub cache_write: f64,
+    pub cache_read: f64,
+}
+
+impl ModelPricing {
+    const fn new(input: f64, output: f64, cache_write: f64, cache_read: f64) -> Self {
+        Self {
+            input,
+            output,
+            cache_write,
+            cache_read,
+        }
+    }
+
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        (usage.input_tokens as f64 * self.input
+            + usage.output_tokens as f64 * self.output
+            + usage.cache_creation_input_tokens as f64 * self.cache_write
+            + usage.cache_read_input_tokens as f64 * self.cache_read)
+            / 1_000_000.0
+    }
+
+    /// What prompt caching saved compared to sending every token as regular
+    /// input. Negative when cache writes weren't read back often enough.
+    pub fn cache_savings(&self, usage: &Usage) -> f64 {
+        (usage.cache_read_input_tokens as f64 * (self.input - self.cache_read)
+            - usage.cache_creation_input_tokens as f64 * (self.cache_write - self.input))
+            / 1_000_000.0
+    }
+}
+
+/// Message Batches requests cost this fraction of the listed prices.
+const BATCH_DISCOUNT: f64 = 0.5;
+
+/// Known prices, matched by model name prefix so dated snapshots
+/// (`claude-3-5-sonnet-20240620`) share an entry.
+const PRICES: &[(&str, ModelPricing)] = &[
+    ("claude-opus-4", ModelPricing::new(15.0, 75.0, 18.75, 1.5)),
+    ("claude-sonnet-4", ModelPricing::new(3.0, 15.0, 3.75, 0.3)),
+    ("claude-3-7-sonnet", ModelPricing::new(3.0, 15.0, 3.75, 0.3)),
+    ("claude-3-5-sonnet", ModelPricing::new(3.0, 15.0, 3.75, 0.3)),
+    ("claude-3-5-haiku", ModelPricing::new(0.8, 4.0, 1.0, 0.08)),
+    ("claude-3-opus", ModelPricing::new(15.0, 75.0, 18.75, 1.5)),
+    ("claude-3-sonnet", ModelPricing::new(3.0, 15.0, 3.75, 0.3)),
+    ("claude-3-haiku", ModelPricing::new(0.25, 1.25, 0.3, 0.03)),
+    ("claude-2", ModelPricing::new(8.0, 24.0, 8.0, 8.0)),
+    ("gpt-4o-mini", ModelPricing::new(0.15, 0.6, 0.15, 0.075)),
+    ("gpt-4o", ModelPricing::new(2.5, 10.0, 2.5, 1.25)),
+    ("gpt-4-turbo", ModelPricing::new(10.0, 30.0, 10.0, 10.0)),
+    (
+        "gpt-4-0125-preview",
+        ModelPricing::new(10.0, 30.0, 10.0, 10.0),
+    ),
+    ("gpt-4", ModelPricing::new(30.0, 60.0, 30.0, 30.0)),
+    ("gpt-3.5-turbo", ModelPricing::new(0.5, 1.5, 0.5, 0.5)),
+    ("gemini-2.5-pro", ModelPricing::new(1.25, 10.0, 1.25, 0.31)),
+    ("gemini-2.5-flash", ModelPricing::new(0.3, 2.5, 0.3, 0.075)),
+    ("gemini-2.0-flash", ModelPricing::new(0.1, 0.4, 0.1, 0.025)),
+    ("gemini-1.5-pro", ModelPricing::new(1.25, 5.0, 1.25, 0.3125)),
+    (
+        "gemini-1.5-flash",
+        ModelPricing::new(0.075, 0.3, 0.075, 0.01875),
+    ),
+];
+
+/// Looks up the price of `model`, preferring the longest matching prefix.
+/// Local models have no entry.
+pub fn pricing_for(model: &str) -> Option<ModelPricing> {
+    // OpenRouter names models `vendor/model`
+    let model = model.rsplit('/').next().unwrap_or(model);
+    PRICES
+        .iter()
+        .filter(|(prefix, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, pricing)| *pricing)
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageGrouping {
+    Conversation,
+    Model,
+    Day,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct UsageSummary {
+    key: String,
+    message_count: i64,
+    input_tokens: u64,
+    output_tokens: u64,
+    cache_creation_input_tokens: u64,
+    cache_read_input_tokens: u64,
+    estimated_cost: f64,
+    estimated_cache_savings: f64,
+    /// Messages from models without a known price, left out of `estimated_cost`
+    unpriced_message_count: i64,
+}
+
+impl UsageSummary {
+    fn add(&mut self, usage: &Usage, model: Option<&str>, batch: bool) {
+        self.message_count += 1;
+        self.input_tokens += u64::from(usage.input_tokens);
+        self.output_tokens += u64::from(usage.output_tokens);
+        self.cache_creation_input_tokens += u64::from(usage.cache_creation_input_tokens);
+        self.cache_read_input_tokens += u64::from(usage.cache_read_input_tokens);
+
+        match model.and_then(pricing_for) {
+            Some(pricing) => {
+                let rate = if batch { BATCH_DISCOUNT } else { 1.0 };
+                self.estimated_cost += pricing.cost(usage) * rate;
+                self.estimated_cache_savings += pricing.cache_savings(usage) * rate;
+            }
+            None => self.unpriced_message_count += 1,
+        }
+    }
+}
+
+/// Totals token usage and estimated cost of all assistant messages,
+/// grouped by conversation, model or (local) day.
+#[tauri::command]
+pub async fn get_usage_summary(
+    group_by: UsageGrouping,
+    app_handle: AppHandle,
+) -> Result<Vec<UsageSummary>, ErrorResponse> {
+    let app_state = app_handle.state::<AppState>();
+    let db = &app_state.db;
+
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+            conversation_id as "conversation_id!",
+            date(created_at, 'localtime') as "day!: String",
+            json_extract(metadata, '$.model') as "model: String",
+            json_extract(metadata, '$.usage') as "usage!: String",
+            json_extract(metadata, '$.batch') as "batch: bool"
+        FROM messages
+        WHERE json_extract(metadata, '$.usage') IS NOT NULL
+        "#
+    )
+    .fetch_all(db)
+    .await
+    .map_err(db_error)?;
+
+    let mut summaries: HashMap<String, UsageSummary> = HashMap::new();
+    for row in rows {
+        let Ok(usage) = serde_json::from_str::<Usage>(&row.usage) else {
+            continue;
+        };
+
+        let key = match group_by {
+            UsageGrouping::Conversation => row.conversation_id.to_string(),
+            UsageGrouping::Model => row.model.clone().unwrap_or_else(|| "unknown".to_string()),
+            UsageGrouping::Day => row.day,
+        };
+
+        let summary = summaries
+            .entry(key.clone())
+            .or_insert_with(|| UsageSummary {
+                key,
+                ..Default::default()
+            });
+        summary.add(&usage, row.model.as_deref(), row.batch.unwrap_or_default());
+    }
+
+    let mut summaries = summaries.into_values().collect::<Vec<_>>();
+    match group_by {
+        UsageGrouping::Conversation => {
+            summaries.sort_by_key(|summary| summary.key.parse::<i64>().unwrap_or_default())
+        }
+        UsageGrouping::Model | UsageGrouping::Day => summaries.sort_by(|a, b| a.key.cmp(&b.key)),
+    }
+
+    Ok(summaries)
+}
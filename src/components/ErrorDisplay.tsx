@@ -1,19 +1,56 @@
 import React from "react";
-import { AlertCircle, RotateCcw } from "lucide-react";
+import { AlertCircle, KeyRound, RotateCcw } from "lucide-react";
 import { useZustandTheme } from "@/store";
+import { ProviderError } from "./chat/useChat";
 
 interface ErrorDisplayProps {
   message: string;
   details?: string;
+  providerError?: ProviderError;
   onRetry?: () => void;
+  onOpenSettings?: () => void;
 }
 
+// What the user can do about each kind of provider failure
+const hintFor = (error: ProviderError): string | null => {
+  switch (error.code) {
+    case "auth":
+      return "Check the API key in the model settings.";
+    case "rate_limited":
+      return error.retry_after_ms
+        ? `Try again in ${Math.ceil(error.retry_after_ms / 1000)}s.`
+        : "Wait a moment before trying again.";
+    case "overloaded":
+      return "The provider is busy. Try again shortly.";
+    case "context_too_long":
+      return "Start a new conversation or use a model with a larger context window.";
+    case "network":
+    case "timeout":
+      return "Check your connection and try again.";
+    default:
+      return null;
+  }
+};
+
+// Sending the same request again can't fix these
+const NOT_RETRYABLE: ProviderError["code"][] = [
+  "auth",
+  "invalid_request",
+  "context_too_long",
+];
+
 export const ErrorDisplay: React.FC<ErrorDisplayProps> = ({
   message,
   details,
+  providerError,
   onRetry,
+  onOpenSettings,
 }) => {
   const { theme } = useZustandTheme();
+  const hint = providerError ? hintFor(providerError) : null;
+  const canRetry =
+    onRetry && !(providerError && NOT_RETRYABLE.includes(providerError.code));
+  const showSettings = onOpenSettings && providerError?.code === "auth";
 
   return (
     <div className="flex hover:bg-opacity-50 transition-colors duration-200 py-3 px-4 hover:bg-transparent">
@@ -41,17 +78,33 @@ export const ErrorDisplay: React.FC<ErrorDisplayProps> = ({
               {details && (
                 <div className="text-sm mt-1 text-red-400">{details}</div>
               )}
+              {hint && (
+                <div className="text-sm mt-1" style={{ color: theme.textSecondary }}>
+                  {hint}
+                </div>
+              )}
             </div>
           </div>
-          {onRetry && (
+          {(canRetry || showSettings) && (
             <div className="flex-shrink-0 w-12 flex space-x-1">
-              <button
-                onClick={onRetry}
-                className="text-red-400 hover:text-red-500 transition-colors duration-200"
-                aria-label="Retry"
-              >
-                <RotateCcw size={16} />
-              </button>
+              {showSettings && (
+                <button
+                  onClick={onOpenSettings}
+                  className="text-red-400 hover:text-red-500 transition-colors duration-200"
+                  aria-label="Open API key settings"
+                >
+                  <KeyRound size={16} />
+                </button>
+              )}
+              {canRetry && (
+                <button
+                  onClick={onRetry}
+                  className="text-red-400 hover:text-red-500 transition-colors duration-200"
+                  aria-label="Retry"
+                >
+                  <RotateCcw size={16} />
+                </button>
+              )}
             </div>
           )}
         </div>
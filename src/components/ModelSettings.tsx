@@ -1,4 +1,4 @@
-// import React from "react";
+import { useEffect, useState } from "react";
 import { invoke } from "@tauri-apps/api/core";
 import { Card } from "@/components/ui/card";
 import { Label } from "@/components/ui/label";
@@ -13,33 +13,26 @@ import {
   SelectTrigger,
   SelectValue,
 } from "@/components/ui/select";
-import { useModelStore } from "@/store";
-
-const modelOptions = {
-  anthropic: [
-    "claude-3-opus-20240229",
-    "claude-3-sonnet-20240229",
-    "claude-3-haiku-20240307",
-    "claude-2.1",
-    "claude-2.0",
-  ],
-  openai: [
-    "gpt-4-turbo-preview",
-    "gpt-4-0125-preview",
-    "gpt-4",
-    "gpt-3.5-turbo",
-  ],
-  openrouter: [
-    "anthropic/claude-3-opus",
-    "anthropic/claude-3-sonnet",
-    "openai/gpt-4-turbo-preview",
-    "google/gemini-pro",
-    "meta/llama-3-70b",
-  ],
-};
+import { ModelEntry, ModelList, NetworkSettings, useModelStore } from "@/store";
+
+const PROVIDERS = ["anthropic", "openai", "openrouter", "gemini", "ollama"];
 
 export function ModelSettings() {
   const { config, updateProviderSettings, setActiveProvider } = useModelStore();
+  const [modelLists, setModelLists] = useState<Record<string, ModelList>>({});
+
+  const loadModels = async (provider: string, refresh = false) => {
+    try {
+      const list = await invoke<ModelList>("list_models", { provider, refresh });
+      setModelLists((lists) => ({ ...lists, [provider]: list }));
+    } catch (error) {
+      console.error(`Failed to list ${provider} models:`, error);
+    }
+  };
+
+  useEffect(() => {
+    PROVIDERS.forEach((provider) => loadModels(provider));
+  }, []);
 
   const handleProviderChange = async (provider: string) => {
     try {
@@ -53,7 +46,7 @@ export function ModelSettings() {
   const handleSettingChange = (
     provider: string,
     setting: string,
-    value: string | number | boolean
+    value: string | number | boolean | NetworkSettings | null
   ) => {
     const currentSettings = config.providers[provider];
     const newSettings = {
@@ -63,10 +56,23 @@ export function ModelSettings() {
     updateProviderSettings(provider, newSettings);
   };
 
+  const handleNetworkChange = (
+    provider: string,
+    setting: keyof NetworkSettings,
+    value: NetworkSettings[keyof NetworkSettings]
+  ) => {
+    const network = config.providers[provider].network ?? {};
+    handleSettingChange(provider, "network", { ...network, [setting]: value });
+  };
+
+  const parseSeconds = (value: string) => (value ? parseInt(value) : null);
+
   const saveProviderSettings = async (provider: string) => {
     try {
       const settings = config.providers[provider];
       await invoke("update_provider_settings", { provider, settings });
+      // A new API key or base URL can change what's available
+      loadModels(provider, true);
     } catch (error) {
       console.error("Failed to save provider settings:", error);
     }
@@ -74,7 +80,13 @@ export function ModelSettings() {
 
   const renderProviderSettings = (provider: string) => {
     const settings = config.providers[provider];
-    const models = modelOptions[provider as keyof typeof modelOptions] || [];
+    const modelList = modelLists[provider];
+    const models = modelList?.models ?? [];
+    // Keep the configured model selectable even if it isn't listed
+    const names = models.some((model) => model.name === settings.model)
+      ? models
+      : [{ name: settings.model }, ...models];
+    const network = settings.network ?? {};
 
     return (
       <div className="space-y-6">
@@ -92,7 +104,16 @@ export function ModelSettings() {
           </div>
 
           <div className="space-y-2">
-            <Label>Model</Label>
+            <div className="flex items-center justify-between">
+              <Label>Model</Label>
+              <Button
+                variant="ghost"
+                size="sm"
+                onClick={() => loadModels(provider, true)}
+              >
+                Refresh
+              </Button>
+            </div>
             <Select
               value={settings.model}
               onValueChange={(value) =>
@@ -103,13 +124,33 @@ export function ModelSettings() {
                 <SelectValue placeholder="Select model" />
               </SelectTrigger>
               <SelectContent>
-                {models.map((model) => (
-                  <SelectItem key={model} value={model}>
-                    {model}
+                {names.map((model: ModelEntry) => (
+                  <SelectItem key={model.name} value={model.name}>
+                    {model.display_name ?? model.name}
+                    {model.context_window
+                      ? ` (${Math.round(model.context_window / 1000)}k context)`
+                      : ""}
                   </SelectItem>
                 ))}
               </SelectContent>
             </Select>
+            {modelList?.error && (
+              <p className="text-xs text-gray-500">
+                Couldn't reach {provider} ({modelList.error}); showing{" "}
+                {modelList.source === "cache" ? "cached" : "built-in"} models.
+              </p>
+            )}
+          </div>
+
+          <div className="space-y-2">
+            <Label>Base URL</Label>
+            <Input
+              value={settings.base_url ?? ""}
+              onChange={(e) =>
+                handleSettingChange(provider, "base_url", e.target.value)
+              }
+              placeholder="Leave empty for the provider default"
+            />
           </div>
 
           <div className="space-y-2">
@@ -127,6 +168,40 @@ export function ModelSettings() {
             />
           </div>
 
+          {provider === "anthropic" && (
+            <div className="space-y-2">
+              <Label>Thinking Budget (tokens)</Label>
+              <Input
+                type="number"
+                min={1024}
+                value={settings.thinking_budget_tokens ?? ""}
+                onChange={(e) =>
+                  handleSettingChange(
+                    provider,
+                    "thinking_budget_tokens",
+                    e.target.value ? parseInt(e.target.value) : null
+                  )
+                }
+                placeholder="Leave empty to disable extended thinking"
+              />
+            </div>
+          )}
+
+          {provider === "anthropic" && (
+            <div className="flex items-center space-x-2">
+              <Switch
+                id={`${provider}-prompt-caching`}
+                checked={settings.prompt_caching ?? false}
+                onCheckedChange={(checked) =>
+                  handleSettingChange(provider, "prompt_caching", checked)
+                }
+              />
+              <Label htmlFor={`${provider}-prompt-caching`}>
+                Enable Prompt Caching
+              </Label>
+            </div>
+          )}
+
           <div className="flex items-center space-x-2">
             <Switch
               id={`${provider}-streaming`}
@@ -138,6 +213,115 @@ export function ModelSettings() {
             <Label htmlFor={`${provider}-streaming`}>Enable Streaming</Label>
           </div>
 
+          <div className="space-y-4 border-t pt-4">
+            <h3 className="text-sm font-medium">Network</h3>
+
+            <div className="space-y-2">
+              <Label>Proxy</Label>
+              <Input
+                value={network.proxy ?? ""}
+                onChange={(e) =>
+                  handleNetworkChange(provider, "proxy", e.target.value || null)
+                }
+                placeholder="http://proxy.example.com:8080 (defaults to HTTPS_PROXY)"
+              />
+            </div>
+
+            <div className="space-y-2">
+              <Label>No Proxy</Label>
+              <Input
+                value={network.no_proxy ?? ""}
+                onChange={(e) =>
+                  handleNetworkChange(provider, "no_proxy", e.target.value || null)
+                }
+                placeholder="localhost, .internal, 10.0.0.0/8"
+              />
+            </div>
+
+            <div className="space-y-2">
+              <Label>CA Certificate Files (PEM)</Label>
+              {/* Parsed on blur so a trailing comma can be typed */}
+              <Input
+                defaultValue={(network.ca_certificates ?? []).join(", ")}
+                onBlur={(e) =>
+                  handleNetworkChange(
+                    provider,
+                    "ca_certificates",
+                    e.target.value
+                      .split(",")
+                      .map((path) => path.trim())
+                      .filter(Boolean)
+                  )
+                }
+                placeholder="/path/to/corporate-ca.pem"
+              />
+            </div>
+
+            <div className="grid grid-cols-3 gap-2">
+              <div className="space-y-2">
+                <Label>Connect Timeout (s)</Label>
+                <Input
+                  type="number"
+                  min={1}
+                  value={network.connect_timeout_secs ?? ""}
+                  onChange={(e) =>
+                    handleNetworkChange(
+                      provider,
+                      "connect_timeout_secs",
+                      parseSeconds(e.target.value)
+                    )
+                  }
+                  placeholder="30"
+                />
+              </div>
+              <div className="space-y-2">
+                <Label>Read Timeout (s)</Label>
+                <Input
+                  type="number"
+                  min={1}
+                  value={network.read_timeout_secs ?? ""}
+                  onChange={(e) =>
+                    handleNetworkChange(
+                      provider,
+                      "read_timeout_secs",
+                      parseSeconds(e.target.value)
+                    )
+                  }
+                  placeholder="None"
+                />
+              </div>
+              <div className="space-y-2">
+                <Label>Total Timeout (s)</Label>
+                <Input
+                  type="number"
+                  min={0}
+                  value={network.timeout_secs ?? ""}
+                  onChange={(e) =>
+                    handleNetworkChange(
+                      provider,
+                      "timeout_secs",
+                      parseSeconds(e.target.value)
+                    )
+                  }
+                  placeholder="Default; 0 for none"
+                />
+              </div>
+            </div>
+
+            <div className="flex items-center space-x-2">
+              <Switch
+                id={`${provider}-accept-invalid-certs`}
+                checked={network.accept_invalid_certs ?? false}
+                onCheckedChange={(checked) =>
+                  handleNetworkChange(provider, "accept_invalid_certs", checked)
+                }
+              />
+              <Label htmlFor={`${provider}-accept-invalid-certs`}>
+                Accept Invalid Certificates (local testing only)
+              </Label>
+            </div>
+          </div>
+
           <Button
             onClick={() => saveProviderSettings(provider)}
             className="w-full mt-4"
@@ -177,6 +361,20 @@ export function ModelSettings() {
             >
               OpenRouter
             </TabsTrigger>
+            <TabsTrigger
+              value="gemini"
+              onClick={() => handleProviderChange("gemini")}
+              className="flex-1"
+            >
+              Gemini
+            </TabsTrigger>
+            <TabsTrigger
+              value="ollama"
+              onClick={() => handleProviderChange("ollama")}
+              className="flex-1"
+            >
+              Ollama
+            </TabsTrigger>
           </TabsList>
 
           <TabsContent value="anthropic" className="mt-6">
@@ -190,6 +388,14 @@ export function ModelSettings() {
           <TabsContent value="openrouter" className="mt-6">
             {renderProviderSettings("openrouter")}
           </TabsContent>
+
+          <TabsContent value="gemini" className="mt-6">
+            {renderProviderSettings("gemini")}
+          </TabsContent>
+
+          <TabsContent value="ollama" className="mt-6">
+            {renderProviderSettings("ollama")}
+          </TabsContent>
         </Tabs>
       </div>
     </Card>
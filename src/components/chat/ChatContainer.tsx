@@ -1,38 +1,93 @@
-import React, { useRef, useEffect } from "react";
-import { Paperclip, Zap, CornerRightUp } from "lucide-react";
-import { useZustandTheme } from "@/store.ts";
-import { useModel } from "../../contexts/ModelContext";
+import React, { useRef, useEffect, useState } from "react";
+import { Columns, Paperclip, Square, CornerRightUp, X } from "lucide-react";
+import { useModelStore, useZustandTheme } from "@/store.ts";
 import ErrorBoundary from "../ErrorBoundary";
 import { ErrorDisplay } from "../ErrorDisplay";
+import { ConversationModelPicker } from "./ConversationModelPicker";
+import { CompareReplies } from "./CompareReplies";
 import { MessageBlock } from "./MessageBlock";
+import { Message } from "./types";
 import { useChat } from "./useChat";
 
-export function ChatContainer() {
+interface ChatContainerProps {
+  // Opens the provider settings, e.g. to fix an API key
+  onOpenSettings?: () => void;
+}
+
+export function ChatContainer({ onOpenSettings }: ChatContainerProps) {
   const { theme } = useZustandTheme();
-  const { currentModel } = useModel();
+  const { config } = useModelStore();
+  // Providers the next message is compared across; empty sends normally
+  const [compareProviders, setCompareProviders] = useState<string[]>([]);
+  const [showCompare, setShowCompare] = useState(false);
   const messageListRef = useRef<HTMLDivElement>(null);
+  const fileInputRef = useRef<HTMLInputElement>(null);
 
   const {
     messages,
+    currentConversationId,
     input,
     setInput,
     isStreaming,
     isLoading,
     error,
+    retryStatus,
+    queueStatus,
+    fallbackStatus,
+    contextReport,
     lastAttemptedMessage,
     setLastAttemptedMessage,
     processMessage,
+    compareModels,
+    compareStreams,
+    selectResponse,
+    cancelGeneration,
+    pendingAttachments,
+    attachFiles,
+    removeAttachment,
     // clearChat,
   } = useChat();
 
   const handleSend = async () => {
-    if (input && !isStreaming) {
+    if ((input || pendingAttachments.length > 0) && !isStreaming) {
       setLastAttemptedMessage(input);
       setInput("");
-      await processMessage(input);
+      if (showCompare && compareProviders.length > 0) {
+        await compareModels(
+          input,
+          compareProviders.map((provider) => ({
+            provider,
+            model: config.providers[provider].model,
+          }))
+        );
+      } else {
+        await processMessage(input);
+      }
     }
   };
 
+  const toggleCompareProvider = (provider: string) => {
+    setCompareProviders((prev) =>
+      prev.includes(provider)
+        ? prev.filter((p) => p !== provider)
+        : [...prev, provider]
+    );
+  };
+
+  // Replies of one comparison are shown together rather than one by one
+  const groups: Message[][] = [];
+  for (const msg of messages) {
+    const previous = groups[groups.length - 1]?.[0];
+    if (
+      msg.comparison &&
+      previous?.comparison?.user_message_id === msg.comparison.user_message_id
+    ) {
+      groups[groups.length - 1].push(msg);
+    } else {
+      groups.push([msg]);
+    }
+  }
+
   const handleRetry = async () => {
     if (lastAttemptedMessage) {
       const lastAssistantIndex = [...messages]
@@ -74,9 +129,7 @@ export function ChatContainer() {
             borderBottom: `1px solid ${theme.border}`,
           }}
         >
-          <span className="text-sm font-medium" style={{ color: theme.text }}>
-            {currentModel?.name || "No model selected"}
-          </span>
+          <ConversationModelPicker conversationId={currentConversationId} />
         </div>
 
         {/* Message list */}
@@ -95,24 +148,80 @@ export function ChatContainer() {
                 Start a new conversation
               </div>
             ) : (
-              messages.map((msg, index) => (
-                <React.Fragment key={msg.id}>
-                  {index > 0 && messages[index - 1].role !== msg.role && (
+              groups.map((group, index) => (
+                <React.Fragment key={group[0].id}>
+                  {index > 0 && groups[index - 1][0].role !== group[0].role && (
                     <div className="h-4" />
                   )}
-                  <MessageBlock
-                    message={msg}
-                    onReact={handleReact}
-                    isStreaming={isStreaming && index === messages.length - 1}
-                  />
+                  {group[0].comparison ? (
+                    <CompareReplies
+                      replies={group}
+                      isStreaming={false}
+                      onSelect={isStreaming ? undefined : selectResponse}
+                    />
+                  ) : (
+                    <MessageBlock
+                      message={group[0]}
+                      onReact={handleReact}
+                      isStreaming={
+                        isStreaming &&
+                        compareStreams.length === 0 &&
+                        index === groups.length - 1
+                      }
+                    />
+                  )}
                 </React.Fragment>
               ))
             )}
+            {compareStreams.length > 0 && (
+              <CompareReplies replies={compareStreams} isStreaming />
+            )}
+            {retryStatus && (
+              <div className="text-sm text-gray-500 px-2 py-1">
+                Retrying in {Math.ceil(retryStatus.delay_ms / 1000)}s (attempt{" "}
+                {retryStatus.attempt} of {retryStatus.max_attempts}):{" "}
+                {retryStatus.reason}
+              </div>
+            )}
+            {Object.values(queueStatus).map((queued) => (
+              <div key={queued.provider} className="text-sm text-gray-500 px-2 py-1">
+                Waiting for {queued.provider}:{" "}
+                {queued.position > 0
+                  ? `${queued.position} request${queued.position === 1 ? "" : "s"} ahead`
+                  : queued.rate_limited
+                    ? "rate limit reached"
+                    : "next in line"}
+              </div>
+            ))}
+            {fallbackStatus && (
+              <div className="text-sm text-gray-500 px-2 py-1">
+                {fallbackStatus.from_provider} ({fallbackStatus.from_model})
+                failed, answering with {fallbackStatus.to_provider} (
+                {fallbackStatus.to_model}): {fallbackStatus.reason}
+              </div>
+            )}
+            {contextReport && (
+              <div className="text-sm text-gray-500 px-2 py-1">
+                {contextReport.dropped_message_ids.length} earlier message
+                {contextReport.dropped_message_ids.length === 1 ? " was" : "s were"}{" "}
+                {contextReport.summary ? "summarized" : "left out"} to fit the
+                context window (~{contextReport.sent_tokens} of{" "}
+                {contextReport.budget_tokens} tokens sent).
+                {contextReport.summary && (
+                  <details className="mt-1">
+                    <summary className="cursor-pointer">Summary sent</summary>
+                    <div className="whitespace-pre-wrap">{contextReport.summary}</div>
+                  </details>
+                )}
+              </div>
+            )}
             {error && (
               <ErrorDisplay
                 message={error.message}
                 details={error.details}
+                providerError={error.providerError}
                 onRetry={handleRetry}
+                onOpenSettings={onOpenSettings}
               />
             )}
           </div>
@@ -127,6 +236,46 @@ export function ChatContainer() {
           }}
         >
           <div className="p-4">
+            {showCompare && (
+              <div className="flex flex-wrap items-center gap-2 mb-2 text-xs">
+                <span className="text-gray-500">Compare:</span>
+                {Object.entries(config.providers).map(([provider, settings]) => (
+                  <button
+                    key={provider}
+                    className="rounded px-2 py-1"
+                    style={{
+                      backgroundColor: compareProviders.includes(provider)
+                        ? theme.border
+                        : theme.surface,
+                      color: theme.text,
+                    }}
+                    onClick={() => toggleCompareProvider(provider)}
+                  >
+                    {provider} ({settings.model})
+                  </button>
+                ))}
+              </div>
+            )}
+            {pendingAttachments.length > 0 && (
+              <div className="flex flex-wrap gap-2 mb-2">
+                {pendingAttachments.map((attachment) => (
+                  <span
+                    key={attachment.id}
+                    className="inline-flex items-center text-xs rounded px-2 py-1"
+                    style={{ backgroundColor: theme.surface, color: theme.text }}
+                  >
+                    {attachment.filename}
+                    <button
+                      className="ml-1 text-gray-400 hover:text-white"
+                      onClick={() => removeAttachment(attachment.id)}
+                      aria-label={`Remove ${attachment.filename}`}
+                    >
+                      <X size={12} />
+                    </button>
+                  </span>
+                ))}
+              </div>
+            )}
             <div
               className="flex items-end rounded-lg"
               style={{
@@ -134,9 +283,34 @@ export function ChatContainer() {
                 boxShadow: `0 2px 4px -2px ${theme.shadowColor}, 0 1px 2px -1px ${theme.shadowColor}`,
               }}
             >
-              <button className="p-3 text-gray-400 hover:text-white transition-colors">
+              <input
+                ref={fileInputRef}
+                type="file"
+                multiple
+                accept="image/png,image/jpeg,image/gif,image/webp,application/pdf,.txt,.md,.csv,.json"
+                className="hidden"
+                onChange={(e) => {
+                  if (e.target.files) attachFiles(e.target.files);
+                  e.target.value = "";
+                }}
+              />
+              <button
+                className="p-3 text-gray-400 hover:text-white transition-colors"
+                onClick={() => fileInputRef.current?.click()}
+                disabled={isStreaming}
+                aria-label="Attach files"
+              >
                 <Paperclip size={20} />
               </button>
+              <button
+                className="p-3 transition-colors hover:text-white"
+                style={{ color: showCompare ? theme.text : undefined }}
+                onClick={() => setShowCompare((show) => !show)}
+                disabled={isStreaming}
+                aria-label="Compare models"
+              >
+                <Columns size={20} className={showCompare ? "" : "text-gray-400"} />
+              </button>
               <textarea
                 value={input}
                 onChange={(e) => setInput(e.target.value)}
@@ -154,10 +328,10 @@ export function ChatContainer() {
               />
               <button
                 className="p-3 text-gray-400 hover:text-white transition-colors"
-                onClick={handleSend}
-                disabled={isStreaming}
+                onClick={isStreaming ? cancelGeneration : handleSend}
+                aria-label={isStreaming ? "Stop generating" : "Send message"}
               >
-                {isStreaming ? <Zap size={20} /> : <CornerRightUp size={20} />}
+                {isStreaming ? <Square size={20} /> : <CornerRightUp size={20} />}
               </button>
             </div>
           </div>
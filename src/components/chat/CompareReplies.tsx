@@ -0,0 +1,71 @@
+import { useZustandTheme } from "@/store.ts";
+import { Message } from "./types";
+import { MessageContent } from "./MessageContent";
+import { CompareStream } from "./useChat";
+
+interface CompareRepliesProps {
+  // Saved replies to one user message, or replies still streaming in
+  replies: (Message | CompareStream)[];
+  isStreaming: boolean;
+  onSelect?: (messageId: string) => void;
+}
+
+// Replies from several models to the same message, side by side
+export function CompareReplies({
+  replies,
+  isStreaming,
+  onSelect,
+}: CompareRepliesProps) {
+  const { theme } = useZustandTheme();
+
+  return (
+    <div
+      className="grid gap-3 py-3 px-4"
+      style={{
+        gridTemplateColumns: `repeat(${Math.min(replies.length, 3)}, minmax(0, 1fr))`,
+      }}
+    >
+      {replies.map((reply, index) => {
+        const saved = "id" in reply ? reply : null;
+        const selected = saved?.comparison?.selected ?? false;
+        const message: Message = saved ?? {
+          id: `compare-${index}`,
+          role: "assistant",
+          content: reply.text,
+          model: reply.model,
+          timestamp: "",
+          thinking: reply.thinking
+            ? [{ type: "thinking", thinking: reply.thinking, signature: "" }]
+            : undefined,
+        };
+        return (
+          <div
+            key={message.id}
+            className="rounded-lg p-3 flex flex-col min-w-0"
+            style={{
+              backgroundColor: theme.surface,
+              border: `1px solid ${selected ? theme.text : theme.border}`,
+              opacity: saved && !selected ? 0.75 : 1,
+            }}
+          >
+            <MessageContent message={message} isStreaming={isStreaming} />
+            {saved && onSelect && (
+              <div className="mt-2 text-xs text-gray-500">
+                {selected ? (
+                  "Conversation continues from this reply"
+                ) : (
+                  <button
+                    className="underline hover:text-white"
+                    onClick={() => onSelect(saved.id)}
+                  >
+                    Use this reply
+                  </button>
+                )}
+              </div>
+            )}
+          </div>
+        );
+      })}
+    </div>
+  );
+}
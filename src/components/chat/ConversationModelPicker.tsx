@@ -0,0 +1,114 @@
+import { useEffect, useState } from "react";
+import { invoke } from "@tauri-apps/api/core";
+import {
+  Select,
+  SelectContent,
+  SelectItem,
+  SelectTrigger,
+  SelectValue,
+} from "@/components/ui/select";
+import { ConversationModel, ModelList, useModelStore } from "@/store.ts";
+
+interface ConversationModelPickerProps {
+  conversationId: string | null;
+}
+
+// Picks the provider and model of one conversation, leaving others alone
+export function ConversationModelPicker({
+  conversationId,
+}: ConversationModelPickerProps) {
+  const { config } = useModelStore();
+  const [current, setCurrent] = useState<ConversationModel | null>(null);
+  const [models, setModels] = useState<string[]>([]);
+
+  const loadModels = async (provider: string) => {
+    try {
+      const list = await invoke<ModelList>("list_models", { provider });
+      setModels(list.models.map((model) => model.name));
+    } catch (error) {
+      console.error(`Failed to list ${provider} models:`, error);
+      setModels([]);
+    }
+  };
+
+  useEffect(() => {
+    if (!conversationId) {
+      setCurrent(null);
+      return;
+    }
+    invoke<ConversationModel>("get_conversation_model", {
+      conversationId: parseInt(conversationId, 10),
+    })
+      .then((model) => {
+        setCurrent(model);
+        loadModels(model.provider);
+      })
+      .catch((error) =>
+        console.error("Failed to load conversation model:", error)
+      );
+  }, [conversationId]);
+
+  const update = async (provider: string, model: string) => {
+    if (!conversationId) return;
+    try {
+      await invoke("set_conversation_model", {
+        conversationId: parseInt(conversationId, 10),
+        provider,
+        model,
+      });
+      const updated = await invoke<ConversationModel>("get_conversation_model", {
+        conversationId: parseInt(conversationId, 10),
+      });
+      setCurrent(updated);
+    } catch (error) {
+      console.error("Failed to change conversation model:", error);
+    }
+  };
+
+  const handleProviderChange = (provider: string) => {
+    // Start from the provider's default model
+    const model = config.providers[provider]?.model ?? "";
+    loadModels(provider);
+    update(provider, model);
+  };
+
+  if (!current) {
+    return <span className="text-sm font-medium">No model selected</span>;
+  }
+
+  const modelOptions = models.includes(current.model)
+    ? models
+    : [current.model, ...models];
+
+  return (
+    <div className="flex items-center space-x-2">
+      <Select value={current.provider} onValueChange={handleProviderChange}>
+        <SelectTrigger className="h-7 w-32 text-sm">
+          <SelectValue />
+        </SelectTrigger>
+        <SelectContent>
+          {Object.keys(config.providers).map((provider) => (
+            <SelectItem key={provider} value={provider}>
+              {provider}
+            </SelectItem>
+          ))}
+        </SelectContent>
+      </Select>
+      <Select
+        value={current.model}
+        onValueChange={(model) => update(current.provider, model)}
+      >
+        <SelectTrigger className="h-7 w-64 text-sm">
+          <SelectValue />
+        </SelectTrigger>
+        <SelectContent>
+          {modelOptions.map((model) => (
+            <SelectItem key={model} value={model}>
+              {model}
+            </SelectItem>
+          ))}
+        </SelectContent>
+      </Select>
+    </div>
+  );
+}
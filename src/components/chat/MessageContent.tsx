@@ -31,7 +31,45 @@ export const MessageContent: React.FC<MessageContentProps> = ({
         <span className="text-xs" style={{ color: theme.textSecondary }}>
           {message.timestamp}
         </span>
+        {message.interrupted && (
+          <span className="text-xs ml-2" style={{ color: theme.textSecondary }}>
+            (stopped)
+          </span>
+        )}
       </div>
+      {message.attachments && message.attachments.length > 0 && (
+        <div className="flex flex-wrap gap-2 mb-2">
+          {message.attachments.map((attachment) => (
+            <span
+              key={attachment.id}
+              className="text-xs rounded px-2 py-1"
+              style={{ backgroundColor: theme.surface, color: theme.textSecondary }}
+            >
+              {attachment.filename}
+            </span>
+          ))}
+        </div>
+      )}
+      {message.thinking && message.thinking.length > 0 && (
+        <details className="mb-2 text-sm" open={isStreaming}>
+          <summary
+            className="cursor-pointer select-none"
+            style={{ color: theme.textSecondary }}
+          >
+            Thinking
+          </summary>
+          <div
+            className="mt-1 pl-3 whitespace-pre-wrap border-l-2"
+            style={{ color: theme.textSecondary, borderColor: theme.border }}
+          >
+            {message.thinking
+              .map((block) =>
+                block.type === "thinking" ? block.thinking : "[redacted]"
+              )
+              .join("\n\n")}
+          </div>
+        </details>
+      )}
       <div className="prose prose-slate dark:prose-invert prose-code:before:content-none prose-code:after:content-none max-w-none font-sans leading-relaxed tracking-normal break-words">
         <ReactMarkdown
           remarkPlugins={[remarkGfm, remarkBreaks, remarkMath]}
@@ -1,3 +1,5 @@
+import { Attachment, Comparison, ThinkingBlock } from "@/store.ts";
+
 export interface Message {
   id: string;
   content: string;
@@ -7,6 +9,10 @@ export interface Message {
   reactions?: {
     thumbsUp: number;
   };
+  interrupted?: boolean;
+  thinking?: ThinkingBlock[];
+  attachments?: Attachment[];
+  comparison?: Comparison;
 }
 
 export interface ArchivedChat {
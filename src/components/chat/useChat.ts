@@ -1,9 +1,85 @@
-import { useState, useEffect } from "react";
+import { useState, useEffect, useRef } from "react";
 import { invoke } from "@tauri-apps/api/core";
 import { listen } from "@tauri-apps/api/event";
-import { Message } from "@/store.ts";
+import { getCurrentWebview } from "@tauri-apps/api/webview";
+import { Attachment, ConversationModel, Message } from "@/store.ts";
 import { useChatStore, useModelStore } from "@/store.ts";
 
+// Emitted by the backend before it retries a rate-limited or failed request
+export interface RetryEvent {
+  attempt: number;
+  max_attempts: number;
+  delay_ms: number;
+  reason: string;
+  discard_partial: boolean;
+}
+
+// Emitted while a request waits for its turn with a rate-limited provider,
+// and with waiting false once it is sent
+export interface QueueEvent {
+  provider: string;
+  waiting: boolean;
+  // Requests queued ahead of this one
+  position: number;
+  // Waiting on the per-minute limits rather than on other requests
+  rate_limited: boolean;
+}
+
+// Emitted when a provider failed and the next one in the fallback chain
+// takes over; whatever the failed provider streamed is discarded
+export interface FallbackEvent {
+  from_provider: string;
+  from_model: string;
+  to_provider: string;
+  to_model: string;
+  reason: string;
+}
+
+// Streamed by compare_models; index is the target's position
+interface CompareChunk {
+  index: number;
+  text: string;
+  thinking: string;
+  // The target is retrying from scratch
+  restart: boolean;
+}
+
+export interface CompareTarget {
+  provider: string;
+  model: string;
+}
+
+// One target's reply while a comparison is in flight
+export interface CompareStream extends CompareTarget {
+  text: string;
+  thinking: string;
+}
+
+// Emitted when older messages were left out to fit the context window
+export interface ContextReport {
+  strategy: "drop_oldest" | "keep_first_last" | "summarize";
+  budget_tokens: number;
+  original_tokens: number;
+  sent_tokens: number;
+  dropped_message_ids: string[];
+  summary?: string;
+}
+
+// Attached to errors from process_message when the provider request failed
+export interface ProviderError {
+  code:
+    | "auth"
+    | "rate_limited"
+    | "overloaded"
+    | "invalid_request"
+    | "context_too_long"
+    | "network"
+    | "timeout"
+    | "parse";
+  message: string;
+  retry_after_ms?: number | null;
+}
+
 export function useChat() {
   // TODO: fix
   const { config } = useModelStore();
@@ -14,20 +90,34 @@ export function useChat() {
     setCurrentConversationId,
     addMessage, 
     updateLastMessage,
+    updateLastMessageThinking,
     clearMessages
   } = useChatStore();
   
   const [input, setInput] = useState("");
   const [streamBuffer, setStreamBuffer] = useState("");
+  const [thinkingBuffer, setThinkingBuffer] = useState("");
   const [error, setError] = useState<{
     message: string;
     details?: string;
+    providerError?: ProviderError;
   } | null>(null);
   const [lastAttemptedMessage, setLastAttemptedMessage] = useState<string>("");
   // TODO: fix
   const [retryingMessageId, setRetryingMessageId] = useState<string | null>(null);
   const [isStreaming, setIsStreaming] = useState(false);
   const [isLoading, setIsLoading] = useState(true);
+  const [retryStatus, setRetryStatus] = useState<RetryEvent | null>(null);
+  // Requests waiting for a rate-limited provider, by provider
+  const [queueStatus, setQueueStatus] = useState<Record<string, QueueEvent>>({});
+  const [contextReport, setContextReport] = useState<ContextReport | null>(null);
+  const [fallbackStatus, setFallbackStatus] = useState<FallbackEvent | null>(null);
+  // Replies streaming in during compare_models, one per target
+  const [compareStreams, setCompareStreams] = useState<CompareStream[]>([]);
+  // Files attached in the composer, sent with the next message
+  const [pendingAttachments, setPendingAttachments] = useState<Attachment[]>([]);
+  // Id of the in-flight process_message call, used to cancel it
+  const requestIdRef = useRef<string | null>(null);
 
   // Load messages when conversation changes
   useEffect(() => {
@@ -70,12 +160,166 @@ export function useChat() {
     };
   }, []);
 
+  useEffect(() => {
+    const unlisten = listen<RetryEvent>("stream-retry", (event) => {
+      setRetryStatus(event.payload);
+      // The retry streams the whole reply again
+      if (event.payload.discard_partial) {
+        setStreamBuffer("");
+        setThinkingBuffer("");
+        updateLastMessage("");
+      }
+    });
+
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, [updateLastMessage]);
+
+  useEffect(() => {
+    const unlisten = listen<QueueEvent>("stream-queued", (event) => {
+      const { provider, waiting } = event.payload;
+      setQueueStatus((prev) => {
+        const { [provider]: _, ...rest } = prev;
+        return waiting ? { ...rest, [provider]: event.payload } : rest;
+      });
+    });
+
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, []);
+
+  useEffect(() => {
+    const unlisten = listen<FallbackEvent>("provider-fallback", (event) => {
+      setFallbackStatus(event.payload);
+      setRetryStatus(null);
+      setStreamBuffer("");
+      setThinkingBuffer("");
+      updateLastMessage("");
+    });
+
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, [updateLastMessage]);
+
+  useEffect(() => {
+    const unlisten = listen<CompareChunk>("compare-stream", (event) => {
+      const { index, text, thinking, restart } = event.payload;
+      setCompareStreams((prev) =>
+        prev.map((stream, i) =>
+          i !== index
+            ? stream
+            : restart
+              ? { ...stream, text: "", thinking: "" }
+              : {
+                  ...stream,
+                  text: stream.text + text,
+                  thinking: stream.thinking + thinking,
+                }
+        )
+      );
+    });
+
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, []);
+
+  useEffect(() => {
+    const unlisten = listen<ContextReport>("context-trimmed", (event) => {
+      setContextReport(event.payload);
+    });
+
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, []);
+
+  useEffect(() => {
+    if (!streamBuffer) return;
+    setRetryStatus(null);
+  }, [streamBuffer]);
+
+  useEffect(() => {
+    const unlisten = listen("stream-thinking", (event) => {
+      const chunk = event.payload as string;
+      setThinkingBuffer((prevBuffer) => prevBuffer + chunk);
+    });
+
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, []);
+
   useEffect(() => {
     if (isStreaming && streamBuffer) {
       updateLastMessage(streamBuffer);
     }
   }, [streamBuffer, isStreaming, updateLastMessage]);
 
+  useEffect(() => {
+    if (isStreaming && thinkingBuffer) {
+      updateLastMessageThinking(thinkingBuffer);
+    }
+  }, [thinkingBuffer, isStreaming, updateLastMessageThinking]);
+
+  // Files dropped on the window arrive as local paths
+  useEffect(() => {
+    const unlisten = getCurrentWebview().onDragDropEvent(async (event) => {
+      if (event.payload.type !== "drop") return;
+      for (const path of event.payload.paths) {
+        await attachPath(path);
+      }
+    });
+
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, []);
+
+  const attachPath = async (path: string) => {
+    try {
+      const attachment = await invoke<Attachment>("attach_file", { path });
+      setPendingAttachments((prev) => [...prev, attachment]);
+    } catch (error: any) {
+      setError({
+        message: error?.message || "Failed to attach file",
+        details: error?.details,
+      });
+    }
+  };
+
+  const attachFiles = async (files: FileList) => {
+    for (const file of Array.from(files)) {
+      try {
+        const data = Array.from(new Uint8Array(await file.arrayBuffer()));
+        const attachment = await invoke<Attachment>("attach_file_data", {
+          filename: file.name,
+          data,
+        });
+        setPendingAttachments((prev) => [...prev, attachment]);
+      } catch (error: any) {
+        setError({
+          message: error?.message || "Failed to attach file",
+          details: error?.details,
+        });
+      }
+    }
+  };
+
+  const removeAttachment = async (attachmentId: string) => {
+    setPendingAttachments((prev) => prev.filter((a) => a.id !== attachmentId));
+    try {
+      await invoke("remove_attachment", {
+        attachmentId: parseInt(attachmentId, 10),
+      });
+    } catch (error: any) {
+      console.error("Error removing attachment:", error);
+    }
+  };
+
   const getCurrentTime = () => {
     const now = new Date();
     return now.toLocaleTimeString('en-US', { 
@@ -88,15 +332,32 @@ export function useChat() {
   const processMessage = async (messageText: string, existingMessageId?: string) => {
     try {
       setError(null);
-      const config = await invoke<any>("get_config");
-      const streamingEnabled = config.providers[config.active_provider].streaming;
-      const currentModelName = config.providers[config.active_provider].model;
+      setContextReport(null);
+      setFallbackStatus(null);
+      // The conversation may run on another provider or model than the
+      // global default; a new one starts with the active provider's
+      let streamingEnabled: boolean;
+      let currentModelName: string;
+      if (currentConversationId) {
+        const current = await invoke<ConversationModel>("get_conversation_model", {
+          conversationId: parseInt(currentConversationId, 10),
+        });
+        streamingEnabled = current.streaming;
+        currentModelName = current.model;
+      } else {
+        const config = await invoke<any>("get_config");
+        streamingEnabled = config.providers[config.active_provider].streaming;
+        currentModelName = config.providers[config.active_provider].model;
+      }
+
+      const attachments = pendingAttachments;
 
       // Always add user message immediately
       if (!existingMessageId) {
         addMessage({
           id: "temp-user-" + Date.now(),
           content: messageText,
+          attachments,
           role: "user",
           timestamp: getCurrentTime(),
           reactions: { thumbsUp: 0 },
@@ -105,6 +366,7 @@ export function useChat() {
 
       if (streamingEnabled) {
         setStreamBuffer("");
+        setThinkingBuffer("");
         setIsStreaming(true);
         if (existingMessageId) {
           const messageIndex = messages.findIndex(
@@ -130,9 +392,23 @@ export function useChat() {
         }
       }
 
-      const response = await invoke<{ reply: string }>("process_message", {
-        message: messageText,
-      });
+      const requestId = crypto.randomUUID();
+      requestIdRef.current = requestId;
+      const response = await invoke<{
+        reply: string;
+        interrupted: boolean;
+        // Set when the request carried a response schema
+        structured?: unknown;
+      }>(
+        "process_message",
+        {
+          message: messageText,
+          requestId,
+          attachmentIds: attachments.map((a) => parseInt(a.id, 10)),
+        }
+      );
+      // They now belong to the sent message; on failure they stay pending
+      setPendingAttachments([]);
 
       // After successful processing, reload messages to get proper DB IDs
       if (currentConversationId) {
@@ -166,14 +442,98 @@ export function useChat() {
       setError({
         message: error?.message || "An error occurred while processing your message.",
         details: errorDetails,
+        providerError: error?.provider_error,
       });
     } finally {
+      requestIdRef.current = null;
       setIsStreaming(false);
+      setRetryStatus(null);
+      setQueueStatus({});
       // TODO: fix- not callable
       setRetryingMessageId(null);
     }
   };
 
+  const reloadMessages = async () => {
+    const messages = currentConversationId
+      ? await invoke<Message[]>("load_conversation_messages", {
+          conversationId: parseInt(currentConversationId, 10),
+        })
+      : await invoke<Message[]>("get_chat_history");
+    setMessages(messages);
+  };
+
+  // Sends the message to every target at once; each reply is kept and the
+  // first to arrive continues the conversation until another is picked
+  const compareModels = async (messageText: string, targets: CompareTarget[]) => {
+    try {
+      setError(null);
+      setFallbackStatus(null);
+      const attachments = pendingAttachments;
+      addMessage({
+        id: "temp-user-" + Date.now(),
+        content: messageText,
+        attachments,
+        role: "user",
+        timestamp: getCurrentTime(),
+        reactions: { thumbsUp: 0 },
+      });
+      setCompareStreams(
+        targets.map((target) => ({ ...target, text: "", thinking: "" }))
+      );
+      setIsStreaming(true);
+
+      const requestId = crypto.randomUUID();
+      requestIdRef.current = requestId;
+      await invoke("compare_models", {
+        message: messageText,
+        targets,
+        requestId,
+        attachmentIds: attachments.map((a) => parseInt(a.id, 10)),
+      });
+      setPendingAttachments([]);
+      await reloadMessages();
+    } catch (error: any) {
+      console.error("Error in compare_models:", error);
+      setError({
+        message: error?.message || "An error occurred while comparing models.",
+        details: error?.details || null,
+        providerError: error?.provider_error,
+      });
+    } finally {
+      requestIdRef.current = null;
+      setIsStreaming(false);
+      setRetryStatus(null);
+      setQueueStatus({});
+      setCompareStreams([]);
+    }
+  };
+
+  // Continues the conversation from another reply of a comparison
+  const selectResponse = async (messageId: string) => {
+    try {
+      await invoke("select_response", { messageId: parseInt(messageId, 10) });
+      await reloadMessages();
+    } catch (error: any) {
+      console.error("Error selecting response:", error);
+      setError({
+        message: error?.message || "Failed to select response",
+        details: error?.details,
+      });
+    }
+  };
+
+  const cancelGeneration = async () => {
+    if (!requestIdRef.current) return;
+    try {
+      await invoke<boolean>("cancel_generation", {
+        requestId: requestIdRef.current,
+      });
+    } catch (error: any) {
+      console.error("Error cancelling generation:", error);
+    }
+  };
+
   const clearChat = async () => {
     try {
       await invoke("clear_chat_history");
@@ -196,9 +556,20 @@ export function useChat() {
     isStreaming,
     isLoading,
     error,
+    retryStatus,
+    queueStatus,
+    fallbackStatus,
+    contextReport,
     lastAttemptedMessage,
     setLastAttemptedMessage,
     processMessage,
+    compareModels,
+    compareStreams,
+    selectResponse,
+    cancelGeneration,
+    pendingAttachments,
+    attachFiles,
+    removeAttachment,
     clearChat,
     setMessages,
     setCurrentConversationId
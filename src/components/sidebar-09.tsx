@@ -73,7 +73,7 @@ export default function Page() {
       <AppSidebar setActiveContent={setActiveContent} />
       <SidebarInset className="flex flex-col h-[calc(100vh-64px)]">
         {activeContent === "inbox" ? (
-          <ChatContainer />
+          <ChatContainer onOpenSettings={() => setActiveContent("model")} />
         ) : activeContent === "trash" ? (
           <TrashContent />
         ) : activeContent === "model" ? (
@@ -26,8 +26,31 @@ export interface Message {
   reactions?: {
     thumbsUp: number;
   };
+  interrupted?: boolean;
+  thinking?: ThinkingBlock[];
+  attachments?: Attachment[];
+  // Set on each reply of a model comparison
+  comparison?: Comparison;
 }
 
+// Replies of a comparison share the user message they answer; the selected
+// one is the reply the conversation continues from
+export interface Comparison {
+  user_message_id: string;
+  selected: boolean;
+}
+
+export interface Attachment {
+  id: string;
+  kind: "image" | "document";
+  media_type: string;
+  filename: string;
+}
+
+export type ThinkingBlock =
+  | { type: "thinking"; thinking: string; signature: string }
+  | { type: "redacted_thinking"; data: string };
+
 export interface Conversation {
   id: string;
   title: string;
@@ -46,6 +69,7 @@ interface ChatStore {
   setCurrentConversationId: (id: string | null) => void;
   addMessage: (message: Message) => void;
   updateLastMessage: (content: string) => void;
+  updateLastMessageThinking: (thinking: string) => void;
   clearMessages: () => void;
 }
 
@@ -54,6 +78,69 @@ interface ProviderSettings {
   model: string;
   max_tokens: number;
   streaming: boolean;
+  base_url?: string | null;
+  num_ctx?: number | null;
+  temperature?: number | null;
+  top_p?: number | null;
+  top_k?: number | null;
+  stop_sequences?: string[];
+  thinking_budget_tokens?: number | null;
+  prompt_caching?: boolean;
+  // Scripted responses for the mock provider
+  fixture?: string | null;
+  // Requests beyond these limits wait in a queue
+  rate_limits?: RateLimits;
+  network?: NetworkSettings;
+}
+
+export interface NetworkSettings {
+  proxy?: string | null;
+  // Comma-separated hosts that bypass the proxy
+  no_proxy?: string | null;
+  // PEM files of extra root certificates to trust
+  ca_certificates?: string[];
+  accept_invalid_certs?: boolean;
+  connect_timeout_secs?: number | null;
+  read_timeout_secs?: number | null;
+  // 0 disables the limit on a whole request
+  timeout_secs?: number | null;
+}
+
+interface RateLimits {
+  requests_per_minute?: number | null;
+  tokens_per_minute?: number | null;
+  max_concurrent?: number | null;
+}
+
+// An entry of the backend models registry (list_models)
+export interface ModelEntry {
+  name: string;
+  display_name?: string;
+  context_window?: number;
+}
+
+export interface ModelList {
+  provider: string;
+  source: "live" | "cache" | "bundled";
+  models: ModelEntry[];
+  error?: string;
+}
+
+// The provider, model and parameters a conversation runs with
+export interface ConversationModel {
+  provider: string;
+  model: string;
+  // A missing field uses the provider's setting; null clears temperature,
+  // top_p, top_k or thinking_budget_tokens so the API default applies
+  params: {
+    max_tokens?: number | null;
+    temperature?: number | null;
+    top_p?: number | null;
+    top_k?: number | null;
+    stop_sequences?: string[] | null;
+    thinking_budget_tokens?: number | null;
+  };
+  streaming: boolean;
 }
 
 interface ModelConfig {
@@ -105,10 +192,20 @@ const getInitialTheme = (): ThemeType => {
 };
 
 const getInitialModelConfig = (): ModelConfig => {
+  const defaultConfig = getDefaultModelConfig();
   const savedConfig = localStorage.getItem(MODEL_CONFIG_STORAGE_KEY);
   if (savedConfig) {
-    return JSON.parse(savedConfig);
+    const config: ModelConfig = JSON.parse(savedConfig);
+    // Providers added since the config was saved start from their defaults
+    return {
+      ...config,
+      providers: { ...defaultConfig.providers, ...config.providers },
+    };
   }
+  return defaultConfig;
+};
+
+const getDefaultModelConfig = (): ModelConfig => {
   return {
     active_provider: "anthropic",
     providers: {
@@ -129,6 +226,21 @@ const getInitialModelConfig = (): ModelConfig => {
         model: "anthropic/claude-3-opus",
         max_tokens: 1024,
         streaming: true,
+        base_url: "https://openrouter.ai/api/v1",
+      },
+      gemini: {
+        api_key: "",
+        model: "gemini-2.5-pro",
+        max_tokens: 1024,
+        streaming: true,
+      },
+      ollama: {
+        api_key: "",
+        model: "llama3.1",
+        max_tokens: 1024,
+        streaming: true,
+        base_url: "http://localhost:11434",
+        num_ctx: 8192,
       },
     },
   };
@@ -184,6 +296,16 @@ export const useChatStore = create<ChatStore>((set) => ({
     }
     return { messages };
   }),
+  updateLastMessageThinking: (thinking) => set((state) => {
+    const messages = [...state.messages];
+    if (messages.length > 0) {
+      messages[messages.length - 1] = {
+        ...messages[messages.length - 1],
+        thinking: [{ type: "thinking", thinking, signature: "" }]
+      };
+    }
+    return { messages };
+  }),
   clearMessages: () => set({ messages: [] })
 }));
 